@@ -1,5 +1,5 @@
 use log::{LevelFilter, Log, Metadata, Record};
-use oxidenes::NES;
+use oxidenes::{cartridge_ram_size, Screen, Speaker, NES};
 use std::{env, fs::File, io::Read};
 
 static LOGGER: Logger = Logger;
@@ -16,6 +16,22 @@ impl Log for Logger {
     fn flush(&self) {}
 }
 
+/// nestest only checks the CPU, nothing is drawn or played
+struct NoScreen;
+
+impl Screen for NoScreen {
+    fn render_pixel(&mut self, _x: u16, _y: u16, _pixel: (u8, u8, u8)) {}
+}
+
+struct NoSpeaker;
+
+impl Speaker for NoSpeaker {
+    fn sample_rate(&self) -> u32 {
+        44100
+    }
+    fn push_sample(&mut self, _sample: f32) {}
+}
+
 pub fn init() {
     log::set_logger(&LOGGER).unwrap();
     log::set_max_level(LevelFilter::Trace);
@@ -32,7 +48,21 @@ fn main() {
     let mut rom = File::open(&rom_path).unwrap();
     let mut buffer = Vec::with_capacity(24 * 1024);
     rom.read_to_end(&mut buffer).expect("Read File error");
-    let mut nes = NES::new(&buffer);
+    let ram_size = match cartridge_ram_size(&buffer) {
+        Ok(size) => size,
+        Err(e) => {
+            eprintln!("{}: {}", rom_path, e);
+            return;
+        }
+    };
+    let mut ram = vec![0; ram_size];
+    let mut nes = match NES::new(&buffer, &mut ram, NoScreen, NoSpeaker) {
+        Ok(nes) => nes,
+        Err(e) => {
+            eprintln!("{}: {}", rom_path, e);
+            return;
+        }
+    };
     nes.set_pc(0xC000);
     loop {
         nes.step();
@@ -0,0 +1,245 @@
+/// Timer periods in CPU cycles (NTSC)
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+/// Delta modulation channel, $4010-$4013.
+/// It plays 1 bit delta encoded samples fetched from $C000-$FFFF by DMA.
+/// see http://wiki.nesdev.com/w/index.php/APU_DMC
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    period: u16,
+    timer: u16,
+    /// 7 bit output level
+    level: u8,
+    /// $4012
+    sample_addr: u16,
+    /// $4013
+    sample_length: u16,
+    /// Memory reader
+    addr: u16,
+    remaining: u16,
+    /// Sample buffer, filled by DMA
+    buffer: Option<u8>,
+    /// Output unit
+    shift: u8,
+    bits: u8,
+    silence: bool,
+    pub irq: bool,
+}
+
+state!(Dmc {
+    irq_enabled,
+    looping,
+    period,
+    timer,
+    level,
+    sample_addr,
+    sample_length,
+    addr,
+    remaining,
+    buffer,
+    shift,
+    bits,
+    silence,
+    irq
+});
+
+impl Dmc {
+    pub fn new() -> Dmc {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            period: RATE_TABLE[0],
+            timer: 0,
+            level: 0,
+            sample_addr: 0xC000,
+            sample_length: 1,
+            addr: 0xC000,
+            remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+    pub fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = val & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = val & 0x40 != 0;
+                self.period = RATE_TABLE[val as usize & 0xF];
+            }
+            // -DDD DDDD
+            1 => self.level = val & 0x7F,
+            // AAAA AAAA  $C000 + A * 64
+            2 => self.sample_addr = 0xC000 | ((val as u16) << 6),
+            // LLLL LLLL  L * 16 + 1 bytes
+            3 => self.sample_length = ((val as u16) << 4) + 1,
+            _ => unreachable!(),
+        }
+    }
+    /// Bit 4 of $4015
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.remaining = 0;
+        } else if self.remaining == 0 {
+            self.restart();
+        }
+    }
+    fn restart(&mut self) {
+        self.addr = self.sample_addr;
+        self.remaining = self.sample_length;
+    }
+    /// Bytes of the sample still to be fetched
+    pub fn active(&self) -> bool {
+        self.remaining > 0
+    }
+    /// Address the memory reader wants to fetch when the sample buffer is empty
+    pub fn dma_request(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.remaining > 0 {
+            Some(self.addr)
+        } else {
+            None
+        }
+    }
+    /// Complete the fetch requested by `dma_request`
+    pub fn dma_fill(&mut self, val: u8) {
+        self.buffer = Some(val);
+        // address wraps around to $8000
+        self.addr = self.addr.wrapping_add(1) | 0x8000;
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+    /// Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits -= 1;
+        if self.bits == 0 {
+            self.bits = 8;
+            match self.buffer.take() {
+                Some(val) => {
+                    self.silence = false;
+                    self.shift = val;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+    /// 7 bit output level
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enable a sample of `length` * 16 + 1 bytes at $FFC0
+    fn start(dmc: &mut Dmc, flags: u8, length: u8) {
+        dmc.write(0, flags);
+        dmc.write(2, 0xFF);
+        dmc.write(3, length);
+        dmc.set_enabled(true);
+    }
+
+    /// Fetch `bytes` bytes of the sample, return the address of the last one
+    fn fetch(dmc: &mut Dmc, bytes: usize) -> u16 {
+        let mut addr = 0;
+        for _ in 0..bytes {
+            addr = dmc.dma_request().unwrap();
+            dmc.dma_fill(0);
+            // Empty the sample buffer
+            dmc.buffer = None;
+        }
+        addr
+    }
+
+    #[test]
+    fn irq_at_sample_end() {
+        let mut dmc = Dmc::new();
+        start(&mut dmc, 0x80, 0x01);
+        assert_eq!(fetch(&mut dmc, 16), 0xFFCF);
+        assert!(dmc.active());
+        assert!(!dmc.irq);
+        fetch(&mut dmc, 1);
+        assert!(!dmc.active());
+        assert!(dmc.irq);
+        assert_eq!(dmc.dma_request(), None);
+        // Cleared by clearing the IRQ enable or by writing $4015
+        dmc.write(0, 0x00);
+        assert!(!dmc.irq);
+        start(&mut dmc, 0x80, 0x01);
+        fetch(&mut dmc, 17);
+        dmc.set_enabled(false);
+        assert!(!dmc.irq);
+    }
+
+    #[test]
+    fn address_wraps_to_8000() {
+        let mut dmc = Dmc::new();
+        start(&mut dmc, 0x00, 0x04);
+        assert_eq!(fetch(&mut dmc, 64), 0xFFFF);
+        assert_eq!(fetch(&mut dmc, 1), 0x8000);
+    }
+
+    #[test]
+    fn looping_sample_restarts() {
+        let mut dmc = Dmc::new();
+        start(&mut dmc, 0xC0, 0x01);
+        fetch(&mut dmc, 17);
+        assert!(dmc.active());
+        assert!(!dmc.irq);
+        assert_eq!(dmc.dma_request(), Some(0xFFC0));
+    }
+
+    fn clock(dmc: &mut Dmc, cycles: usize) {
+        for _ in 0..cycles {
+            dmc.clock_timer();
+        }
+    }
+
+    #[test]
+    fn output_follows_the_sample_bits() {
+        let mut dmc = Dmc::new();
+        start(&mut dmc, 0x0F, 0x00);
+        dmc.write(1, 0x40);
+        dmc.dma_fill(0x0F);
+        // The output unit finishes its silent byte before taking the buffer
+        clock(&mut dmc, 8 * 54);
+        assert_eq!(dmc.output(), 0x40);
+        // 4 ones then 4 zeros
+        clock(&mut dmc, 4 * 54);
+        assert_eq!(dmc.output(), 0x48);
+        clock(&mut dmc, 4 * 54);
+        assert_eq!(dmc.output(), 0x40);
+    }
+}
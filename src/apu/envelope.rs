@@ -0,0 +1,68 @@
+/// Volume envelope of pulse and noise channels, clocked by quarter frames.
+/// see http://wiki.nesdev.com/w/index.php/APU_Envelope
+pub struct Envelope {
+    /// Restart the envelope on the next clock
+    start: bool,
+    /// Loop the decay, it's also the length counter halt flag
+    pub looping: bool,
+    /// Output the volume directly instead of the decay level
+    constant: bool,
+    /// Constant volume or the period of the divider
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+state!(Envelope {
+    start,
+    looping,
+    constant,
+    volume,
+    divider,
+    decay
+});
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            start: false,
+            looping: false,
+            constant: false,
+            volume: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+    /// Write the low 6 bits of $4000/$4004/$400C  --LC VVVV
+    pub fn write(&mut self, val: u8) {
+        self.looping = val & 0x20 != 0;
+        self.constant = val & 0x10 != 0;
+        self.volume = val & 0x0F;
+    }
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+    pub fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
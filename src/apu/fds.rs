@@ -0,0 +1,316 @@
+use super::mixer::pulse_out;
+
+/// Modulation table entries, 4 resets the counter
+const MOD_STEPS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+/// Master volume $4089, 2/2 2/3 2/4 2/5
+const MASTER_VOLUME: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+
+/// Volume or modulation envelope, $4080 and $4084
+struct Envelope {
+    /// Ignore the envelope and use `speed` as the gain
+    disabled: bool,
+    increase: bool,
+    speed: u8,
+    gain: u8,
+    /// CPU cycles until the next clock
+    timer: u32,
+}
+
+state!(Envelope {
+    disabled,
+    increase,
+    speed,
+    gain,
+    timer
+});
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            disabled: true,
+            increase: false,
+            speed: 0,
+            gain: 0,
+            timer: 0,
+        }
+    }
+    /// MDSS SSSS
+    /// M: disable the envelope, D: increase, S: speed or gain
+    fn write(&mut self, val: u8, master: u8) {
+        self.disabled = val & 0x80 != 0;
+        self.increase = val & 0x40 != 0;
+        self.speed = val & 0x3F;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.reset(master);
+    }
+    fn reset(&mut self, master: u8) {
+        self.timer = 8 * (self.speed as u32 + 1) * master as u32;
+    }
+    /// The gain moves one step every 8 * (speed + 1) * $408A CPU cycles
+    fn clock(&mut self, master: u8) {
+        if self.disabled || master == 0 {
+            return;
+        }
+        if self.timer == 0 {
+            self.reset(master);
+            if self.increase && self.gain < 32 {
+                self.gain += 1;
+            } else if !self.increase && self.gain > 0 {
+                self.gain -= 1;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+}
+
+/// Wavetable sound channel of the Famicom Disk System, $4040-$4092.
+/// A 64 step 6 bit waveform whose pitch is bent by a modulation unit.
+/// see http://wiki.nesdev.com/w/index.php/FDS_audio
+pub struct FdsAudio {
+    /// $4040-$407F
+    wave: [u8; 64],
+    /// $4089 bit 7, the CPU can write the wavetable and the channel holds its output
+    wave_write: bool,
+    /// $4089 bits 0-1
+    master_volume: u8,
+    /// $4082-$4083 12 bit frequency
+    frequency: u16,
+    /// $4083 bit 7
+    wave_halt: bool,
+    /// $4083 bit 6
+    envelope_halt: bool,
+    wave_accumulator: u32,
+    wave_position: u8,
+    volume: Envelope,
+    /// Volume gain, latched at the start of every wave cycle
+    output_gain: u8,
+    /// $4084
+    modulation: Envelope,
+    /// $4085 7 bit signed counter
+    mod_counter: i8,
+    /// $4086-$4087 12 bit frequency
+    mod_frequency: u16,
+    /// $4087 bit 7, $4088 writes the modulation table
+    mod_halt: bool,
+    mod_table: [u8; 64],
+    mod_accumulator: u32,
+    mod_position: u8,
+    /// $408A
+    envelope_speed: u8,
+    output: u8,
+}
+
+state!(FdsAudio {
+    wave,
+    wave_write,
+    master_volume,
+    frequency,
+    wave_halt,
+    envelope_halt,
+    wave_accumulator,
+    wave_position,
+    volume,
+    output_gain,
+    modulation,
+    mod_counter,
+    mod_frequency,
+    mod_halt,
+    mod_table,
+    mod_accumulator,
+    mod_position,
+    envelope_speed,
+    output
+});
+
+impl FdsAudio {
+    pub fn new() -> FdsAudio {
+        FdsAudio {
+            wave: [0; 64],
+            wave_write: false,
+            master_volume: 0,
+            frequency: 0,
+            wave_halt: true,
+            envelope_halt: true,
+            wave_accumulator: 0,
+            wave_position: 0,
+            volume: Envelope::new(),
+            output_gain: 0,
+            modulation: Envelope::new(),
+            mod_counter: 0,
+            mod_frequency: 0,
+            mod_halt: true,
+            mod_table: [0; 64],
+            mod_accumulator: 0,
+            mod_position: 0,
+            envelope_speed: 0xE8,
+            output: 0,
+        }
+    }
+    pub fn read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x407F => Some(self.wave[addr as usize & 0x3F]),
+            0x4090 => Some(self.volume.gain),
+            0x4092 => Some(self.modulation.gain),
+            _ => None,
+        }
+    }
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4040..=0x407F => {
+                if self.wave_write {
+                    self.wave[addr as usize & 0x3F] = val & 0x3F;
+                }
+            }
+            0x4080 => self.volume.write(val, self.envelope_speed),
+            0x4082 => self.frequency = (self.frequency & 0xF00) | val as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0xFF) | (val as u16 & 0x0F) << 8;
+                self.wave_halt = val & 0x80 != 0;
+                self.envelope_halt = val & 0x40 != 0;
+                if self.wave_halt {
+                    self.wave_accumulator = 0;
+                    self.wave_position = 0;
+                }
+                if self.envelope_halt {
+                    self.volume.reset(self.envelope_speed);
+                    self.modulation.reset(self.envelope_speed);
+                }
+            }
+            0x4084 => self.modulation.write(val, self.envelope_speed),
+            0x4085 => self.mod_counter = ((val << 1) as i8) >> 1,
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0xF00) | val as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0xFF) | (val as u16 & 0x0F) << 8;
+                self.mod_halt = val & 0x80 != 0;
+                if self.mod_halt {
+                    self.mod_accumulator = 0;
+                }
+            }
+            0x4088 => {
+                // Every write fills two steps of the table, the modulator
+                // may have been halted on an odd step
+                if self.mod_halt {
+                    let position = self.mod_position as usize;
+                    self.mod_table[position] = val & 0x7;
+                    self.mod_table[(position + 1) & 0x3F] = val & 0x7;
+                    self.mod_position = (self.mod_position + 2) & 0x3F;
+                }
+            }
+            0x4089 => {
+                self.wave_write = val & 0x80 != 0;
+                self.master_volume = val & 0x3;
+            }
+            0x408A => self.envelope_speed = val,
+            _ => {}
+        }
+    }
+    /// Called once every CPU cycle
+    pub fn clock(&mut self) {
+        if !self.envelope_halt && !self.wave_halt {
+            self.volume.clock(self.envelope_speed);
+            self.modulation.clock(self.envelope_speed);
+        }
+        if !self.mod_halt {
+            self.mod_accumulator += self.mod_frequency as u32;
+            if self.mod_accumulator > 0xFFFF {
+                self.mod_accumulator &= 0xFFFF;
+                self.step_modulation();
+            }
+        }
+        if !self.wave_halt && !self.wave_write {
+            self.wave_accumulator += self.pitch();
+            if self.wave_accumulator > 0xFFFF {
+                self.wave_accumulator &= 0xFFFF;
+                self.wave_position = (self.wave_position + 1) & 0x3F;
+                if self.wave_position == 0 {
+                    self.output_gain = self.volume.gain.min(32);
+                }
+            }
+        }
+        if !self.wave_write {
+            self.output = self.wave[self.wave_position as usize];
+        }
+    }
+    fn step_modulation(&mut self) {
+        let step = self.mod_table[self.mod_position as usize];
+        self.mod_counter = if step == 4 {
+            0
+        } else {
+            // 7 bit signed wrap around
+            ((self.mod_counter.wrapping_add(MOD_STEPS[step as usize]) as u8) << 1) as i8 >> 1
+        };
+        self.mod_position = (self.mod_position + 1) & 0x3F;
+    }
+    /// Frequency bent by the modulation unit
+    fn pitch(&self) -> u32 {
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * self.modulation.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        let mut temp = self.frequency as i32 * temp;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        (self.frequency as i32 + temp).max(0) as u32
+    }
+    /// At full volume the channel is about 2.4 times as loud as an APU pulse
+    pub fn output(&self) -> f32 {
+        let level = self.output as f32 * self.output_gain as f32 / (63.0 * 32.0);
+        level * MASTER_VOLUME[self.master_volume as usize] * 2.4 * pulse_out(15.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mod_table_writes_two_steps() {
+        let mut audio = FdsAudio::new();
+        for val in 0..32 {
+            audio.write(0x4088, val);
+        }
+        assert_eq!(audio.mod_position, 0);
+        assert_eq!(audio.mod_table[..6], [0, 0, 1, 1, 2, 2]);
+        assert_eq!(audio.mod_table[62..], [7, 7]);
+    }
+
+    #[test]
+    fn mod_table_write_at_odd_position() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4086, 0xFF);
+        audio.write(0x4087, 0x0F);
+        while audio.mod_position == 0 {
+            audio.clock();
+        }
+        // Ignored while the modulator runs
+        audio.write(0x4088, 0x01);
+        assert_eq!(audio.mod_table, [0; 64]);
+        audio.write(0x4087, 0x80);
+        audio.write(0x4088, 0x02);
+        audio.write(0x4088, 0x03);
+        assert_eq!(audio.mod_table[..6], [0, 2, 2, 3, 3, 0]);
+        assert_eq!(audio.mod_position, 5);
+        // Wraps around the end of the table
+        for _ in 0..29 {
+            audio.write(0x4088, 0x05);
+        }
+        audio.write(0x4088, 0x06);
+        assert_eq!(audio.mod_position, 1);
+        assert_eq!(audio.mod_table[63], 6);
+        assert_eq!(audio.mod_table[0], 6);
+    }
+}
@@ -0,0 +1,75 @@
+use core::f32::consts::PI;
+
+/// First order high-pass filter
+pub struct HighPass {
+    alpha: f32,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+state!(HighPass { prev_in, prev_out });
+
+impl HighPass {
+    pub fn new(cutoff: f32, sample_rate: u32) -> HighPass {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        HighPass {
+            alpha: rc / (rc + dt),
+            prev_in: 0.0,
+            prev_out: 0.0,
+        }
+    }
+    pub fn apply(&mut self, sample: f32) -> f32 {
+        self.prev_out = self.alpha * (self.prev_out + sample - self.prev_in);
+        self.prev_in = sample;
+        self.prev_out
+    }
+}
+
+/// First order low-pass filter
+pub struct LowPass {
+    alpha: f32,
+    prev_out: f32,
+}
+
+state!(LowPass { prev_out });
+
+impl LowPass {
+    pub fn new(cutoff: f32, sample_rate: u32) -> LowPass {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        LowPass {
+            alpha: dt / (rc + dt),
+            prev_out: 0.0,
+        }
+    }
+    pub fn apply(&mut self, sample: f32) -> f32 {
+        self.prev_out += self.alpha * (sample - self.prev_out);
+        self.prev_out
+    }
+}
+
+/// Filters between the 2A03 and the audio jack of a NES:
+/// high-pass at 90 Hz and 440 Hz, low-pass at 14 kHz
+pub struct OutputFilter {
+    high1: HighPass,
+    high2: HighPass,
+    low: LowPass,
+}
+
+state!(OutputFilter { high1, high2, low });
+
+impl OutputFilter {
+    pub fn new(sample_rate: u32) -> OutputFilter {
+        OutputFilter {
+            high1: HighPass::new(90.0, sample_rate),
+            high2: HighPass::new(440.0, sample_rate),
+            low: LowPass::new(14000.0, sample_rate),
+        }
+    }
+    pub fn apply(&mut self, sample: f32) -> f32 {
+        let sample = self.high1.apply(sample);
+        let sample = self.high2.apply(sample);
+        self.low.apply(sample)
+    }
+}
@@ -0,0 +1,52 @@
+/// Length counter load values, indexed by the top 5 bits of $4003/$4007/$400B/$400F
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// Silences a channel after a programmed time, clocked by half frames.
+/// see http://wiki.nesdev.com/w/index.php/APU_Length_Counter
+pub struct LengthCounter {
+    /// Channel enable bit of $4015
+    enabled: bool,
+    /// Halt flag, shared with the envelope loop flag or the triangle control flag
+    pub halt: bool,
+    counter: u8,
+}
+
+state!(LengthCounter {
+    enabled,
+    halt,
+    counter
+});
+
+impl LengthCounter {
+    pub fn new() -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            counter: 0,
+        }
+    }
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+    /// Load the counter from the 5 bit index written to the channel's 4th register
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[index as usize & 0x1F];
+        }
+    }
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+    /// True if the channel isn't silenced
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+}
@@ -0,0 +1,74 @@
+/// Nonlinear DAC of the 2A03, approximated with lookup tables.
+/// see http://wiki.nesdev.com/w/index.php/APU_Mixer
+pub struct Mixer {
+    /// Indexed by pulse1 + pulse2
+    pulse: [f32; 31],
+    /// Indexed by 3 * triangle + 2 * noise + dmc
+    tnd: [f32; 203],
+}
+
+impl Mixer {
+    pub fn new() -> Mixer {
+        let mut pulse = [0.0; 31];
+        for (n, out) in pulse.iter_mut().enumerate().skip(1) {
+            *out = pulse_out(n as f32);
+        }
+        let mut tnd = [0.0; 203];
+        for (n, out) in tnd.iter_mut().enumerate().skip(1) {
+            *out = tnd_out(n as f32);
+        }
+        Mixer { pulse, tnd }
+    }
+    /// Mix the levels of pulse 1, pulse 2, triangle, noise and DMC, output is in 0.0..1.0
+    pub fn mix(&self, levels: [u8; 5]) -> f32 {
+        let [pulse1, pulse2, triangle, noise, dmc] = levels;
+        self.pulse[(pulse1 + pulse2) as usize]
+            + self.tnd[3 * triangle as usize + 2 * noise as usize + dmc as usize]
+    }
+}
+
+/// Output of the pulse DAC for pulse1 + pulse2 = `n`
+pub fn pulse_out(n: f32) -> f32 {
+    if n == 0.0 {
+        0.0
+    } else {
+        95.52 / (8128.0 / n + 100.0)
+    }
+}
+
+/// Output of the triangle/noise/DMC DAC for 3 * triangle + 2 * noise + dmc = `n`
+pub fn tnd_out(n: f32) -> f32 {
+    if n == 0.0 {
+        0.0
+    } else {
+        163.67 / (24329.0 / n + 100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silence_is_zero() {
+        assert_eq!(Mixer::new().mix([0; 5]), 0.0);
+    }
+
+    #[test]
+    fn tables_match_the_formulas() {
+        let mixer = Mixer::new();
+        assert_eq!(mixer.mix([15, 0, 0, 0, 0]), pulse_out(15.0));
+        assert_eq!(mixer.mix([0, 0, 15, 15, 127]), tnd_out(202.0));
+        assert!((pulse_out(30.0) - 0.2575).abs() < 1e-4);
+        assert!((tnd_out(202.0) - 0.7425).abs() < 1e-4);
+    }
+
+    #[test]
+    fn nonlinear() {
+        let mixer = Mixer::new();
+        let one = mixer.mix([15, 0, 0, 0, 0]);
+        let both = mixer.mix([15, 15, 0, 0, 0]);
+        assert!(both > one && both < 2.0 * one);
+        assert!(mixer.mix([15, 15, 15, 15, 127]) < 1.0);
+    }
+}
@@ -0,0 +1,410 @@
+mod dmc;
+mod envelope;
+mod fds;
+mod filter;
+mod length;
+mod mixer;
+mod n163;
+mod noise;
+mod pulse;
+mod resampler;
+mod speaker;
+mod sunsoft5b;
+mod triangle;
+mod vrc6;
+mod vrc7;
+
+use self::{
+    dmc::Dmc, filter::OutputFilter, mixer::Mixer, noise::Noise, resampler::Resampler,
+    triangle::Triangle,
+};
+pub(crate) use self::{
+    fds::FdsAudio,
+    mixer::{pulse_out, tnd_out},
+    n163::N163Audio,
+    pulse::Pulse,
+    sunsoft5b::Sunsoft5bAudio,
+    vrc6::Vrc6Audio,
+    vrc7::Vrc7Audio,
+};
+pub use speaker::Speaker;
+
+bitflags! {
+    /// APU status $4015 write: ---D NT21, read: IF-D NT21
+    struct Status:u8{
+        /// 7 DMC interrupt (read)
+        const I = 1 << 7;
+        /// 6 frame interrupt (read)
+        const F = 1 << 6;
+        /// 4 DMC enable / DMC active
+        const D = 1 << 4;
+        /// 3 noise enable / length counter > 0
+        const N = 1 << 3;
+        /// 2 triangle enable / length counter > 0
+        const T = 1 << 2;
+        /// 1 pulse 2 enable / length counter > 0
+        const P2 = 1 << 1;
+        /// 0 pulse 1 enable / length counter > 0
+        const P1 = 1 << 0;
+    }
+}
+
+bitflags! {
+    /// Frame counter $4017 MI-- ---- Write Only
+    struct FrameCounter:u8{
+        /// 7 sequencer mode 0(4 step) 1(5 step)
+        const M = 1 << 7;
+        /// 6 IRQ inhibit
+        const I = 1 << 6;
+    }
+}
+
+state_bits!(FrameCounter);
+
+/// Audio Processing Unit of the 2A03 (NTSC).
+/// see http://wiki.nesdev.com/w/index.php/APU
+pub struct APU {
+    /// $4000-$4003
+    pulse1: Pulse,
+    /// $4004-$4007
+    pulse2: Pulse,
+    /// $4008-$400B
+    triangle: Triangle,
+    /// $400C-$400F
+    noise: Noise,
+    /// $4010-$4013
+    dmc: Dmc,
+    /// $4017
+    mode: FrameCounter,
+    /// CPU cycles since the frame sequencer started
+    frame_cycles: usize,
+    /// CPU cycles until a $4017 write restarts the sequencer
+    frame_reset: Option<u8>,
+    frame_irq: bool,
+    /// Odd CPU cycle, the pulse timers are clocked on even ones
+    odd: bool,
+    mixer: Mixer,
+    resampler: Resampler,
+    filter: OutputFilter,
+}
+
+state!(APU {
+    pulse1,
+    pulse2,
+    triangle,
+    noise,
+    dmc,
+    mode,
+    frame_cycles,
+    frame_reset,
+    frame_irq,
+    odd,
+    resampler,
+    filter
+});
+
+impl APU {
+    pub fn new(sample_rate: u32) -> APU {
+        APU {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            mode: FrameCounter::empty(),
+            frame_cycles: 0,
+            frame_reset: None,
+            frame_irq: false,
+            odd: false,
+            mixer: Mixer::new(),
+            resampler: Resampler::new(sample_rate),
+            filter: OutputFilter::new(sample_rate),
+        }
+    }
+    /// Reset silences all channels and restarts the frame sequencer
+    /// with the last mode written to $4017
+    pub fn reset(&mut self) {
+        self.set_channel(0);
+        self.set_mode(self.mode.bits());
+        self.frame_irq = false;
+    }
+    /// $4000-$4013 are write only
+    pub fn loadb(&mut self, addr: u16) -> u8 {
+        crate::mapper::open_bus(addr)
+    }
+    pub fn storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr & 0x3, val),
+            0x4004..=0x4007 => self.pulse2.write(addr & 0x3, val),
+            0x4008..=0x400B => self.triangle.write(addr & 0x3, val),
+            0x400C..=0x400F => self.noise.write(addr & 0x3, val),
+            0x4010..=0x4013 => self.dmc.write(addr & 0x3, val),
+            _ => unreachable!(),
+        }
+    }
+    /// Read $4015, clears the frame interrupt flag but not the DMC one
+    pub fn get_channel(&mut self) -> u8 {
+        let mut status = Status::empty();
+        status.set(Status::P1, self.pulse1.length.active());
+        status.set(Status::P2, self.pulse2.length.active());
+        status.set(Status::T, self.triangle.length.active());
+        status.set(Status::N, self.noise.length.active());
+        status.set(Status::D, self.dmc.active());
+        status.set(Status::F, self.frame_irq);
+        status.set(Status::I, self.dmc.irq);
+        self.frame_irq = false;
+        status.bits()
+    }
+    /// Write $4015
+    pub fn set_channel(&mut self, val: u8) {
+        let status = Status::from_bits_truncate(val);
+        self.pulse1.length.set_enabled(status.contains(Status::P1));
+        self.pulse2.length.set_enabled(status.contains(Status::P2));
+        self.triangle.length.set_enabled(status.contains(Status::T));
+        self.noise.length.set_enabled(status.contains(Status::N));
+        self.dmc.set_enabled(status.contains(Status::D));
+    }
+    /// Write $4017
+    pub fn set_mode(&mut self, val: u8) {
+        self.mode = FrameCounter::from_bits_truncate(val);
+        if self.mode.contains(FrameCounter::I) {
+            self.frame_irq = false;
+        }
+        // The sequencer restarts 3 or 4 CPU cycles later depending on the APU cycle parity
+        self.frame_reset = Some(if self.odd { 4 } else { 3 });
+    }
+    /// Level of the frame counter IRQ output
+    pub fn frame_irq(&self) -> bool {
+        self.frame_irq
+    }
+    /// Level of the DMC IRQ output
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc.irq
+    }
+    /// Address of the sample byte the DMC wants to fetch
+    pub fn dmc_request(&self) -> Option<u16> {
+        self.dmc.dma_request()
+    }
+    /// Hand the byte fetched at `dmc_request` to the DMC
+    pub fn dmc_fill(&mut self, val: u8) {
+        self.dmc.dma_fill(val);
+    }
+    /// Run the APU for one CPU cycle, send finished samples to the speaker.
+    /// `expansion` is the cartridge audio, already on the scale of the mixer output.
+    pub fn step<A: Speaker>(&mut self, speaker: &mut A, expansion: f32) {
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if !self.odd {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd = !self.odd;
+        self.step_frame_counter();
+        let amplitude = self.mixer.mix(self.output()) + expansion;
+        if let Some(sample) = self.resampler.push(amplitude) {
+            speaker.push_sample(self.filter.apply(sample));
+        }
+    }
+    fn step_frame_counter(&mut self) {
+        if let Some(delay) = self.frame_reset {
+            if delay == 0 {
+                self.frame_reset = None;
+                self.frame_cycles = 0;
+                if self.mode.contains(FrameCounter::M) {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+            } else {
+                self.frame_reset = Some(delay - 1);
+            }
+        }
+        self.frame_cycles += 1;
+        let five_step = self.mode.contains(FrameCounter::M);
+        match (five_step, self.frame_cycles) {
+            (_, 7457) | (_, 22371) => self.quarter_frame(),
+            (_, 14913) => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+            (false, 29828) => self.set_frame_irq(),
+            (false, 29829) => {
+                self.quarter_frame();
+                self.half_frame();
+                self.set_frame_irq();
+            }
+            (false, 29830) => {
+                self.set_frame_irq();
+                self.frame_cycles = 0;
+            }
+            (true, 37281) => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+            (true, 37282) => self.frame_cycles = 0,
+            _ => {}
+        }
+    }
+    fn set_frame_irq(&mut self) {
+        if !self.mode.contains(FrameCounter::I) {
+            self.frame_irq = true;
+        }
+    }
+    /// Clock envelopes and the triangle's linear counter
+    fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+    /// Clock length counters and sweep units
+    fn half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+    /// Output levels of pulse 1, pulse 2, triangle, noise (4 bit) and DMC (7 bit)
+    fn output(&self) -> [u8; 5] {
+        [
+            self.pulse1.output(),
+            self.pulse2.output(),
+            self.triangle.output(),
+            self.noise.output(),
+            self.dmc.output(),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoSpeaker;
+
+    impl Speaker for NoSpeaker {
+        fn sample_rate(&self) -> u32 {
+            44100
+        }
+        fn push_sample(&mut self, _sample: f32) {}
+    }
+
+    fn run(apu: &mut APU, cycles: usize) {
+        for _ in 0..cycles {
+            apu.step(&mut NoSpeaker, 0.0);
+        }
+    }
+
+    #[test]
+    fn registers_are_write_only() {
+        let mut apu = APU::new(44100);
+        assert_eq!(apu.loadb(0x4000), 0x40);
+        assert_eq!(apu.loadb(0x4013), 0x40);
+    }
+
+    #[test]
+    fn frame_irq_in_four_step_mode() {
+        let mut apu = APU::new(44100);
+        run(&mut apu, 29827);
+        assert_eq!(apu.get_channel() & 0x40, 0);
+        run(&mut apu, 1);
+        assert!(apu.frame_irq());
+        // Set again on the next 2 cycles, reading $4015 clears it after that
+        run(&mut apu, 2);
+        assert_eq!(apu.get_channel() & 0x40, 0x40);
+        assert_eq!(apu.get_channel() & 0x40, 0);
+        assert!(!apu.frame_irq());
+        run(&mut apu, 29830);
+        assert!(apu.frame_irq());
+        // The inhibit flag clears it
+        apu.set_mode(0x40);
+        assert!(!apu.frame_irq());
+        run(&mut apu, 29830 * 2);
+        assert!(!apu.frame_irq());
+    }
+
+    #[test]
+    fn no_frame_irq_in_five_step_mode() {
+        let mut apu = APU::new(44100);
+        apu.set_mode(0x80);
+        run(&mut apu, 37282 * 2);
+        assert_eq!(apu.get_channel() & 0x40, 0);
+    }
+
+    /// Enable pulse 1 with a length of 2 half frames
+    fn short_pulse(apu: &mut APU) {
+        apu.set_channel(0x01);
+        apu.storeb(0x4003, 3 << 3);
+        assert_eq!(apu.get_channel() & 0x01, 0x01);
+    }
+
+    #[test]
+    fn length_counter_in_four_step_mode() {
+        let mut apu = APU::new(44100);
+        short_pulse(&mut apu);
+        // Half frames at 14913 and 29829
+        run(&mut apu, 29828);
+        assert_eq!(apu.get_channel() & 0x01, 0x01);
+        run(&mut apu, 1);
+        assert_eq!(apu.get_channel() & 0x01, 0);
+    }
+
+    #[test]
+    fn length_counter_in_five_step_mode() {
+        let mut apu = APU::new(44100);
+        short_pulse(&mut apu);
+        // Writing $4017 with the 5 step mode clocks a half frame when the
+        // sequencer restarts 3 cycles later, then half frames are at 14913
+        apu.set_mode(0x80);
+        run(&mut apu, 3 + 14912);
+        assert_eq!(apu.get_channel() & 0x01, 0x01);
+        run(&mut apu, 1);
+        assert_eq!(apu.get_channel() & 0x01, 0);
+    }
+
+    #[test]
+    fn length_counter_halt_and_disable() {
+        let mut apu = APU::new(44100);
+        short_pulse(&mut apu);
+        apu.storeb(0x4000, 0x20);
+        run(&mut apu, 29830 * 2);
+        assert_eq!(apu.get_channel() & 0x01, 0x01);
+        apu.set_channel(0x00);
+        assert_eq!(apu.get_channel() & 0x01, 0);
+        // Can't be loaded while the channel is disabled
+        apu.storeb(0x4003, 3 << 3);
+        assert_eq!(apu.get_channel() & 0x01, 0);
+    }
+
+    /// Largest output of pulse 1 over a few duty cycles with timer period `period`
+    fn pulse_peak(sweep: u8, period: u16) -> u8 {
+        let mut apu = APU::new(44100);
+        apu.set_channel(0x01);
+        // Duty 50%, constant volume 15
+        apu.storeb(0x4000, 0xBF);
+        apu.storeb(0x4001, sweep);
+        apu.storeb(0x4002, period as u8);
+        apu.storeb(0x4003, (period >> 8) as u8);
+        let mut peak = 0;
+        for _ in 0..(period as usize + 1) * 32 {
+            apu.step(&mut NoSpeaker, 0.0);
+            peak = peak.max(apu.output()[0]);
+        }
+        peak
+    }
+
+    #[test]
+    fn sweep_mutes_pulse() {
+        assert_eq!(pulse_peak(0x00, 8), 15);
+        assert_eq!(pulse_peak(0x00, 7), 0);
+        // Even when disabled, the target period of shift 0 is twice the period
+        assert_eq!(pulse_peak(0x00, 0x3FF), 15);
+        assert_eq!(pulse_peak(0x00, 0x400), 0);
+        assert_eq!(pulse_peak(0x01, 0x555), 15);
+        assert_eq!(pulse_peak(0x01, 0x556), 0);
+        // Decreasing periods never overflow
+        assert_eq!(pulse_peak(0x08, 0x7FF), 15);
+    }
+}
@@ -0,0 +1,102 @@
+use super::mixer::pulse_out;
+
+/// CPU cycles spent on each channel
+const CHANNEL_CYCLES: u8 = 15;
+
+/// Expansion audio of the Namco 163, up to 8 wavetable channels.
+/// Waveforms and channel registers share 128 bytes of internal RAM, channel
+/// `n` uses $40 + 8 * n to $47 + 8 * n. The chip updates one channel every
+/// 15 CPU cycles and outputs only that one, so more channels play quieter.
+/// see http://wiki.nesdev.com/w/index.php/Namco_163_audio
+pub struct N163Audio {
+    ram: [u8; 0x80],
+    /// $F800 A-- address, A: increment after every $4800 access
+    address: u8,
+    auto_increment: bool,
+    /// CPU cycles until the next channel update
+    cycles: u8,
+    /// Channel being updated
+    channel: u8,
+    output: i8,
+}
+
+state!(N163Audio {
+    ram,
+    address,
+    auto_increment,
+    cycles,
+    channel,
+    output
+});
+
+impl N163Audio {
+    pub fn new() -> N163Audio {
+        N163Audio {
+            ram: [0; 0x80],
+            address: 0,
+            auto_increment: false,
+            cycles: CHANNEL_CYCLES,
+            channel: 7,
+            output: 0,
+        }
+    }
+    /// Write $F800
+    pub fn set_address(&mut self, val: u8) {
+        self.address = val & 0x7F;
+        self.auto_increment = val & 0x80 != 0;
+    }
+    /// Read $4800
+    pub fn read_data(&mut self) -> u8 {
+        let val = self.ram[self.address as usize];
+        self.increment();
+        val
+    }
+    /// Write $4800
+    pub fn write_data(&mut self, val: u8) {
+        self.ram[self.address as usize] = val;
+        self.increment();
+    }
+    fn increment(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7F;
+        }
+    }
+    /// Called once every CPU cycle
+    pub fn clock(&mut self) {
+        self.cycles -= 1;
+        if self.cycles > 0 {
+            return;
+        }
+        self.cycles = CHANNEL_CYCLES;
+        // Channels 7 down to 8 - enabled are updated in turn
+        let enabled = (self.ram[0x7F] >> 4) & 0x7;
+        self.channel = if self.channel <= 7 - enabled {
+            7
+        } else {
+            self.channel - 1
+        };
+        self.output = self.update(self.channel as usize);
+    }
+    /// Advance the phase of channel `n` and return its level
+    fn update(&mut self, n: usize) -> i8 {
+        let base = 0x40 + n * 8;
+        let reg = |i: usize| self.ram[base + i] as u32;
+        let frequency = reg(0) | reg(2) << 8 | (reg(4) & 0x3) << 16;
+        let phase = reg(1) | reg(3) << 8 | reg(5) << 16;
+        let length = 256 - (reg(4) & 0xFC);
+        let offset = reg(6);
+        let volume = (reg(7) & 0x0F) as i8;
+        let phase = (phase + frequency) % (length << 16);
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+        // 4 bit samples, the low nibble first
+        let index = ((phase >> 16) + offset) & 0xFF;
+        let sample = (self.ram[index as usize >> 1] >> ((index & 1) * 4)) & 0x0F;
+        (sample as i8 - 8) * volume
+    }
+    /// A lone channel at full volume is about as loud as an APU pulse
+    pub fn output(&self) -> f32 {
+        self.output as f32 / (8.0 * 15.0) * pulse_out(15.0)
+    }
+}
@@ -0,0 +1,82 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+
+/// Timer periods in CPU cycles (NTSC)
+const PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+/// Pseudo-random noise channel, $400C-$400F.
+/// see http://wiki.nesdev.com/w/index.php/APU_Noise
+pub struct Noise {
+    /// 15 bit linear feedback shift register
+    shift: u16,
+    /// Take the feedback from bit 6 instead of bit 1, gives a short metallic loop
+    mode: bool,
+    period: u16,
+    timer: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+state!(Noise {
+    shift,
+    mode,
+    period,
+    timer,
+    envelope,
+    length
+});
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            shift: 1,
+            mode: false,
+            period: PERIOD_TABLE[0],
+            timer: 0,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+        }
+    }
+    pub fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            // --LC VVVV
+            0 => {
+                self.envelope.write(val);
+                self.length.halt = self.envelope.looping;
+            }
+            1 => {}
+            // M--- PPPP
+            2 => {
+                self.mode = val & 0x80 != 0;
+                self.period = PERIOD_TABLE[val as usize & 0xF];
+            }
+            // llll l---
+            3 => {
+                self.length.load(val >> 3);
+                self.envelope.restart();
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+    /// 4 bit output level
+    pub fn output(&self) -> u8 {
+        if self.shift & 1 != 0 || !self.length.active() {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use super::envelope::Envelope;
+use super::length::LengthCounter;
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// Square wave channel, $4000-$4003 and $4004-$4007.
+/// see http://wiki.nesdev.com/w/index.php/APU_Pulse
+pub struct Pulse {
+    /// Pulse 1 negates the sweep with one's complement, pulse 2 with two's complement
+    ones_complement: bool,
+    /// Expansion pulses have no sweep unit, so it can't mute them either
+    sweep: bool,
+    duty: u8,
+    /// Position in the 8 step duty sequence
+    step: u8,
+    /// 11 bit timer period
+    period: u16,
+    timer: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+state!(Pulse {
+    ones_complement,
+    sweep,
+    duty,
+    step,
+    period,
+    timer,
+    envelope,
+    length,
+    sweep_enabled,
+    sweep_period,
+    sweep_negate,
+    sweep_shift,
+    sweep_reload,
+    sweep_divider
+});
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            ones_complement,
+            sweep: true,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+        }
+    }
+    /// Pulse channel of expansion audio chips like the MMC5
+    pub fn without_sweep() -> Pulse {
+        Pulse {
+            sweep: false,
+            ..Pulse::new(false)
+        }
+    }
+    /// Write the `reg`th register of the channel
+    pub fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            // DDLC VVVV
+            0 => {
+                self.duty = val >> 6;
+                self.envelope.write(val);
+                self.length.halt = self.envelope.looping;
+            }
+            // EPPP NSSS
+            1 => {
+                self.sweep_enabled = val & 0x80 != 0;
+                self.sweep_period = (val >> 4) & 0x7;
+                self.sweep_negate = val & 0x08 != 0;
+                self.sweep_shift = val & 0x7;
+                self.sweep_reload = true;
+            }
+            // LLLL LLLL
+            2 => self.period = (self.period & 0x700) | val as u16,
+            // llll lHHH
+            3 => {
+                self.period = (self.period & 0xFF) | ((val as u16 & 0x7) << 8);
+                self.length.load(val >> 3);
+                self.step = 0;
+                self.envelope.restart();
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Clocked every APU cycle (2 CPU cycles)
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) & 0x7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+    /// Period the sweep unit is adjusting to
+    fn target_period(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = change + self.ones_complement as u16;
+            self.period.saturating_sub(change)
+        } else {
+            self.period + change
+        }
+    }
+    /// The sweep unit mutes the channel even when it's disabled
+    fn muted(&self) -> bool {
+        self.sweep && (self.period < 8 || self.target_period() > 0x7FF)
+    }
+    /// Clocked by half frames
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.target_period();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+    /// 4 bit output level
+    pub fn output(&self) -> u8 {
+        if self.muted() || !self.length.active() {
+            0
+        } else {
+            DUTY_TABLE[self.duty as usize][self.step as usize] * self.envelope.output()
+        }
+    }
+}
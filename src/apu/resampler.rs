@@ -0,0 +1,189 @@
+use core::f64::consts::PI;
+
+/// NTSC CPU clock in Hz
+pub const CPU_CLOCK: f64 = 1_789_773.0;
+/// Kernel phases per output sample
+const PHASES: usize = 64;
+/// Kernel width in output samples
+const TAPS: usize = 32;
+/// Length of the circular buffer, a power of two not smaller than TAPS
+const BUFFER: usize = 64;
+/// Cutoff frequency relative to the output sample rate
+const CUTOFF: f64 = 0.45;
+
+/// Band-limited resampler from the CPU clock to the host sample rate.
+///
+/// The mixed APU output is a step function that changes at most once per CPU
+/// cycle. Instead of low-pass filtering at the CPU clock, every change of the
+/// level is added to the output buffer as a band-limited step, see blargg's
+/// blip_buf. A step is a windowed sinc impulse added to the buffer of
+/// differences, which is integrated when output samples are finished.
+pub struct Resampler {
+    /// Output samples per CPU cycle
+    ratio: f64,
+    /// Time of current CPU cycle in output samples, relative to `index`
+    time: f64,
+    /// Next output sample to be finished
+    index: usize,
+    buffer: [f32; BUFFER],
+    /// Sum of all finished differences
+    level: f32,
+    /// Input level of last CPU cycle
+    amplitude: f32,
+    /// Impulse response for each phase
+    kernel: [[f32; TAPS]; PHASES],
+}
+
+state!(Resampler {
+    time,
+    index,
+    buffer,
+    level,
+    amplitude
+});
+
+impl Resampler {
+    pub fn new(sample_rate: u32) -> Resampler {
+        let mut kernel = [[0.0; TAPS]; PHASES];
+        let half = (TAPS / 2) as f64;
+        for (phase, taps) in kernel.iter_mut().enumerate() {
+            let offset = phase as f64 / PHASES as f64;
+            let mut sum = 0.0;
+            let mut impulse = [0.0; TAPS];
+            for (k, value) in impulse.iter_mut().enumerate() {
+                // Distance from the step, in output samples
+                let x = k as f64 + 1.0 - half - offset;
+                *value = sinc(2.0 * CUTOFF * x) * blackman(x / half);
+                sum += *value;
+            }
+            // Normalize so a step keeps its height
+            for (tap, value) in taps.iter_mut().zip(impulse.iter()) {
+                *tap = (value / sum) as f32;
+            }
+        }
+        Resampler {
+            ratio: sample_rate as f64 / CPU_CLOCK,
+            time: 0.0,
+            index: 0,
+            buffer: [0.0; BUFFER],
+            level: 0.0,
+            amplitude: 0.0,
+            kernel,
+        }
+    }
+    /// Feed the level of one CPU cycle, return an output sample if one is finished
+    pub fn push(&mut self, amplitude: f32) -> Option<f32> {
+        if amplitude != self.amplitude {
+            let delta = amplitude - self.amplitude;
+            self.amplitude = amplitude;
+            let phase = (self.time * PHASES as f64) as usize;
+            for (k, tap) in self.kernel[phase].iter().enumerate() {
+                self.buffer[(self.index + k) & (BUFFER - 1)] += delta * tap;
+            }
+        }
+        self.time += self.ratio;
+        if self.time < 1.0 {
+            return None;
+        }
+        self.time -= 1.0;
+        self.level += self.buffer[self.index];
+        self.buffer[self.index] = 0.0;
+        self.index = (self.index + 1) & (BUFFER - 1);
+        Some(self.level)
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        sin(PI * x) / (PI * x)
+    }
+}
+
+/// Blackman window on -1.0..1.0
+fn blackman(x: f64) -> f64 {
+    0.42 + 0.5 * cos(PI * x) + 0.08 * cos(2.0 * PI * x)
+}
+
+/// core has no trigonometric functions, Taylor series are precise enough for the kernel
+pub(super) fn sin(x: f64) -> f64 {
+    let mut x = x;
+    while x > PI {
+        x -= 2.0 * PI;
+    }
+    while x < -PI {
+        x += 2.0 * PI;
+    }
+    // sin(PI - x) = sin(x)
+    if x > PI / 2.0 {
+        x = PI - x;
+    } else if x < -PI / 2.0 {
+        x = -PI - x;
+    }
+    let x2 = x * x;
+    x * (1.0
+        - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0 * (1.0 - x2 / 72.0 * (1.0 - x2 / 110.0)))))
+}
+
+fn cos(x: f64) -> f64 {
+    sin(x + PI / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_per_second() {
+        let mut resampler = Resampler::new(44100);
+        let samples = (0..CPU_CLOCK as usize)
+            .filter(|_| resampler.push(0.0).is_some())
+            .count();
+        assert!((44099..=44100).contains(&samples));
+    }
+
+    #[test]
+    fn step_settles_at_its_height() {
+        let mut resampler = Resampler::new(48000);
+        let mut last = 0.0;
+        for _ in 0..2000 {
+            if let Some(sample) = resampler.push(0.5) {
+                last = sample;
+            }
+        }
+        // The kernel is normalized, the step keeps its height
+        assert!((last - 0.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn step_is_delayed_by_half_the_kernel() {
+        let mut resampler = Resampler::new(48000);
+        let mut samples = [0.0; TAPS];
+        let mut n = 0;
+        while n < TAPS {
+            if let Some(sample) = resampler.push(1.0) {
+                samples[n] = sample;
+                n += 1;
+            }
+        }
+        assert!(samples[0].abs() < 0.01);
+        assert!((samples[TAPS - 1] - 1.0).abs() < 0.01);
+        // It rises through the middle of the kernel
+        assert!(samples[TAPS / 2 - 2] < 0.5 && samples[TAPS / 2 + 1] > 0.5);
+    }
+
+    #[test]
+    fn taylor_sine() {
+        for &(x, sin_x) in [
+            (0.0, 0.0),
+            (PI / 6.0, 0.5),
+            (PI / 2.0, 1.0),
+            (-7.0, -0.656987),
+        ]
+        .iter()
+        {
+            assert!((sin(x) - sin_x).abs() < 1e-5);
+        }
+    }
+}
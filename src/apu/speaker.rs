@@ -0,0 +1,7 @@
+/// Host audio output, receives mono samples at its own sample rate
+pub trait Speaker {
+    /// Sample rate in Hz, e.g. 44100 or 48000
+    fn sample_rate(&self) -> u32;
+    /// Receive the next sample, roughly in -1.0..1.0
+    fn push_sample(&mut self, sample: f32);
+}
@@ -0,0 +1,253 @@
+use super::mixer::pulse_out;
+
+/// The chip runs its tone, noise and envelope counters at CPU / 16
+const DIVIDER: u8 = 16;
+/// 10^(-1.5 / 20), the gain of one volume step
+const STEP_GAIN: f32 = 0.841_395_1;
+
+/// Square wave tone generator
+struct Tone {
+    /// 12 bit period
+    period: u16,
+    counter: u16,
+    output: bool,
+}
+
+state!(Tone {
+    period,
+    counter,
+    output
+});
+
+impl Tone {
+    fn new() -> Tone {
+        Tone {
+            period: 0,
+            counter: 0,
+            output: false,
+        }
+    }
+    /// The output flips every `period` clocks, period 0 acts like 1
+    fn clock(&mut self) {
+        self.counter += 1;
+        if self.counter >= self.period.max(1) {
+            self.counter = 0;
+            self.output = !self.output;
+        }
+    }
+}
+
+/// Shared envelope, 32 steps of 1.5 dB
+struct Envelope {
+    /// $0B-$0C 16 bit period
+    period: u16,
+    counter: u32,
+    /// $0D ---- CAaH
+    /// C: continue, A: attack (count up), a: alternate, H: hold
+    shape: u8,
+    step: u8,
+    /// Counting up
+    attack: bool,
+    holding: bool,
+}
+
+state!(Envelope {
+    period,
+    counter,
+    shape,
+    step,
+    attack,
+    holding
+});
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            period: 0,
+            counter: 0,
+            shape: 0,
+            step: 0,
+            attack: false,
+            holding: true,
+        }
+    }
+    fn set_shape(&mut self, val: u8) {
+        self.shape = val & 0x0F;
+        self.attack = self.shape & 0x4 != 0;
+        self.step = 0;
+        self.counter = 0;
+        self.holding = false;
+    }
+    /// A step lasts `period` / 2 clocks, so a full ramp takes 16 * `period`
+    fn clock(&mut self) {
+        self.counter += 2;
+        if self.counter < self.period.max(1) as u32 {
+            return;
+        }
+        self.counter = 0;
+        if self.holding {
+            return;
+        }
+        if self.step < 31 {
+            self.step += 1;
+            return;
+        }
+        // End of a ramp
+        let (continues, alternate, hold) = (
+            self.shape & 0x8 != 0,
+            self.shape & 0x2 != 0,
+            self.shape & 0x1 != 0,
+        );
+        if !continues {
+            // Drop to silence and stay there
+            self.attack = false;
+            self.holding = true;
+        } else if hold {
+            if alternate {
+                self.attack = !self.attack;
+            }
+            self.holding = true;
+        } else {
+            if alternate {
+                self.attack = !self.attack;
+            }
+            self.step = 0;
+        }
+    }
+    /// Level between 0 and 31
+    fn level(&self) -> u8 {
+        match (self.holding, self.attack) {
+            (true, true) => 31,
+            (true, false) => 0,
+            (false, true) => self.step,
+            (false, false) => 31 - self.step,
+        }
+    }
+}
+
+/// Expansion audio of the Sunsoft 5B, a variant of the AY-3-8910 (YM2149F)
+/// with 3 square channels, a noise generator and an envelope.
+/// see http://wiki.nesdev.com/w/index.php/Sunsoft_5B_audio
+pub struct Sunsoft5bAudio {
+    /// $C000 register selected for $E000
+    address: u8,
+    tones: [Tone; 3],
+    /// $06 5 bit noise period
+    noise_period: u8,
+    noise_counter: u8,
+    /// 17 bit linear feedback shift register
+    noise_shift: u32,
+    /// $07 --NN NTTT
+    /// N: disable noise, T: disable tone, one bit for each channel
+    mixer: u8,
+    /// $08-$0A ---E VVVV
+    /// E: use the envelope, V: volume
+    volumes: [u8; 3],
+    envelope: Envelope,
+    /// CPU cycles until the next clock
+    divider: u8,
+    /// Level of each 5 bit volume step, 0 is silence
+    levels: [f32; 32],
+}
+
+state!(Sunsoft5bAudio {
+    address,
+    tones,
+    noise_period,
+    noise_counter,
+    noise_shift,
+    mixer,
+    volumes,
+    envelope,
+    divider
+});
+
+impl Sunsoft5bAudio {
+    pub fn new() -> Sunsoft5bAudio {
+        // A lone channel at full volume is about as loud as an APU pulse
+        let mut levels = [0.0; 32];
+        let mut level = pulse_out(15.0);
+        for out in levels.iter_mut().skip(1).rev() {
+            *out = level;
+            level *= STEP_GAIN;
+        }
+        Sunsoft5bAudio {
+            address: 0,
+            tones: [Tone::new(), Tone::new(), Tone::new()],
+            noise_period: 0,
+            noise_counter: 0,
+            noise_shift: 1,
+            mixer: 0,
+            volumes: [0; 3],
+            envelope: Envelope::new(),
+            divider: DIVIDER,
+            levels,
+        }
+    }
+    /// Write $C000
+    pub fn write_address(&mut self, val: u8) {
+        self.address = val;
+    }
+    /// Write $E000, register selected by the last $C000 write
+    pub fn write_data(&mut self, val: u8) {
+        match self.address {
+            0x00 | 0x02 | 0x04 => {
+                let tone = &mut self.tones[self.address as usize / 2];
+                tone.period = (tone.period & 0xF00) | val as u16;
+            }
+            0x01 | 0x03 | 0x05 => {
+                let tone = &mut self.tones[self.address as usize / 2];
+                tone.period = (tone.period & 0xFF) | (val as u16 & 0x0F) << 8;
+            }
+            0x06 => self.noise_period = val & 0x1F,
+            0x07 => self.mixer = val,
+            0x08..=0x0A => self.volumes[self.address as usize - 8] = val & 0x1F,
+            0x0B => self.envelope.period = (self.envelope.period & 0xFF00) | val as u16,
+            0x0C => self.envelope.period = (self.envelope.period & 0xFF) | (val as u16) << 8,
+            0x0D => self.envelope.set_shape(val),
+            _ => {}
+        }
+    }
+    /// Called once every CPU cycle
+    pub fn clock(&mut self) {
+        self.divider -= 1;
+        if self.divider > 0 {
+            return;
+        }
+        self.divider = DIVIDER;
+        for tone in self.tones.iter_mut() {
+            tone.clock();
+        }
+        // The noise runs at half the rate of the tones
+        self.noise_counter += 1;
+        if self.noise_counter >= self.noise_period.max(1) * 2 {
+            self.noise_counter = 0;
+            let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 1;
+            self.noise_shift = (self.noise_shift >> 1) | feedback << 16;
+        }
+        self.envelope.clock();
+    }
+    pub fn output(&self) -> f32 {
+        let noise = self.noise_shift & 1 != 0;
+        (0..3)
+            .map(|n| {
+                let tone = self.tones[n].output || self.mixer & (1 << n) != 0;
+                let noise = noise || self.mixer & (8 << n) != 0;
+                let volume = self.volumes[n];
+                let step = if volume & 0x10 != 0 {
+                    self.envelope.level()
+                } else if volume == 0 {
+                    0
+                } else {
+                    // 4 bit volumes are 3 dB steps
+                    volume * 2 + 1
+                };
+                if tone && noise {
+                    self.levels[step as usize]
+                } else {
+                    0.0
+                }
+            })
+            .sum()
+    }
+}
@@ -0,0 +1,92 @@
+use super::length::LengthCounter;
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+/// Triangle wave channel, $4008-$400B.
+/// see http://wiki.nesdev.com/w/index.php/APU_Triangle
+pub struct Triangle {
+    step: u8,
+    period: u16,
+    timer: u16,
+    pub length: LengthCounter,
+    /// Control flag, it's also the length counter halt flag
+    control: bool,
+    linear_reload_value: u8,
+    linear_reload: bool,
+    linear_counter: u8,
+}
+
+state!(Triangle {
+    step,
+    period,
+    timer,
+    length,
+    control,
+    linear_reload_value,
+    linear_reload,
+    linear_counter
+});
+
+impl Triangle {
+    pub fn new() -> Triangle {
+        Triangle {
+            step: 0,
+            period: 0,
+            timer: 0,
+            length: LengthCounter::new(),
+            control: false,
+            linear_reload_value: 0,
+            linear_reload: false,
+            linear_counter: 0,
+        }
+    }
+    pub fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            // CRRR RRRR
+            0 => {
+                self.control = val & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = val & 0x7F;
+            }
+            1 => {}
+            // LLLL LLLL
+            2 => self.period = (self.period & 0x700) | val as u16,
+            // llll lHHH
+            3 => {
+                self.period = (self.period & 0xFF) | ((val as u16 & 0x7) << 8);
+                self.length.load(val >> 3);
+                self.linear_reload = true;
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+    /// Clocked by quarter frames
+    pub fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+    /// 4 bit output level, the triangle keeps its level when silenced
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
@@ -0,0 +1,209 @@
+use super::mixer::pulse_out;
+
+/// Pulse channel of the VRC6, $9000-$9002 and $A000-$A002
+struct Vrc6Pulse {
+    /// Ignore the duty cycle and output the volume
+    constant: bool,
+    /// Number of the 16 steps the output is high is duty + 1
+    duty: u8,
+    volume: u8,
+    enabled: bool,
+    /// 12 bit timer period
+    period: u16,
+    timer: u16,
+    /// Counts down from 15
+    step: u8,
+}
+
+state!(Vrc6Pulse {
+    constant,
+    duty,
+    volume,
+    enabled,
+    period,
+    timer,
+    step
+});
+
+impl Vrc6Pulse {
+    fn new() -> Vrc6Pulse {
+        Vrc6Pulse {
+            constant: false,
+            duty: 0,
+            volume: 0,
+            enabled: false,
+            period: 0,
+            timer: 0,
+            step: 15,
+        }
+    }
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            // MDDD VVVV
+            0 => {
+                self.constant = val & 0x80 != 0;
+                self.duty = (val >> 4) & 0x7;
+                self.volume = val & 0x0F;
+            }
+            // FFFF FFFF
+            1 => self.period = (self.period & 0xF00) | val as u16,
+            // E--- FFFF
+            _ => {
+                self.period = (self.period & 0xFF) | (val as u16 & 0x0F) << 8;
+                self.enabled = val & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+        }
+    }
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        } else {
+            self.timer -= 1;
+        }
+    }
+    fn output(&self) -> u8 {
+        if self.enabled && (self.constant || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+/// Sawtooth channel of the VRC6, $B000-$B002
+struct Sawtooth {
+    /// 6 bit value added to the accumulator
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    /// The accumulator is increased every other clock and cleared on the 14th
+    step: u8,
+    accumulator: u8,
+}
+
+state!(Sawtooth {
+    rate,
+    enabled,
+    period,
+    timer,
+    step,
+    accumulator
+});
+
+impl Sawtooth {
+    fn new() -> Sawtooth {
+        Sawtooth {
+            rate: 0,
+            enabled: false,
+            period: 0,
+            timer: 0,
+            step: 0,
+            accumulator: 0,
+        }
+    }
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            // --AA AAAA
+            0 => self.rate = val & 0x3F,
+            // FFFF FFFF
+            1 => self.period = (self.period & 0xF00) | val as u16,
+            // E--- FFFF
+            _ => {
+                self.period = (self.period & 0xFF) | (val as u16 & 0x0F) << 8;
+                self.enabled = val & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step += 1;
+            if self.step == 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            } else if self.step & 1 == 0 {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+    /// The top 5 bits of the accumulator
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+/// Expansion audio of the Konami VRC6, two pulse channels and a sawtooth.
+/// see http://wiki.nesdev.com/w/index.php/VRC6_audio
+pub struct Vrc6Audio {
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    sawtooth: Sawtooth,
+    /// $9003 ---- -ABH
+    /// A: periods shifted right by 8, B: shifted right by 4, H: halt
+    control: u8,
+}
+
+state!(Vrc6Audio {
+    pulse1,
+    pulse2,
+    sawtooth,
+    control
+});
+
+impl Vrc6Audio {
+    pub fn new() -> Vrc6Audio {
+        Vrc6Audio {
+            pulse1: Vrc6Pulse::new(),
+            pulse2: Vrc6Pulse::new(),
+            sawtooth: Sawtooth::new(),
+            control: 0,
+        }
+    }
+    /// Write register `reg`, the address with the mapper's line swap undone
+    pub fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0x9000..=0x9002 => self.pulse1.write(reg & 0x3, val),
+            0x9003 => self.control = val,
+            0xA000..=0xA002 => self.pulse2.write(reg & 0x3, val),
+            0xB000..=0xB002 => self.sawtooth.write(reg & 0x3, val),
+            _ => {}
+        }
+    }
+    /// Called once every CPU cycle
+    pub fn clock(&mut self) {
+        if self.control & 0x01 != 0 {
+            return;
+        }
+        let shift = match self.control & 0x06 {
+            0 => 0,
+            0x02 => 4,
+            _ => 8,
+        };
+        self.pulse1.clock(shift);
+        self.pulse2.clock(shift);
+        self.sawtooth.clock(shift);
+    }
+    /// The 6 bit DAC sums the channels linearly, a pulse at full volume is
+    /// about as loud as one of the APU
+    pub fn output(&self) -> f32 {
+        let level = self.pulse1.output() + self.pulse2.output() + self.sawtooth.output();
+        level as f32 * pulse_out(15.0) / 15.0
+    }
+}
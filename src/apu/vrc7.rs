@@ -0,0 +1,397 @@
+use super::mixer::pulse_out;
+use super::resampler::sin;
+use crate::state::{Reader, State, Writer};
+use core::f64::consts::PI;
+
+/// CPU cycles per sample of the synthesizer, it runs at 3.58 MHz / 72
+const CYCLES_PER_SAMPLE: u8 = 36;
+const SAMPLE_RATE: f32 = 49_716.0;
+/// Envelope attenuation is 7 bits of 0.375 dB steps
+const ENV_MAX: f32 = 127.0;
+/// Attenuations past it are silent
+const GAIN_STEPS: usize = 256;
+/// 10^(-0.375 / 20), the gain of one attenuation step
+const STEP_GAIN: f32 = 0.957_745_2;
+/// Tremolo depth of 4.8 dB
+const AM_DEPTH: f32 = 12.8;
+const AM_RATE: f32 = 3.7;
+/// Vibrato depth of about 7 cents
+const VIB_DEPTH: f32 = 0.004;
+const VIB_RATE: f32 = 6.4;
+
+/// Built-in instruments 1-15, instrument 0 is the custom one at $00-$07
+static PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+/// Key scale level attenuation in 0.75 dB steps, indexed by the top 4 bits of F-Number
+static KSL_TABLE: [u8; 16] = [
+    0, 24, 32, 37, 40, 43, 45, 47, 48, 50, 51, 52, 53, 54, 55, 56,
+];
+/// Frequency multipliers times 2
+static MULTIPLIER: [u32; 16] = [1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Envelope {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+impl State for Envelope {
+    fn save(&self, w: &mut Writer) {
+        (*self as u8).save(w);
+    }
+    fn load(&mut self, r: &mut Reader) {
+        let mut val = 0u8;
+        val.load(r);
+        *self = match val {
+            0 => Envelope::Attack,
+            1 => Envelope::Decay,
+            2 => Envelope::Sustain,
+            _ => Envelope::Release,
+        };
+    }
+}
+
+/// Modulator or carrier of a channel
+#[derive(Clone, Copy)]
+struct Operator {
+    /// 19 bit phase, the top 10 bits index the sine table
+    phase: u32,
+    /// Attenuation in 0.375 dB steps
+    env: f32,
+    state: Envelope,
+}
+
+state!(Operator { phase, env, state });
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            phase: 0,
+            env: ENV_MAX,
+            state: Envelope::Release,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    /// 9 bit F-Number
+    fnum: u16,
+    /// Octave
+    block: u8,
+    /// Release slowly after key off
+    sustain: bool,
+    key: bool,
+    instrument: u8,
+    /// Carrier attenuation in 3 dB steps
+    volume: u8,
+    /// Modulator and carrier
+    ops: [Operator; 2],
+    /// Last two modulator outputs
+    feedback: [f32; 2],
+}
+
+state!(Channel {
+    fnum,
+    block,
+    sustain,
+    key,
+    instrument,
+    volume,
+    ops,
+    feedback
+});
+
+impl Channel {
+    fn new() -> Channel {
+        Channel {
+            fnum: 0,
+            block: 0,
+            sustain: false,
+            key: false,
+            instrument: 0,
+            volume: 0,
+            ops: [Operator::new(); 2],
+            feedback: [0.0; 2],
+        }
+    }
+}
+
+/// Envelope change per sample at `rate` 0-63, in attenuation steps
+fn env_step(rate: u8) -> f32 {
+    if rate == 0 {
+        0.0
+    } else {
+        ((4 + (rate as u32 & 0x3)) << (rate >> 2)) as f32 / 32768.0
+    }
+}
+
+/// Expansion audio of the Konami VRC7, a 6 channel FM synthesizer derived
+/// from the YM2413 (OPLL). Each channel is a modulator and a carrier playing
+/// one of 15 built-in instruments or the custom one.
+/// see http://wiki.nesdev.com/w/index.php/VRC7_audio
+pub struct Vrc7Audio {
+    sine: [f32; 1024],
+    /// Gain of attenuations in 0.375 dB steps
+    gain: [f32; GAIN_STEPS],
+    /// $9010
+    address: u8,
+    /// Registers $00-$07
+    custom: [u8; 8],
+    channels: [Channel; 6],
+    /// CPU cycles until the next sample
+    cycles: u8,
+    /// Tremolo and vibrato phases, in cycles
+    am_phase: f32,
+    vib_phase: f32,
+    output: f32,
+}
+
+state!(Vrc7Audio {
+    address,
+    custom,
+    channels,
+    cycles,
+    am_phase,
+    vib_phase,
+    output
+});
+
+impl Vrc7Audio {
+    pub fn new() -> Vrc7Audio {
+        let mut sine = [0.0; 1024];
+        for (i, out) in sine.iter_mut().enumerate() {
+            *out = sin(2.0 * PI * (i as f64 + 0.5) / 1024.0) as f32;
+        }
+        let mut gain = [0.0; GAIN_STEPS];
+        let mut level = 1.0;
+        for out in gain.iter_mut() {
+            *out = level;
+            level *= STEP_GAIN;
+        }
+        Vrc7Audio {
+            sine,
+            gain,
+            address: 0,
+            custom: [0; 8],
+            channels: [Channel::new(); 6],
+            cycles: CYCLES_PER_SAMPLE,
+            am_phase: 0.0,
+            vib_phase: 0.0,
+            output: 0.0,
+        }
+    }
+    /// Write $9010
+    pub fn write_address(&mut self, val: u8) {
+        self.address = val;
+    }
+    /// Write $9030, the register selected by $9010
+    pub fn write_data(&mut self, val: u8) {
+        let reg = self.address;
+        let channel = (reg & 0x0F) as usize;
+        match reg {
+            0x00..=0x07 => self.custom[reg as usize] = val,
+            0x10..=0x15 => {
+                let ch = &mut self.channels[channel];
+                ch.fnum = (ch.fnum & 0x100) | val as u16;
+            }
+            // --ST OOOH
+            0x20..=0x25 => {
+                let ch = &mut self.channels[channel];
+                ch.fnum = (ch.fnum & 0xFF) | (val as u16 & 0x1) << 8;
+                ch.block = (val >> 1) & 0x7;
+                ch.sustain = val & 0x20 != 0;
+                let key = val & 0x10 != 0;
+                if key && !ch.key {
+                    for op in ch.ops.iter_mut() {
+                        op.phase = 0;
+                        op.state = Envelope::Attack;
+                    }
+                } else if !key && ch.key {
+                    for op in ch.ops.iter_mut() {
+                        op.state = Envelope::Release;
+                    }
+                }
+                ch.key = key;
+            }
+            // IIII VVVV
+            0x30..=0x35 => {
+                let ch = &mut self.channels[channel];
+                ch.instrument = val >> 4;
+                ch.volume = val & 0x0F;
+            }
+            _ => {}
+        }
+    }
+    /// Called once every CPU cycle
+    pub fn clock(&mut self) {
+        self.cycles -= 1;
+        if self.cycles == 0 {
+            self.cycles = CYCLES_PER_SAMPLE;
+            self.output = self.sample();
+        }
+    }
+    /// Each channel at full volume is about as loud as an APU pulse
+    pub fn output(&self) -> f32 {
+        self.output * pulse_out(15.0)
+    }
+    fn patch(&self, instrument: u8) -> [u8; 8] {
+        match instrument {
+            0 => self.custom,
+            n => PATCHES[n as usize - 1],
+        }
+    }
+    fn sine(&self, phase: f32) -> f32 {
+        self.sine[phase as i32 as usize & 0x3FF]
+    }
+    fn sample(&mut self) -> f32 {
+        self.am_phase = (self.am_phase + AM_RATE / SAMPLE_RATE) % 1.0;
+        self.vib_phase = (self.vib_phase + VIB_RATE / SAMPLE_RATE) % 1.0;
+        let am = (1.0 + self.sine(self.am_phase * 1024.0)) / 2.0 * AM_DEPTH;
+        let vib = 1.0 + self.sine(self.vib_phase * 1024.0) * VIB_DEPTH;
+        let mut output = 0.0;
+        for n in 0..self.channels.len() {
+            output += self.channel_sample(n, am, vib);
+        }
+        output
+    }
+    fn channel_sample(&mut self, n: usize, am: f32, vib: f32) -> f32 {
+        let mut ch = self.channels[n];
+        let patch = self.patch(ch.instrument);
+        let mut attenuations = [0.0; 2];
+        for (i, op) in ch.ops.iter_mut().enumerate() {
+            // AM VIB EG KSR MMMM
+            let flags = patch[i];
+            let sustained = flags & 0x20 != 0;
+            let rks = if flags & 0x10 != 0 {
+                ch.block << 1 | (ch.fnum >> 8) as u8
+            } else {
+                ch.block >> 1
+            };
+            let rate = |r: u8| if r == 0 { 0 } else { (4 * r + rks).min(63) };
+            let attack = patch[4 + i] >> 4;
+            let decay = patch[4 + i] & 0x0F;
+            let sustain_level = (patch[6 + i] >> 4) as f32 * 8.0;
+            let release = patch[6 + i] & 0x0F;
+            match op.state {
+                Envelope::Attack => {
+                    let r = rate(attack);
+                    if r >= 60 {
+                        op.env = 0.0;
+                    } else {
+                        op.env -= env_step(r) * (op.env / 8.0 + 1.0);
+                    }
+                    if op.env <= 0.0 {
+                        op.env = 0.0;
+                        op.state = Envelope::Decay;
+                    }
+                }
+                Envelope::Decay => {
+                    op.env += env_step(rate(decay));
+                    if op.env >= sustain_level {
+                        op.env = sustain_level;
+                        op.state = Envelope::Sustain;
+                    }
+                }
+                // Percussive instruments keep decaying with the release rate
+                Envelope::Sustain if !sustained => op.env += env_step(rate(release)),
+                Envelope::Sustain => {}
+                Envelope::Release => {
+                    let r = if ch.sustain {
+                        5
+                    } else if sustained {
+                        release
+                    } else {
+                        7
+                    };
+                    op.env += env_step(rate(r));
+                }
+            }
+            op.env = op.env.min(ENV_MAX);
+
+            let inc = ((ch.fnum as u32 * MULTIPLIER[flags as usize & 0x0F]) << ch.block) >> 1;
+            let inc = if flags & 0x40 != 0 {
+                (inc as f32 * vib) as u32
+            } else {
+                inc
+            };
+            op.phase = (op.phase + inc) & 0x7FFFF;
+
+            let ksl = patch[2 + i] >> 6;
+            let ksl = if ksl == 0 {
+                0.0
+            } else {
+                let base =
+                    KSL_TABLE[(ch.fnum >> 5) as usize & 0x0F] as i16 - 8 * (7 - ch.block as i16);
+                ((base.max(0) * 2) >> (3 - ksl)) as f32
+            };
+            let level = if i == 0 {
+                // Modulator total level in 0.75 dB steps
+                (patch[2] & 0x3F) as f32 * 2.0
+            } else {
+                ch.volume as f32 * 8.0
+            };
+            let mut attenuation = op.env + ksl + level;
+            if flags & 0x80 != 0 {
+                attenuation += am;
+            }
+            attenuations[i] = attenuation;
+        }
+
+        // The modulator feeds back into itself, shifting its phase by up to
+        // 2 cycles at FB 7, and shifts the carrier phase by up to 2 cycles
+        let feedback = patch[3] & 0x7;
+        let offset = if feedback == 0 {
+            0.0
+        } else {
+            (ch.feedback[0] + ch.feedback[1]) / 2.0 * (16u32 << feedback) as f32
+        };
+        let modulator = self.operator(
+            ch.ops[0].phase,
+            offset,
+            attenuations[0],
+            patch[3] & 0x08 != 0,
+        );
+        ch.feedback = [ch.feedback[1], modulator];
+        let carrier = self.operator(
+            ch.ops[1].phase,
+            modulator * 2048.0,
+            attenuations[1],
+            patch[3] & 0x10 != 0,
+        );
+        self.channels[n] = ch;
+        carrier
+    }
+    /// Output of an operator at `phase` shifted by `offset` table entries,
+    /// half-wave rectified if `rectify`
+    fn operator(&self, phase: u32, offset: f32, attenuation: f32, rectify: bool) -> f32 {
+        let index = attenuation as usize;
+        if index >= GAIN_STEPS {
+            return 0.0;
+        }
+        let wave = self.sine((phase >> 9) as f32 + offset);
+        if rectify && wave < 0.0 {
+            0.0
+        } else {
+            wave * self.gain[index]
+        }
+    }
+}
@@ -1,20 +1,151 @@
-pub struct Input{}
-impl Input{
-    pub fn new()->Input{
-        Input{}
+bitflags! {
+    /// Buttons of a standard controller, in the order they are shifted out
+    pub struct Buttons:u8{
+        const A = 1 << 0;
+        const B = 1 << 1;
+        const SELECT = 1 << 2;
+        const START = 1 << 3;
+        const UP = 1 << 4;
+        const DOWN = 1 << 5;
+        const LEFT = 1 << 6;
+        const RIGHT = 1 << 7;
     }
-    pub fn reset(&mut self){
-        warn!("TODO");
+}
+
+state_bits!(Buttons);
+
+/// Upper bits of $4016/$4017 are open bus, usually $40 left by the address high byte
+const OPEN_BUS: u8 = 0x40;
+
+/// Standard controller, a 4021 8 bit parallel to serial shift register
+struct Controller {
+    buttons: Buttons,
+    shift: u8,
+}
+
+state!(Controller { buttons, shift });
+
+impl Controller {
+    fn new() -> Controller {
+        Controller {
+            buttons: Buttons::empty(),
+            shift: 0,
+        }
     }
-    pub fn set(&mut self,val:u8){
-        warn!("TODO");
+    fn latch(&mut self) {
+        self.shift = self.buttons.bits();
     }
-    pub fn load1(&mut self) -> u8{
-        warn!("TODO");
-        0
+    /// Shift out the next button, 1 after all 8 have been read
+    fn read(&mut self) -> u8 {
+        let bit = self.shift & 1;
+        self.shift = (self.shift >> 1) | 0x80;
+        bit
     }
-    pub fn load2(&mut self) -> u8{
-        warn!("TODO");
-        0
+}
+
+/// $4016 W: strobe both controllers
+/// $4016 R: serial data of controller 1
+/// $4017 R: serial data of controller 2
+/// see http://wiki.nesdev.com/w/index.php/Standard_controller
+pub struct Input {
+    /// While strobe is high the controllers keep reloading their buttons
+    strobe: bool,
+    ports: [Controller; 2],
+}
+
+state!(Input { strobe, ports });
+
+impl Input {
+    pub fn new() -> Input {
+        Input {
+            strobe: false,
+            ports: [Controller::new(), Controller::new()],
+        }
     }
-}
\ No newline at end of file
+    pub fn reset(&mut self) {
+        self.strobe = false;
+        for port in self.ports.iter_mut() {
+            port.shift = 0;
+        }
+    }
+    pub fn set(&mut self, val: u8) {
+        self.strobe = val & 1 != 0;
+        if self.strobe {
+            for port in self.ports.iter_mut() {
+                port.latch();
+            }
+        }
+    }
+    pub fn load1(&mut self) -> u8 {
+        self.load(0)
+    }
+    pub fn load2(&mut self) -> u8 {
+        self.load(1)
+    }
+    fn load(&mut self, port: usize) -> u8 {
+        let port = &mut self.ports[port];
+        if self.strobe {
+            port.latch();
+        }
+        port.read() | OPEN_BUS
+    }
+    /// Update the buttons held on controller `port` (0 or 1), other ports are ignored
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        if let Some(port) = self.ports.get_mut(port) {
+            port.buttons = buttons;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &mut Input) -> [u8; 10] {
+        let mut bits = [0; 10];
+        bits.iter_mut().for_each(|bit| *bit = input.load1());
+        bits
+    }
+
+    #[test]
+    fn shift_out_buttons() {
+        let mut input = Input::new();
+        input.set_buttons(0, Buttons::A | Buttons::START | Buttons::RIGHT);
+        input.set(1);
+        input.set(0);
+        // A B Select Start Up Down Left Right, then 1s, over open bus $40
+        assert_eq!(
+            read(&mut input),
+            [0x41, 0x40, 0x40, 0x41, 0x40, 0x40, 0x40, 0x41, 0x41, 0x41]
+        );
+    }
+
+    #[test]
+    fn strobe_high_reloads() {
+        let mut input = Input::new();
+        input.set_buttons(0, Buttons::A);
+        input.set(1);
+        assert_eq!(read(&mut input), [0x41; 10]);
+        // Buttons are latched while strobe is high
+        input.set_buttons(0, Buttons::B);
+        input.set(0);
+        assert_eq!(read(&mut input)[..3], [0x40, 0x40, 0x40]);
+        input.set(1);
+        input.set(0);
+        assert_eq!(read(&mut input)[..3], [0x40, 0x41, 0x40]);
+    }
+
+    #[test]
+    fn two_ports() {
+        let mut input = Input::new();
+        input.set_buttons(1, Buttons::B);
+        // Ignored
+        input.set_buttons(2, Buttons::A);
+        input.set(1);
+        input.set(0);
+        assert_eq!(input.load1(), 0x40);
+        assert_eq!(input.load2(), 0x40);
+        assert_eq!(input.load2(), 0x41);
+        assert_eq!(input.load1(), 0x40);
+    }
+}
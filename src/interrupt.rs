@@ -0,0 +1,106 @@
+bitflags! {
+    /// Devices sharing the /IRQ line of the CPU
+    pub struct Irq:u8{
+        /// APU frame counter
+        const FRAME = 1 << 0;
+        /// APU delta modulation channel
+        const DMC = 1 << 1;
+        /// Cartridge
+        const MAPPER = 1 << 2;
+    }
+}
+
+state_bits!(Irq);
+
+/// Interrupt lines between the CPU and the other chips.
+///
+/// NMI is edge triggered: the CPU only sees an NMI when the line becomes active,
+/// so a source must release the line before it can raise another one.
+/// IRQ is level triggered: it's asserted as long as any source holds it.
+pub struct Interrupt {
+    /// Current level of the NMI line, true if active
+    nmi_line: bool,
+    /// An edge was detected and the CPU hasn't serviced it yet
+    nmi_pending: bool,
+    irq: Irq,
+}
+
+state!(Interrupt {
+    nmi_line,
+    nmi_pending,
+    irq
+});
+
+impl Interrupt {
+    pub fn new() -> Interrupt {
+        Interrupt {
+            nmi_line: false,
+            nmi_pending: false,
+            irq: Irq::empty(),
+        }
+    }
+    pub fn reset(&mut self) {
+        *self = Interrupt::new();
+    }
+    /// Drive the NMI line, latch an NMI on the inactive to active edge
+    pub fn set_nmi(&mut self, active: bool) {
+        if active && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = active;
+    }
+    /// Return and clear the latched NMI
+    pub fn take_nmi(&mut self) -> bool {
+        let nmi = self.nmi_pending;
+        self.nmi_pending = false;
+        nmi
+    }
+    /// Assert or release the IRQ line on behalf of `source`
+    pub fn set_irq(&mut self, source: Irq, active: bool) {
+        self.irq.set(source, active);
+    }
+    /// True if any source is holding the IRQ line
+    pub fn irq(&self) -> bool {
+        !self.irq.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nmi_on_edge() {
+        let mut interrupt = Interrupt::new();
+        interrupt.set_nmi(true);
+        interrupt.set_nmi(true);
+        assert!(interrupt.take_nmi());
+        // The line stays active, no new edge
+        interrupt.set_nmi(true);
+        assert!(!interrupt.take_nmi());
+        interrupt.set_nmi(false);
+        assert!(!interrupt.take_nmi());
+        interrupt.set_nmi(true);
+        assert!(interrupt.take_nmi());
+    }
+
+    #[test]
+    fn nmi_latched_after_release() {
+        let mut interrupt = Interrupt::new();
+        interrupt.set_nmi(true);
+        interrupt.set_nmi(false);
+        assert!(interrupt.take_nmi());
+        assert!(!interrupt.take_nmi());
+    }
+
+    #[test]
+    fn irq_held_by_any_source() {
+        let mut interrupt = Interrupt::new();
+        interrupt.set_irq(Irq::FRAME, true);
+        interrupt.set_irq(Irq::MAPPER, true);
+        interrupt.set_irq(Irq::FRAME, false);
+        assert!(interrupt.irq());
+        interrupt.set_irq(Irq::MAPPER, false);
+        assert!(!interrupt.irq());
+    }
+}
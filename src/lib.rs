@@ -5,33 +5,212 @@ extern crate bitflags;
 extern crate log;
 use mos6502::CPU;
 
+#[macro_use]
+mod state;
 mod apu;
 mod input;
+mod interrupt;
 mod mapper;
 mod mem;
 mod ppu;
+mod rewind;
 mod rom;
 
+pub use apu::Speaker;
+pub use input::Buttons;
+use mapper::Cartridge;
+pub use mapper::FDS_RAM_SIZE;
 use mem::NESMemory;
 pub use ppu::{Screen, SCREEN_HEIGHT, SCREEN_WIDTH};
+use rewind::Rewind;
+use rom::Rom;
+pub use rom::RomError;
+pub use state::StateError;
+use state::{Reader, State, Writer};
+
+/// Bytes of PRG-RAM and CHR-RAM the cartridge of the iNES or NES 2.0 image
+/// `buffer` has, the RAM to give to `NES::new`
+pub fn cartridge_ram_size(buffer: &[u8]) -> Result<usize, RomError> {
+    let (prg, _, chr) = mapper::ram_sizes(&Rom::load(buffer)?);
+    Ok(prg + chr)
+}
 
-pub struct NES<'a, S: Screen> {
+pub struct NES<'a, S: Screen, A: Speaker> {
     cpu: CPU<NESMemory<'a>>,
     screen: S,
+    speaker: A,
+    /// Of the game, save states of other games are rejected
+    checksum: u32,
+    rewind: Option<Rewind<'a>>,
 }
 
-impl<'a, S: Screen> NES<'a, S> {
-    pub fn new(buffer: &'a [u8], screen: S) -> NES<'a, S> {
-        let mem = NESMemory::new(buffer);
-        NES {
+impl<'a, S: Screen, A: Speaker> NES<'a, S, A> {
+    /// Load the iNES or NES 2.0 image `buffer`, errors if it's malformed
+    /// or its mapper isn't supported. The RAM of the cartridge lives in `ram`,
+    /// which needs `cartridge_ram_size(buffer)` bytes.
+    pub fn new(
+        buffer: &'a [u8],
+        ram: &'a mut [u8],
+        screen: S,
+        speaker: A,
+    ) -> Result<NES<'a, S, A>, RomError> {
+        let mem = NESMemory::new(buffer, ram, speaker.sample_rate())?;
+        Ok(NES {
+            checksum: mem.cart.checksum(),
             cpu: CPU::new(mem),
             screen,
-        }
+            speaker,
+            rewind: None,
+        })
+    }
+    /// Boot the Famicom Disk System BIOS `bios` (8 KB) with the .fds image `disk`
+    /// inserted, side A up. Data the game saves is written into `disk`.
+    /// `ram` needs `FDS_RAM_SIZE` bytes.
+    pub fn new_fds(
+        bios: &'a [u8],
+        disk: &'a mut [u8],
+        ram: &'a mut [u8],
+        screen: S,
+        speaker: A,
+    ) -> Result<NES<'a, S, A>, RomError> {
+        let cart = Cartridge::fds(bios, disk, ram)?;
+        let mem = NESMemory::with_cartridge(cart, speaker.sample_rate());
+        Ok(NES {
+            checksum: mem.cart.checksum(),
+            cpu: CPU::new(mem),
+            screen,
+            speaker,
+            rewind: None,
+        })
     }
+    /// Run the machine until the PPU finishes the current frame.
     pub fn frame(&mut self) {
-        assert_eq!(self.cpu.mem.ppu.scanline, 0);
-        assert_eq!(self.cpu.mem.ppu.cycles, 0);
-        loop {}
+        self.run_frame();
+        if let Some(mut rewind) = self.rewind.take() {
+            if rewind.tick() {
+                self.capture(&mut rewind);
+            }
+            self.rewind = Some(rewind);
+        }
+    }
+    fn run_frame(&mut self) {
+        loop {
+            self.cpu.execute();
+            let frame = self.cpu.mem.sync(&mut self.screen, &mut self.speaker);
+            if self.cpu.mem.interrupt.take_nmi() {
+                self.cpu.nmi();
+            } else if self.cpu.mem.interrupt.irq() {
+                // the CPU ignores it while the I flag is set
+                self.cpu.irq();
+            }
+            if frame {
+                break;
+            }
+        }
+    }
+    /// Set the buttons held on controller `port`, 0 for player 1 and 1 for player 2.
+    /// Other ports are ignored.
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        self.cpu.mem.input.set_buttons(port, buttons);
+    }
+    /// Battery backed RAM of the cartridge to write to a .sav file,
+    /// None if it has no battery
+    pub fn save_ram(&self) -> Option<&[u8]> {
+        self.cpu.mem.cart.save_ram()
+    }
+    /// Restore the RAM of a .sav file, call it before the first frame
+    pub fn load_save_ram(&mut self, data: &[u8]) {
+        self.cpu.mem.cart.load_save_ram(data)
+    }
+    /// Whether the game changed the save RAM since the last call,
+    /// frontends only need to write the .sav file then
+    pub fn take_save_ram_dirty(&mut self) -> bool {
+        self.cpu.mem.cart.take_save_ram_dirty()
+    }
+    /// Bytes needed by `save_state`, the same for every state of a game
+    pub fn state_size(&self) -> usize {
+        let mut w = Writer::new(&mut []);
+        self.write_state(&mut w);
+        w.written()
+    }
+    /// Save the whole machine into `buf` and return the bytes written.
+    /// The disk of the disk system is part of it, games save to it.
+    pub fn save_state(&self, buf: &mut [u8]) -> Result<usize, StateError> {
+        let size = self.state_size();
+        if buf.len() < size {
+            return Err(StateError::BufferTooSmall);
+        }
+        self.write_state(&mut Writer::new(buf));
+        Ok(size)
+    }
+    /// Restore a state made by `save_state`. Nothing changes if it was saved
+    /// by another version or for another game.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        state::check_header(data, self.checksum)?;
+        if data.len() < self.state_size() {
+            return Err(StateError::InvalidFormat);
+        }
+        self.read_state(data);
+        // The history leads to another game state, start over from this one
+        if let Some(mut rewind) = self.rewind.take() {
+            rewind.clear();
+            self.capture(&mut rewind);
+            self.rewind = Some(rewind);
+        }
+        Ok(())
+    }
+    fn write_state(&self, w: &mut Writer) {
+        state::write_header(w, self.checksum);
+        self.cpu.regs.save(w);
+        self.cpu.mem.save(w);
+    }
+    fn read_state(&mut self, data: &[u8]) {
+        let mut r = Reader::new(&data[state::HEADER_SIZE..]);
+        self.cpu.regs.load(&mut r);
+        self.cpu.mem.load(&mut r);
+    }
+    /// Save a state every `interval` frames into `buf` to step back with
+    /// `rewind_step`. `buf` needs twice `state_size` bytes, the rest holds
+    /// the history, a few hundred bytes per state for most games.
+    pub fn enable_rewind(&mut self, buf: &'a mut [u8], interval: u32) -> Result<(), StateError> {
+        let mut rewind = Rewind::new(buf, self.state_size(), interval)?;
+        self.capture(&mut rewind);
+        self.rewind = Some(rewind);
+        Ok(())
+    }
+    /// Go back to the last saved state, or to the one before if it's less
+    /// than a frame old, and draw the frame after it. Call it instead of
+    /// `frame` while the rewind key is held. False once the history runs out.
+    pub fn rewind_step(&mut self) -> bool {
+        let mut rewind = match self.rewind.take() {
+            Some(rewind) => rewind,
+            None => return false,
+        };
+        let step = rewind.step();
+        if step {
+            self.read_state(rewind.snapshot());
+        }
+        self.rewind = Some(rewind);
+        if step {
+            self.run_frame();
+        }
+        step
+    }
+    fn capture(&self, rewind: &mut Rewind) {
+        self.write_state(&mut Writer::new(rewind.next_mut()));
+        rewind.push();
+    }
+    /// Number of disk sides, 0 if no disk system is attached
+    pub fn disk_sides(&self) -> usize {
+        self.cpu.mem.cart.disk_drive().map_or(0, |drive| drive.sides())
+    }
+    /// Insert disk side `side` (0 for side A of the first disk, 1 for side B...),
+    /// or eject the disk with None. To swap disks, eject the current one and
+    /// run a few frames before inserting the next one.
+    pub fn insert_disk(&mut self, side: Option<usize>) {
+        if let Some(drive) = self.cpu.mem.cart.disk_drive_mut() {
+            drive.insert(side);
+        }
     }
     #[cfg(feature = "disasm")]
     pub fn step(&mut self) {
@@ -46,3 +225,106 @@ impl<'a, S: Screen> NES<'a, S> {
         self.cpu.mem.get_cycles()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoScreen;
+
+    impl Screen for NoScreen {
+        fn render_pixel(&mut self, _x: u16, _y: u16, _pixel: (u8, u8, u8)) {}
+    }
+
+    struct NoSpeaker;
+
+    impl Speaker for NoSpeaker {
+        fn sample_rate(&self) -> u32 {
+            44100
+        }
+        fn push_sample(&mut self, _sample: f32) {}
+    }
+
+    const ROM_SIZE: usize = 16 + 0x4000 + 0x2000;
+
+    /// NROM looping on JMP $8000, `id` makes games different
+    fn rom(id: u8) -> [u8; ROM_SIZE] {
+        let mut rom = [0; ROM_SIZE];
+        rom[..8].copy_from_slice(b"NES\x1a\x01\x01\x00\x00");
+        rom[16..19].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        rom[19] = id;
+        for vector in rom[16 + 0x3FFA..16 + 0x4000].chunks_mut(2) {
+            vector.copy_from_slice(&[0x00, 0x80]);
+        }
+        rom
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let rom = rom(0);
+        let mut ram = [0; 0x2000];
+        let mut nes = NES::new(&rom, &mut ram, NoScreen, NoSpeaker).unwrap();
+        nes.frame();
+        let mut saved = [0; 0x10000];
+        let size = nes.save_state(&mut saved).unwrap();
+        assert_eq!(size, nes.state_size());
+        nes.frame();
+        let mut later = [0; 0x10000];
+        nes.save_state(&mut later).unwrap();
+        assert_ne!(saved[..size], later[..size]);
+        nes.load_state(&saved[..size]).unwrap();
+        let mut loaded = [0; 0x10000];
+        nes.save_state(&mut loaded).unwrap();
+        assert_eq!(saved[..size], loaded[..size]);
+    }
+
+    #[test]
+    fn save_into_small_buffer() {
+        let rom = rom(0);
+        let mut ram = [0; 0x2000];
+        let nes = NES::new(&rom, &mut ram, NoScreen, NoSpeaker).unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(nes.save_state(&mut buf), Err(StateError::BufferTooSmall));
+    }
+
+    #[test]
+    fn load_rejected_states() {
+        let mut buf = [0; 0x10000];
+        let other_rom = rom(1);
+        let mut other_ram = [0; 0x2000];
+        let other = NES::new(&other_rom, &mut other_ram, NoScreen, NoSpeaker).unwrap();
+        let size = other.save_state(&mut buf).unwrap();
+        let rom = rom(0);
+        let mut ram = [0; 0x2000];
+        let mut nes = NES::new(&rom, &mut ram, NoScreen, NoSpeaker).unwrap();
+        assert_eq!(nes.load_state(&buf[..size]), Err(StateError::RomMismatch));
+        let size = nes.save_state(&mut buf).unwrap();
+        assert_eq!(
+            nes.load_state(&buf[..size - 1]),
+            Err(StateError::InvalidFormat)
+        );
+        buf[4] ^= 0xFF;
+        assert!(matches!(
+            nes.load_state(&buf[..size]),
+            Err(StateError::VersionMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn cartridge_ram() {
+        let rom = rom(0);
+        assert_eq!(cartridge_ram_size(&rom), Ok(0x2000));
+        let mut ram = [0; 0x1FFF];
+        assert!(matches!(
+            NES::new(&rom, &mut ram, NoScreen, NoSpeaker),
+            Err(RomError::RamTooSmall {
+                expected: 0x2000,
+                found: 0x1FFF
+            })
+        ));
+        // CHR-RAM follows PRG-RAM when there is no CHR-ROM
+        let mut chr_ram = rom;
+        chr_ram[5] = 0;
+        assert_eq!(cartridge_ram_size(&chr_ram[..16 + 0x4000]), Ok(0x4000));
+    }
+}
@@ -0,0 +1,325 @@
+use super::{open_bus, Mapper, CHR, PRG};
+use crate::ppu::Mirroring;
+
+/// Boards built from discrete logic chips, a latch holds the bank numbers
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Board {
+    /// Mapper 2, 16 KB PRG bank at $8000, last bank fixed at $C000
+    Uxrom,
+    /// Mapper 3, 8 KB CHR bank
+    Cnrom,
+    /// Mapper 7, 32 KB PRG bank and one-screen mirroring select
+    Axrom,
+    /// Mapper 11, 32 KB PRG bank in bits 0-1 and 8 KB CHR bank in bits 4-7
+    ColorDreams,
+    /// Mapper 34 with CHR-RAM, 32 KB PRG bank
+    Bnrom,
+    /// Mapper 34 with CHR-ROM, registers at $7FFD-$7FFF
+    Nina001,
+    /// Mapper 66, 32 KB PRG bank in bits 4-5 and 8 KB CHR bank in bits 0-1
+    Gxrom,
+    /// Mapper 71, UxROM like with the latch at $C000-$FFFF
+    Camerica,
+}
+
+impl Board {
+    pub fn from_mapper(mapper: u16, submapper: u8, chr_size: usize) -> Option<Board> {
+        match mapper {
+            2 => Some(Board::Uxrom),
+            3 => Some(Board::Cnrom),
+            7 => Some(Board::Axrom),
+            11 => Some(Board::ColorDreams),
+            // NINA-001 has CHR-ROM, BNROM has CHR-RAM
+            34 if submapper == 1 || chr_size > 0x2000 => Some(Board::Nina001),
+            34 => Some(Board::Bnrom),
+            66 => Some(Board::Gxrom),
+            71 => Some(Board::Camerica),
+            _ => None,
+        }
+    }
+}
+
+/// Discrete logic mappers.
+/// Boards with bus conflicts let the ROM drive the data bus while the CPU
+/// writes the latch, so the latch gets the AND of both values.
+/// see http://wiki.nesdev.com/w/index.php/Bus_conflict
+pub struct Discrete<'a> {
+    board: Board,
+    pub(super) prg: PRG<'a>,
+    chr: CHR<'a>,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+    /// Fire Hawk's BF9097 board controls one-screen mirroring at $9000
+    mirroring_control: bool,
+    prg_bank: usize,
+    /// 8 KB CHR bank, or two 4 KB banks on NINA-001
+    chr_bank: [usize; 2],
+}
+
+state!(Discrete<'a> { prg, chr, mirroring, prg_bank, chr_bank });
+
+impl<'a> Discrete<'a> {
+    pub fn new(
+        board: Board,
+        prg: PRG<'a>,
+        chr: CHR<'a>,
+        mirroring: Mirroring,
+        submapper: u8,
+    ) -> Discrete<'a> {
+        let default_conflicts = match board {
+            Board::Uxrom | Board::Cnrom | Board::ColorDreams | Board::Bnrom | Board::Gxrom => true,
+            Board::Axrom | Board::Nina001 | Board::Camerica => false,
+        };
+        // NES 2.0 submappers of mapper 2, 3 and 7: 1(no bus conflicts) 2(AND bus conflicts)
+        let bus_conflicts = match (board, submapper) {
+            (Board::Uxrom, 1) | (Board::Cnrom, 1) | (Board::Axrom, 1) => false,
+            (Board::Uxrom, 2) | (Board::Cnrom, 2) | (Board::Axrom, 2) => true,
+            _ => default_conflicts,
+        };
+        let mirroring = if board == Board::Axrom {
+            Mirroring::OneScreenLower
+        } else {
+            mirroring
+        };
+        Discrete {
+            board,
+            prg,
+            chr,
+            mirroring,
+            bus_conflicts,
+            mirroring_control: board == Board::Camerica && submapper == 1,
+            prg_bank: 0,
+            chr_bank: [0, 1],
+        }
+    }
+    fn prg_rom(&self, addr: u16) -> u8 {
+        match self.board {
+            Board::Uxrom | Board::Camerica => {
+                let bank = if addr >= 0xC000 {
+                    self.prg.banks(0x4000) - 1
+                } else {
+                    self.prg_bank
+                };
+                self.prg.rom(bank, 0x4000, addr)
+            }
+            Board::Cnrom => self.prg.rom(0, 0x8000, addr),
+            _ => self.prg.rom(self.prg_bank, 0x8000, addr),
+        }
+    }
+    fn chr_bank(&self, addr: u16) -> (usize, usize) {
+        if self.board == Board::Nina001 {
+            (self.chr_bank[(addr >> 12) as usize & 1], 0x1000)
+        } else {
+            (self.chr_bank[0], 0x2000)
+        }
+    }
+    fn write_latch(&mut self, addr: u16, val: u8) {
+        let val = if self.bus_conflicts {
+            val & self.prg_rom(addr)
+        } else {
+            val
+        };
+        let val = val as usize;
+        match self.board {
+            Board::Uxrom | Board::Bnrom => self.prg_bank = val,
+            Board::Cnrom => self.chr_bank[0] = val,
+            Board::Axrom => {
+                self.prg_bank = val & 0x7;
+                self.mirroring = if val & 0x10 == 0 {
+                    Mirroring::OneScreenLower
+                } else {
+                    Mirroring::OneScreenUpper
+                };
+            }
+            Board::ColorDreams => {
+                self.prg_bank = val & 0x3;
+                self.chr_bank[0] = val >> 4;
+            }
+            Board::Gxrom => {
+                self.prg_bank = (val >> 4) & 0x3;
+                self.chr_bank[0] = val & 0x3;
+            }
+            Board::Camerica => match addr {
+                0x9000..=0x9FFF if self.mirroring_control => {
+                    self.mirroring = if val & 0x10 == 0 {
+                        Mirroring::OneScreenLower
+                    } else {
+                        Mirroring::OneScreenUpper
+                    };
+                }
+                0xC000..=0xFFFF => self.prg_bank = val,
+                _ => {}
+            },
+            Board::Nina001 => {}
+        }
+    }
+}
+
+impl<'a> Mapper for Discrete<'a> {
+    fn cpu_loadb(&mut self, addr: u16) -> u8 {
+        self.cpu_peekb(addr).unwrap_or_else(|| open_bus(addr))
+    }
+    fn cpu_peekb(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.board == Board::Nina001 => {
+                Some(self.prg.ram(addr as usize & 0x1FFF))
+            }
+            0x8000..=0xFFFF => Some(self.prg_rom(addr)),
+            _ => None,
+        }
+    }
+    fn cpu_storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.board == Board::Nina001 => {
+                // The registers are written along with PRG-RAM
+                self.prg.set_ram(addr as usize & 0x1FFF, val);
+                match addr {
+                    0x7FFD => self.prg_bank = val as usize & 0x1,
+                    0x7FFE => self.chr_bank[0] = val as usize & 0xF,
+                    0x7FFF => self.chr_bank[1] = val as usize & 0xF,
+                    _ => {}
+                }
+            }
+            0x8000..=0xFFFF => self.write_latch(addr, val),
+            _ => {}
+        }
+    }
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        let (bank, size) = self.chr_bank(addr);
+        self.chr.loadb(bank, size, addr)
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        let (bank, size) = self.chr_bank(addr);
+        self.chr.storeb(bank, size, addr, val)
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::numbered;
+    use super::*;
+
+    /// PRG-ROM of $FF with the bank number in the first byte of each bank,
+    /// so writing $8000 conflicts with it and writing $8001 doesn't
+    fn marked<const N: usize>(size: usize) -> [u8; N] {
+        let mut rom = [0xFF; N];
+        for (bank, chunk) in rom.chunks_mut(size).enumerate() {
+            chunk[0] = bank as u8;
+        }
+        rom
+    }
+
+    fn board<'a>(board: Board, prg: &'a [u8], chr: &'a [u8], submapper: u8) -> Discrete<'a> {
+        Discrete::new(
+            board,
+            PRG::new(prg, &mut [], 0),
+            CHR::new(chr, &mut []),
+            Mirroring::Vertical,
+            submapper,
+        )
+    }
+
+    #[test]
+    fn uxrom_bus_conflicts() {
+        let prg = marked::<0x20000>(0x4000);
+        let mut uxrom = board(Board::Uxrom, &prg, &[0; 0x2000], 0);
+        uxrom.cpu_storeb(0x8001, 5);
+        assert_eq!(uxrom.cpu_peekb(0x8000), Some(5));
+        assert_eq!(uxrom.cpu_peekb(0xC000), Some(7));
+        // 3 AND the 5 in ROM
+        uxrom.cpu_storeb(0x8000, 3);
+        assert_eq!(uxrom.cpu_peekb(0x8000), Some(1));
+        // Submapper 1 has none
+        let mut uxrom = board(Board::Uxrom, &prg, &[0; 0x2000], 1);
+        uxrom.cpu_storeb(0x8000, 3);
+        assert_eq!(uxrom.cpu_peekb(0x8000), Some(3));
+    }
+
+    #[test]
+    fn cnrom_bus_conflicts() {
+        let (prg, chr) = (marked::<0x8000>(0x8000), numbered::<0x8000>(0x2000));
+        let mut cnrom = board(Board::Cnrom, &prg, &chr, 0);
+        cnrom.cpu_storeb(0x8001, 3);
+        assert_eq!(cnrom.chr_loadb(0x1000), 3);
+        cnrom.cpu_storeb(0x8000, 2);
+        assert_eq!(cnrom.chr_loadb(0x1000), 0);
+        let mut cnrom = board(Board::Cnrom, &prg, &chr, 1);
+        cnrom.cpu_storeb(0x8000, 2);
+        assert_eq!(cnrom.chr_loadb(0x1000), 2);
+    }
+
+    #[test]
+    fn axrom_has_no_bus_conflicts() {
+        let prg = marked::<0x20000>(0x8000);
+        let mut axrom = board(Board::Axrom, &prg, &[0; 0x2000], 0);
+        assert_eq!(axrom.mirroring(), Mirroring::OneScreenLower);
+        axrom.cpu_storeb(0x8000, 0x13);
+        assert_eq!(axrom.cpu_peekb(0x8000), Some(3));
+        assert_eq!(axrom.mirroring(), Mirroring::OneScreenUpper);
+        // Submapper 2 has them
+        let mut axrom = board(Board::Axrom, &prg, &[0; 0x2000], 2);
+        axrom.cpu_storeb(0x8000, 0x13);
+        assert_eq!(axrom.cpu_peekb(0x8000), Some(0));
+        assert_eq!(axrom.mirroring(), Mirroring::OneScreenLower);
+    }
+
+    #[test]
+    fn color_dreams_and_gxrom_latches() {
+        let (prg, chr) = (marked::<0x20000>(0x8000), numbered::<0x20000>(0x2000));
+        let mut color_dreams = board(Board::ColorDreams, &prg, &chr, 0);
+        color_dreams.cpu_storeb(0x8001, 0x52);
+        assert_eq!(color_dreams.cpu_peekb(0x8000), Some(2));
+        assert_eq!(color_dreams.chr_loadb(0x0000), 5);
+        let mut gxrom = board(Board::Gxrom, &prg, &chr, 0);
+        gxrom.cpu_storeb(0x8001, 0x21);
+        assert_eq!(gxrom.cpu_peekb(0x8000), Some(2));
+        assert_eq!(gxrom.chr_loadb(0x0000), 1);
+    }
+
+    #[test]
+    fn camerica_latch() {
+        let prg = marked::<0x20000>(0x4000);
+        let mut camerica = board(Board::Camerica, &prg, &[0; 0x2000], 0);
+        camerica.cpu_storeb(0x8000, 3);
+        camerica.cpu_storeb(0x9000, 0x10);
+        assert_eq!(camerica.cpu_peekb(0x8000), Some(0));
+        assert_eq!(camerica.mirroring(), Mirroring::Vertical);
+        camerica.cpu_storeb(0xC000, 3);
+        assert_eq!(camerica.cpu_peekb(0x8000), Some(3));
+        // Fire Hawk
+        let mut camerica = board(Board::Camerica, &prg, &[0; 0x2000], 1);
+        camerica.cpu_storeb(0x9000, 0x10);
+        assert_eq!(camerica.mirroring(), Mirroring::OneScreenUpper);
+    }
+
+    #[test]
+    fn nina001_registers() {
+        let (prg, chr) = (marked::<0x10000>(0x8000), numbered::<0x10000>(0x1000));
+        let mut ram = [0; 0x2000];
+        let mut nina = Discrete::new(
+            Board::Nina001,
+            PRG::new(&prg, &mut ram, 0),
+            CHR::new(&chr, &mut []),
+            Mirroring::Vertical,
+            0,
+        );
+        nina.cpu_storeb(0x7FFD, 1);
+        nina.cpu_storeb(0x7FFE, 5);
+        nina.cpu_storeb(0x7FFF, 9);
+        assert_eq!(nina.cpu_peekb(0x8000), Some(1));
+        assert_eq!((nina.chr_loadb(0x0000), nina.chr_loadb(0x1000)), (5, 9));
+        // The registers are in PRG-RAM too
+        assert_eq!(nina.cpu_peekb(0x7FFE), Some(5));
+    }
+
+    #[test]
+    fn mapper_34_boards() {
+        assert_eq!(Board::from_mapper(34, 0, 0), Some(Board::Bnrom));
+        assert_eq!(Board::from_mapper(34, 0, 0x10000), Some(Board::Nina001));
+        assert_eq!(Board::from_mapper(34, 1, 0), Some(Board::Nina001));
+        assert_eq!(Board::from_mapper(4, 0, 0), None);
+    }
+}
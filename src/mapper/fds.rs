@@ -0,0 +1,466 @@
+use super::{open_bus, split_ram, Mapper, CHR, PRG};
+use crate::apu::FdsAudio;
+use crate::ppu::Mirroring;
+use crate::rom::RomError;
+use crate::state::{crc32, Reader, State, Writer};
+
+/// Bytes of a disk side in .fds images
+pub const SIDE_SIZE: usize = 65500;
+/// fwNES header "FDS\x1a" followed by the number of sides
+const HEADER_SIZE: usize = 16;
+/// Gap before the first block, 28300 bits
+const LEAD_GAP: u16 = 28300 / 8;
+/// Gap between blocks, 976 bits
+const BLOCK_GAP: u16 = 976 / 8;
+/// CPU cycles per byte at 96.4 kbit/s
+const BYTE_CYCLES: u16 = 149;
+/// CPU cycles for the head to go back to the start of the disk
+const REWIND_CYCLES: u16 = 50000;
+
+/// Offset of the first side and number of sides of a .fds image,
+/// with or without the fwNES header
+pub fn disk_sides(image: &[u8]) -> Result<(usize, usize), RomError> {
+    let offset = if image.starts_with(b"FDS\x1a") {
+        HEADER_SIZE
+    } else {
+        0
+    };
+    let sides = image.len().saturating_sub(offset) / SIDE_SIZE;
+    if sides == 0 {
+        return Err(RomError::InvalidDisk);
+    }
+    for side in 0..sides {
+        let start = offset + side * SIDE_SIZE;
+        if &image[start..start + 15] != b"\x01*NINTENDO-HVC*" {
+            return Err(RomError::InvalidDisk);
+        }
+    }
+    Ok((offset, sides))
+}
+
+/// Where the head is on the track. The .fds format only keeps the blocks,
+/// gaps, start marks and CRCs are made up while the disk spins.
+#[derive(Clone, Copy, PartialEq)]
+enum Head {
+    Gap(u16),
+    /// Start mark 0x80 of the next block
+    Mark,
+    /// Data bytes left in the block
+    Data(usize),
+    /// CRC bytes left
+    Crc(u8),
+    /// No block follows
+    End,
+}
+
+impl State for Head {
+    fn save(&self, w: &mut Writer) {
+        let (kind, count) = match *self {
+            Head::Gap(count) => (0u8, count as usize),
+            Head::Mark => (1, 0),
+            Head::Data(count) => (2, count),
+            Head::Crc(count) => (3, count as usize),
+            Head::End => (4, 0),
+        };
+        kind.save(w);
+        count.save(w);
+    }
+    fn load(&mut self, r: &mut Reader) {
+        let (mut kind, mut count) = (0u8, 0usize);
+        kind.load(r);
+        count.load(r);
+        *self = match kind {
+            0 => Head::Gap(count as u16),
+            1 => Head::Mark,
+            2 => Head::Data(count),
+            3 => Head::Crc(count as u8),
+            _ => Head::End,
+        };
+    }
+}
+
+/// Famicom Disk System RAM adapter and disk drive, with the BIOS at $E000.
+/// see http://wiki.nesdev.com/w/index.php/Family_Computer_Disk_System
+pub struct Fds<'a> {
+    /// 8 KB BIOS and 32 KB PRG-RAM at $6000-$DFFF
+    pub(super) prg: PRG<'a>,
+    /// 8 KB CHR-RAM
+    chr: CHR<'a>,
+    /// .fds image, writes go straight into it
+    disk: &'a mut [u8],
+    /// Offset of the first side
+    disk_offset: usize,
+    sides: usize,
+    /// Inserted side
+    side: Option<usize>,
+    /// $4020-$4021
+    timer_reload: u16,
+    timer: u16,
+    /// $4022 bit 0
+    timer_repeat: bool,
+    /// $4022 bit 1
+    timer_enabled: bool,
+    timer_irq: bool,
+    /// $4023 bit 0 and bit 1
+    disk_enabled: bool,
+    sound_enabled: bool,
+    /// $4024
+    write_data: u8,
+    /// $4025 IDSC MRTM
+    /// I: byte transfer IRQ enable, D: wait for the end of the gap,
+    /// S: CRC control, C: horizontal mirroring, R: read mode,
+    /// T: transfer reset, M: motor on
+    control: u8,
+    /// $4031
+    read_data: u8,
+    /// A byte was transferred, $4030 bit 1
+    transferred: bool,
+    disk_irq: bool,
+    /// The head reached the end of the disk, $4030 bit 6
+    end_of_head: bool,
+    /// The drive is reading or writing the disk
+    scanning: bool,
+    gap_ended: bool,
+    /// CPU cycles until the next byte
+    delay: u16,
+    head: Head,
+    /// Offset into the side of the next block byte
+    position: usize,
+    /// Size of file data blocks, from the last file header block
+    file_size: usize,
+    audio: FdsAudio,
+}
+
+state!(Fds<'a> {
+    prg,
+    chr,
+    disk,
+    side,
+    timer_reload,
+    timer,
+    timer_repeat,
+    timer_enabled,
+    timer_irq,
+    disk_enabled,
+    sound_enabled,
+    write_data,
+    control,
+    read_data,
+    transferred,
+    disk_irq,
+    end_of_head,
+    scanning,
+    gap_ended,
+    delay,
+    head,
+    position,
+    file_size,
+    audio
+});
+
+impl<'a> Fds<'a> {
+    pub fn new(bios: &'a [u8], disk: &'a mut [u8], ram: &'a mut [u8]) -> Result<Fds<'a>, RomError> {
+        if bios.len() != 0x2000 {
+            return Err(RomError::InvalidBios);
+        }
+        let (disk_offset, sides) = disk_sides(disk)?;
+        let (prg_ram, chr_ram) = split_ram(ram, 0x8000, 0x2000)?;
+        Ok(Fds {
+            prg: PRG::new(bios, prg_ram, 0),
+            chr: CHR::new(&[], chr_ram),
+            disk,
+            disk_offset,
+            sides,
+            side: Some(0),
+            timer_reload: 0,
+            timer: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            disk_enabled: false,
+            sound_enabled: false,
+            write_data: 0,
+            control: 0x26,
+            read_data: 0,
+            transferred: false,
+            disk_irq: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            delay: 0,
+            head: Head::Gap(LEAD_GAP),
+            position: 0,
+            file_size: 0,
+            audio: FdsAudio::new(),
+        })
+    }
+    /// Continue `crc` with the disk info block of the first side
+    pub fn checksum(&self, crc: u32) -> u32 {
+        crc32(crc, &self.disk[self.disk_offset..self.disk_offset + 56])
+    }
+    pub fn sides(&self) -> usize {
+        self.sides
+    }
+    /// Insert side `side`, or eject the disk with None.
+    /// Games only notice a swap if the drive was empty for a moment.
+    pub fn insert(&mut self, side: Option<usize>) {
+        self.side = side.filter(|&side| side < self.sides);
+        self.end_of_head = true;
+        self.scanning = false;
+    }
+    fn motor_on(&self) -> bool {
+        self.control & 0x01 != 0
+    }
+    fn read_mode(&self) -> bool {
+        self.control & 0x04 != 0
+    }
+    fn crc_control(&self) -> bool {
+        self.control & 0x10 != 0
+    }
+    fn wait_gap(&self) -> bool {
+        self.control & 0x40 != 0
+    }
+    fn side_byte(&mut self, offset: usize) -> Option<&mut u8> {
+        let side = self.side?;
+        if offset >= SIDE_SIZE {
+            return None;
+        }
+        self.disk
+            .get_mut(self.disk_offset + side * SIDE_SIZE + offset)
+    }
+    /// Size of the block starting at `position`, None past the last block
+    fn block_size(&mut self) -> Option<usize> {
+        let position = self.position;
+        let kind = *self.side_byte(position)?;
+        match kind {
+            1 => Some(56),
+            2 => Some(2),
+            3 => {
+                let low = *self.side_byte(position + 13)? as usize;
+                let high = *self.side_byte(position + 14)? as usize;
+                self.file_size = low | high << 8;
+                Some(16)
+            }
+            4 => Some(1 + self.file_size),
+            _ => None,
+        }
+    }
+    /// Byte under the head, the head moves on to the next one
+    fn read_byte(&mut self) -> u8 {
+        let (val, head) = match self.head {
+            Head::Gap(1) => (0, Head::Mark),
+            Head::Gap(n) => (0, Head::Gap(n - 1)),
+            Head::Mark => match self.block_size() {
+                Some(size) => (0x80, Head::Data(size)),
+                None => (0, Head::End),
+            },
+            Head::Data(n) => {
+                let val = self.side_byte(self.position).map_or(0, |val| *val);
+                self.position += 1;
+                let head = if n == 1 {
+                    Head::Crc(2)
+                } else {
+                    Head::Data(n - 1)
+                };
+                (val, head)
+            }
+            Head::Crc(1) => (0, Head::Gap(BLOCK_GAP)),
+            Head::Crc(n) => (0, Head::Crc(n - 1)),
+            Head::End => (0, Head::End),
+        };
+        self.head = head;
+        val
+    }
+    /// Write the byte under the head. The BIOS writes a gap of its own
+    /// length, so a written start mark begins the block wherever the head is.
+    fn write_byte(&mut self, val: u8) {
+        self.head = match self.head {
+            Head::Gap(_) | Head::Mark | Head::End if val == 0x80 => Head::Data(usize::MAX),
+            Head::Gap(_) | Head::Mark | Head::End => Head::Gap(BLOCK_GAP),
+            Head::Data(n) => {
+                let position = self.position;
+                if let Some(byte) = self.side_byte(position) {
+                    *byte = val;
+                }
+                // The block type is the first byte
+                let n = if n == usize::MAX {
+                    self.block_size().unwrap_or(1)
+                } else {
+                    n
+                };
+                self.position += 1;
+                if n == 1 {
+                    Head::Crc(2)
+                } else {
+                    Head::Data(n - 1)
+                }
+            }
+            Head::Crc(1) => Head::Gap(BLOCK_GAP),
+            Head::Crc(n) => Head::Crc(n - 1),
+        };
+    }
+    /// Move the disk under the head by one CPU cycle.
+    /// see http://wiki.nesdev.com/w/index.php/FDS_disk_drive
+    fn clock_drive(&mut self) {
+        if !self.motor_on() || self.side.is_none() {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        }
+        if self.control & 0x02 != 0 && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = REWIND_CYCLES;
+            self.end_of_head = false;
+            self.head = Head::Gap(LEAD_GAP);
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+        self.scanning = true;
+        let irq = self.control & 0x80 != 0;
+        if self.read_mode() {
+            let val = self.read_byte();
+            if !self.wait_gap() {
+                self.gap_ended = false;
+            } else if val != 0 && !self.gap_ended {
+                // The start mark ends the gap, it isn't transferred
+                self.gap_ended = true;
+            } else if self.gap_ended {
+                self.transferred = true;
+                self.read_data = val;
+                self.disk_irq |= irq;
+            }
+        } else {
+            if !self.crc_control() {
+                self.transferred = true;
+                self.disk_irq |= irq;
+            }
+            let val = if self.wait_gap() { self.write_data } else { 0 };
+            self.write_byte(val);
+            self.gap_ended = false;
+        }
+        if self.head == Head::End {
+            self.end_of_head = true;
+            self.control &= !0x01;
+        }
+        self.delay = BYTE_CYCLES;
+    }
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer_irq = true;
+            self.timer = self.timer_reload;
+            if !self.timer_repeat {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+}
+
+impl<'a> Mapper for Fds<'a> {
+    fn cpu_loadb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4030 if self.disk_enabled => {
+                let val = self.timer_irq as u8
+                    | (self.transferred as u8) << 1
+                    | (self.end_of_head as u8) << 6;
+                self.timer_irq = false;
+                self.transferred = false;
+                self.disk_irq = false;
+                val
+            }
+            0x4031 if self.disk_enabled => {
+                self.transferred = false;
+                self.disk_irq = false;
+                self.read_data
+            }
+            0x4032 if self.disk_enabled => {
+                let empty = self.side.is_none();
+                0x40 | empty as u8 | ((empty || !self.scanning) as u8) << 1 | (empty as u8) << 2
+            }
+            // Battery is good
+            0x4033 if self.disk_enabled => 0x80,
+            _ => self.cpu_peekb(addr).unwrap_or_else(|| open_bus(addr)),
+        }
+    }
+    fn cpu_peekb(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x4097 if self.sound_enabled => self.audio.read(addr),
+            0x6000..=0xDFFF => Some(self.prg.ram(addr as usize - 0x6000)),
+            0xE000..=0xFFFF => Some(self.prg.rom(0, 0x2000, addr)),
+            _ => None,
+        }
+    }
+    fn cpu_storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | val as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | (val as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = val & 0x01 != 0;
+                self.timer_enabled = val & 0x02 != 0 && self.disk_enabled;
+                if self.timer_enabled {
+                    self.timer = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_enabled = val & 0x01 != 0;
+                self.sound_enabled = val & 0x02 != 0;
+                if !self.disk_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024 if self.disk_enabled => {
+                self.write_data = val;
+                self.transferred = false;
+                self.disk_irq = false;
+            }
+            0x4025 if self.disk_enabled => {
+                self.disk_irq = false;
+                self.control = val;
+            }
+            0x4040..=0x4097 if self.sound_enabled => self.audio.write(addr, val),
+            0x6000..=0xDFFF => self.prg.set_ram(addr as usize - 0x6000, val),
+            _ => {}
+        }
+    }
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.chr.loadb(0, 0x2000, addr)
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.chr.storeb(0, 0x2000, addr, val)
+    }
+    fn mirroring(&self) -> Mirroring {
+        if self.control & 0x08 != 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+    fn clock(&mut self) {
+        self.clock_timer();
+        if self.disk_enabled {
+            self.clock_drive();
+        }
+        self.audio.clock();
+    }
+    fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+}
@@ -0,0 +1,212 @@
+use super::vrc::vrc_mirroring;
+use super::{open_bus, Mapper, CHR, PRG};
+use crate::apu::Sunsoft5bAudio;
+use crate::ppu::Mirroring;
+
+/// Mapper 69, Sunsoft FME-7 and its variant with audio, the Sunsoft 5B.
+/// Registers are written through a command port at $8000 and a parameter port at $A000.
+/// see http://wiki.nesdev.com/w/index.php/Sunsoft_FME-7
+pub struct Fme7<'a> {
+    pub(super) prg: PRG<'a>,
+    chr: CHR<'a>,
+    /// $8000 ---- CCCC, register written by $A000
+    command: u8,
+    /// Commands 0-7, 1 KB CHR banks
+    chr_banks: [u8; 8],
+    /// Command 8 ERBB BBBB
+    /// E: PRG-RAM enable, R: PRG-RAM instead of ROM at $6000, B: ROM bank
+    prg_6000: u8,
+    /// Commands 9-B, 8 KB banks at $8000, $A000 and $C000
+    prg_banks: [u8; 3],
+    /// Command C ---- --MM
+    mirroring: u8,
+    /// Command D C--- ---T
+    /// C: counter enable, T: IRQ enable
+    irq_control: u8,
+    /// Commands E-F, 16 bit down counter
+    irq_counter: u16,
+    irq: bool,
+    audio: Sunsoft5bAudio,
+}
+
+state!(Fme7<'a> {
+    prg,
+    chr,
+    command,
+    chr_banks,
+    prg_6000,
+    prg_banks,
+    mirroring,
+    irq_control,
+    irq_counter,
+    irq,
+    audio
+});
+
+impl<'a> Fme7<'a> {
+    pub fn new(prg: PRG<'a>, chr: CHR<'a>) -> Fme7<'a> {
+        Fme7 {
+            prg,
+            chr,
+            command: 0,
+            chr_banks: [0; 8],
+            prg_6000: 0,
+            prg_banks: [0; 3],
+            mirroring: 0,
+            irq_control: 0,
+            irq_counter: 0,
+            irq: false,
+            audio: Sunsoft5bAudio::new(),
+        }
+    }
+    fn ram_selected(&self) -> bool {
+        self.prg_6000 & 0x40 != 0
+    }
+    fn ram_enabled(&self) -> bool {
+        self.prg_6000 & 0xC0 == 0xC0
+    }
+    fn prg_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x6000..=0x7FFF => (self.prg_6000 & 0x3F) as usize,
+            0x8000..=0xDFFF => self.prg_banks[(addr as usize - 0x8000) >> 13] as usize,
+            _ => self.prg.banks(0x2000) - 1,
+        }
+    }
+    fn write_register(&mut self, val: u8) {
+        match self.command {
+            n @ 0x0..=0x7 => self.chr_banks[n as usize] = val,
+            0x8 => self.prg_6000 = val,
+            n @ 0x9..=0xB => self.prg_banks[n as usize - 9] = val & 0x3F,
+            0xC => self.mirroring = val & 0x3,
+            0xD => {
+                self.irq_control = val;
+                self.irq = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | val as u16,
+            _ => self.irq_counter = (self.irq_counter & 0xFF) | (val as u16) << 8,
+        }
+    }
+}
+
+impl<'a> Mapper for Fme7<'a> {
+    fn cpu_loadb(&mut self, addr: u16) -> u8 {
+        self.cpu_peekb(addr).unwrap_or_else(|| open_bus(addr))
+    }
+    fn cpu_peekb(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.ram_selected() => {
+                if self.ram_enabled() {
+                    Some(self.prg.ram(addr as usize & 0x1FFF))
+                } else {
+                    None
+                }
+            }
+            0x6000..=0xFFFF => Some(self.prg.rom(self.prg_bank(addr), 0x2000, addr)),
+            _ => None,
+        }
+    }
+    fn cpu_storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000..=0x7FFF => {
+                if self.ram_enabled() {
+                    self.prg.set_ram(addr as usize & 0x1FFF, val);
+                }
+            }
+            0x8000..=0x9FFF => self.command = val & 0x0F,
+            0xA000..=0xBFFF => self.write_register(val),
+            0xC000..=0xDFFF => self.audio.write_address(val),
+            0xE000..=0xFFFF => self.audio.write_data(val),
+            _ => {}
+        }
+    }
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_banks[addr as usize >> 10] as usize;
+        self.chr.loadb(bank, 0x400, addr)
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        let bank = self.chr_banks[addr as usize >> 10] as usize;
+        self.chr.storeb(bank, 0x400, addr, val)
+    }
+    fn mirroring(&self) -> Mirroring {
+        vrc_mirroring(self.mirroring)
+    }
+    /// The counter decrements every CPU cycle, the IRQ fires when it wraps to $FFFF
+    fn clock(&mut self) {
+        if self.irq_control & 0x80 != 0 {
+            self.irq_counter = self.irq_counter.wrapping_sub(1);
+            if self.irq_counter == 0xFFFF && self.irq_control & 0x01 != 0 {
+                self.irq = true;
+            }
+        }
+        self.audio.clock();
+    }
+    fn irq(&self) -> bool {
+        self.irq
+    }
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::numbered;
+    use super::*;
+
+    fn command(fme7: &mut Fme7, command: u8, val: u8) {
+        fme7.cpu_storeb(0x8000, command);
+        fme7.cpu_storeb(0xA000, val);
+    }
+
+    #[test]
+    fn prg_banks_and_ram() {
+        let prg = numbered::<0x10000>(0x2000);
+        let mut ram = [0; 0x2000];
+        let mut fme7 = Fme7::new(PRG::new(&prg, &mut ram, 0), CHR::new(&[0; 0x2000], &mut []));
+        for (n, bank) in [(0x8, 1), (0x9, 2), (0xA, 3), (0xB, 4)].iter() {
+            command(&mut fme7, *n, *bank);
+        }
+        for (i, &bank) in [1, 2, 3, 4, 7].iter().enumerate() {
+            assert_eq!(fme7.cpu_peekb(0x6000 + i as u16 * 0x2000), Some(bank));
+        }
+        // RAM selected but disabled is open bus
+        command(&mut fme7, 0x8, 0x40);
+        assert_eq!(fme7.cpu_peekb(0x6000), None);
+        fme7.cpu_storeb(0x6000, 0x12);
+        command(&mut fme7, 0x8, 0xC0);
+        assert_eq!(fme7.cpu_peekb(0x6000), Some(0));
+        fme7.cpu_storeb(0x6000, 0x12);
+        assert_eq!(fme7.cpu_peekb(0x6000), Some(0x12));
+    }
+
+    #[test]
+    fn irq_when_counter_wraps() {
+        let mut fme7 = Fme7::new(
+            PRG::new(&[0; 0x8000], &mut [], 0),
+            CHR::new(&[0; 0x2000], &mut []),
+        );
+        command(&mut fme7, 0xE, 0x01);
+        command(&mut fme7, 0xF, 0x00);
+        command(&mut fme7, 0xD, 0x81);
+        fme7.clock();
+        assert!(!fme7.irq());
+        fme7.clock();
+        assert!(fme7.irq());
+        // Acknowledged by writing the control register
+        command(&mut fme7, 0xD, 0x81);
+        assert!(!fme7.irq());
+        // The counter keeps going from $FFFF
+        for _ in 0..0xFFFF {
+            fme7.clock();
+        }
+        assert!(!fme7.irq());
+        fme7.clock();
+        assert!(fme7.irq());
+        // Counting without the IRQ enabled
+        command(&mut fme7, 0xD, 0x80);
+        for _ in 0..0x10000 {
+            fme7.clock();
+        }
+        assert!(!fme7.irq());
+    }
+}
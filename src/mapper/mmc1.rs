@@ -0,0 +1,284 @@
+use super::{open_bus, Mapper, CHR, PRG};
+use crate::ppu::Mirroring;
+
+/// Mapper 1, Nintendo SxROM boards with the MMC1.
+/// Registers are loaded serially: 5 writes of bit 0 to $8000-$FFFF,
+/// the address of the 5th write selects the register.
+/// see http://wiki.nesdev.com/w/index.php/MMC1
+pub struct Mmc1<'a> {
+    pub(super) prg: PRG<'a>,
+    chr: CHR<'a>,
+    /// Shift register, bit 4 is set on reset and reaches bit 0 after 4 writes
+    shift: u8,
+    /// $8000-$9FFF CPPMM
+    /// C: CHR mode 0(8 KB) 1(two 4 KB)
+    /// PP: PRG mode 0,1(32 KB) 2(fix first bank at $8000) 3(fix last bank at $C000)
+    /// MM: mirroring 0(one-screen lower) 1(one-screen upper) 2(vertical) 3(horizontal)
+    control: u8,
+    /// $A000-$BFFF and $C000-$DFFF, 4 KB CHR banks.
+    /// SxROM boards wire the upper bits to PRG-RAM and PRG-ROM banking.
+    chr_bank: [u8; 2],
+    /// $E000-$FFFF RPPPP
+    /// R: PRG-RAM disable, PPPP: 16 KB PRG bank
+    prg_bank: u8,
+    /// The last pattern table access was at $1000-$1FFF, so SxROM boards
+    /// take the upper bits from the second CHR bank register in 4 KB mode
+    chr_high: bool,
+    /// CPU cycles seen by `clock`
+    cycle: usize,
+    /// Cycle of the last write to the shift register
+    last_write: Option<usize>,
+}
+
+state!(Mmc1<'a> { prg, chr, shift, control, chr_bank, prg_bank, chr_high, cycle, last_write });
+
+impl<'a> Mmc1<'a> {
+    pub fn new(prg: PRG<'a>, chr: CHR<'a>) -> Mmc1<'a> {
+        Mmc1 {
+            prg,
+            chr,
+            shift: 0x10,
+            control: 0x0C,
+            chr_bank: [0; 2],
+            prg_bank: 0,
+            chr_high: false,
+            cycle: 0,
+            last_write: None,
+        }
+    }
+    /// CHR bank register driving the SxROM extra lines
+    fn outer(&self) -> u8 {
+        if self.control & 0x10 != 0 && self.chr_high {
+            self.chr_bank[1]
+        } else {
+            self.chr_bank[0]
+        }
+    }
+    /// 256 KB PRG-ROM block of SUROM/SXROM, selected by CHR bank bit 4
+    fn prg_outer(&self) -> usize {
+        if self.prg.banks(0x4000) > 16 {
+            (self.outer() as usize & 0x10) & (self.prg.banks(0x4000) - 1)
+        } else {
+            0
+        }
+    }
+    /// 8 KB PRG-RAM bank, selected by CHR bank bit 3 on SOROM (16 KB)
+    /// and bits 2-3 on SXROM (32 KB)
+    fn ram_bank(&self) -> usize {
+        match self.prg.ram_size() {
+            0x4000 => (self.outer() as usize >> 3) & 0x1,
+            0x8000 => (self.outer() as usize >> 2) & 0x3,
+            _ => 0,
+        }
+    }
+    fn ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0
+    }
+    fn prg_rom_bank(&self, addr: u16) -> usize {
+        let bank = self.prg_bank as usize & 0x0F;
+        let high = addr >= 0xC000;
+        let bank = match (self.control >> 2) & 0x3 {
+            0 | 1 => (bank & 0x0E) | high as usize,
+            2 if high => bank,
+            2 => 0,
+            _ if high => 0x0F,
+            _ => bank,
+        };
+        self.prg_outer() | bank
+    }
+    fn chr_bank(&self, addr: u16) -> usize {
+        if self.control & 0x10 == 0 {
+            // 8 KB mode ignores the low bit
+            (self.chr_bank[0] as usize & 0x1E) | ((addr >> 12) & 1) as usize
+        } else {
+            self.chr_bank[(addr >> 12) as usize & 1] as usize
+        }
+    }
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x8000..=0x9FFF => self.control = val,
+            0xA000..=0xBFFF => self.chr_bank[0] = val,
+            0xC000..=0xDFFF => self.chr_bank[1] = val,
+            0xE000..=0xFFFF => self.prg_bank = val,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<'a> Mapper for Mmc1<'a> {
+    fn cpu_loadb(&mut self, addr: u16) -> u8 {
+        self.cpu_peekb(addr).unwrap_or_else(|| open_bus(addr))
+    }
+    fn cpu_peekb(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.ram_enabled() => Some(
+                self.prg
+                    .ram(self.ram_bank() << 13 | (addr as usize & 0x1FFF)),
+            ),
+            0x8000..=0xFFFF => Some(self.prg.rom(self.prg_rom_bank(addr), 0x4000, addr)),
+            _ => None,
+        }
+    }
+    fn cpu_storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000..=0x7FFF if self.ram_enabled() => {
+                let offset = self.ram_bank() << 13 | (addr as usize & 0x1FFF);
+                self.prg.set_ram(offset, val);
+            }
+            0x8000..=0xFFFF => {
+                // Writes on consecutive cycles, like the dummy write of
+                // read-modify-write instructions, are ignored
+                let consecutive = match self.last_write {
+                    Some(cycle) => self.cycle - cycle <= 1,
+                    None => false,
+                };
+                self.last_write = Some(self.cycle);
+                if consecutive {
+                    return;
+                }
+                if val & 0x80 != 0 {
+                    self.shift = 0x10;
+                    self.control |= 0x0C;
+                    return;
+                }
+                let full = self.shift & 1 != 0;
+                self.shift = (self.shift >> 1) | ((val & 1) << 4);
+                if full {
+                    let val = self.shift;
+                    self.shift = 0x10;
+                    self.write_register(addr, val);
+                }
+            }
+            _ => {}
+        }
+    }
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.chr_high = addr & 0x1000 != 0;
+        self.chr.loadb(self.chr_bank(addr), 0x1000, addr)
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.chr_high = addr & 0x1000 != 0;
+        self.chr.storeb(self.chr_bank(addr), 0x1000, addr, val)
+    }
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x3 {
+            0 => Mirroring::OneScreenLower,
+            1 => Mirroring::OneScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+    fn clock(&mut self) {
+        self.cycle += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::numbered;
+    use super::*;
+
+    /// Load `val` serially into the register at `addr`, writes are a few cycles apart
+    fn write(mmc1: &mut Mmc1, addr: u16, val: u8) {
+        for bit in 0..5 {
+            mmc1.cpu_storeb(addr, val >> bit & 1);
+            mmc1.clock();
+            mmc1.clock();
+        }
+    }
+
+    /// Banks of PRG-ROM at $8000 and $C000
+    fn prg_banks(mmc1: &Mmc1) -> (u8, u8) {
+        (
+            mmc1.cpu_peekb(0x8000).unwrap(),
+            mmc1.cpu_peekb(0xC000).unwrap(),
+        )
+    }
+
+    #[test]
+    fn prg_modes() {
+        let prg = numbered::<0x40000>(0x4000);
+        let mut mmc1 = Mmc1::new(PRG::new(&prg, &mut [], 0), CHR::new(&[0; 0x2000], &mut []));
+        // The last bank is fixed at $C000 on power on
+        assert_eq!(prg_banks(&mmc1), (0, 15));
+        write(&mut mmc1, 0xE000, 5);
+        assert_eq!(prg_banks(&mmc1), (5, 15));
+        write(&mut mmc1, 0x8000, 0x08);
+        assert_eq!(prg_banks(&mmc1), (0, 5));
+        // 32 KB mode ignores the low bit
+        write(&mut mmc1, 0x8000, 0x00);
+        assert_eq!(prg_banks(&mmc1), (4, 5));
+    }
+
+    #[test]
+    fn reset_bit() {
+        let prg = numbered::<0x40000>(0x4000);
+        let mut mmc1 = Mmc1::new(PRG::new(&prg, &mut [], 0), CHR::new(&[0; 0x2000], &mut []));
+        write(&mut mmc1, 0x8000, 0x00);
+        for _ in 0..4 {
+            mmc1.cpu_storeb(0xE000, 1);
+            mmc1.clock();
+            mmc1.clock();
+        }
+        // Drops the 4 bits written and goes back to the fixed last bank
+        mmc1.cpu_storeb(0xE000, 0x80);
+        mmc1.clock();
+        mmc1.clock();
+        assert_eq!(prg_banks(&mmc1), (0, 15));
+        write(&mut mmc1, 0xE000, 3);
+        assert_eq!(prg_banks(&mmc1), (3, 15));
+    }
+
+    #[test]
+    fn consecutive_writes_ignored() {
+        let prg = numbered::<0x40000>(0x4000);
+        let mut mmc1 = Mmc1::new(PRG::new(&prg, &mut [], 0), CHR::new(&[0; 0x2000], &mut []));
+        // Like the dummy write then the write of INC $E000
+        mmc1.cpu_storeb(0xE000, 1);
+        mmc1.clock();
+        mmc1.cpu_storeb(0xE000, 1);
+        mmc1.clock();
+        mmc1.clock();
+        for _ in 0..4 {
+            mmc1.cpu_storeb(0xE000, 0);
+            mmc1.clock();
+            mmc1.clock();
+        }
+        assert_eq!(prg_banks(&mmc1), (1, 15));
+    }
+
+    #[test]
+    fn chr_modes_and_mirroring() {
+        let chr = numbered::<0x20000>(0x1000);
+        let mut mmc1 = Mmc1::new(PRG::new(&[0; 0x8000], &mut [], 0), CHR::new(&chr, &mut []));
+        write(&mut mmc1, 0xA000, 3);
+        write(&mut mmc1, 0xC000, 7);
+        assert_eq!((mmc1.chr_loadb(0x0000), mmc1.chr_loadb(0x1000)), (2, 3));
+        write(&mut mmc1, 0x8000, 0x1E);
+        assert_eq!((mmc1.chr_loadb(0x0000), mmc1.chr_loadb(0x1000)), (3, 7));
+        assert_eq!(mmc1.mirroring(), Mirroring::Vertical);
+        write(&mut mmc1, 0x8000, 0x1D);
+        assert_eq!(mmc1.mirroring(), Mirroring::OneScreenUpper);
+    }
+
+    #[test]
+    fn prg_ram_banks_and_disable() {
+        let (mut ram, mut chr_ram) = ([0; 0x8000], [0; 0x2000]);
+        let mut mmc1 = Mmc1::new(
+            PRG::new(&[0; 0x8000], &mut ram, 0),
+            CHR::new(&[], &mut chr_ram),
+        );
+        mmc1.cpu_storeb(0x6000, 0x11);
+        // SXROM: bits 2-3 of the CHR bank select the 8 KB RAM bank
+        write(&mut mmc1, 0xA000, 0x04);
+        assert_eq!(mmc1.cpu_peekb(0x6000), Some(0));
+        mmc1.cpu_storeb(0x6000, 0x22);
+        write(&mut mmc1, 0xA000, 0x00);
+        assert_eq!(mmc1.cpu_peekb(0x6000), Some(0x11));
+        write(&mut mmc1, 0xE000, 0x10);
+        assert_eq!(mmc1.cpu_peekb(0x6000), None);
+        mmc1.cpu_storeb(0x6000, 0x33);
+        write(&mut mmc1, 0xE000, 0x00);
+        assert_eq!(mmc1.cpu_peekb(0x6000), Some(0x11));
+    }
+}
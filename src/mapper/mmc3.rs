@@ -0,0 +1,417 @@
+use super::{open_bus, Mapper, CHR, PRG};
+use crate::ppu::{Mirroring, NameTable};
+
+/// Mapper 4, Nintendo TxROM boards with the MMC3.
+/// see http://wiki.nesdev.com/w/index.php/MMC3
+pub struct Mmc3<'a> {
+    pub(super) prg: PRG<'a>,
+    chr: CHR<'a>,
+    /// $8000 CP-- -RRR
+    /// C: CHR A12 inversion, P: PRG mode, RRR: register updated by $8001
+    bank_select: u8,
+    /// R0-R1: 2 KB CHR banks, R2-R5: 1 KB CHR banks, R6-R7: 8 KB PRG banks
+    banks: [u8; 8],
+    mirroring: Mirroring,
+    /// $A001 RW-- ----
+    /// R: PRG-RAM enable, W: deny writes
+    ram_protect: u8,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq: bool,
+    /// MMC3A and NEC MMC3 only raise an IRQ when the counter becomes 0,
+    /// not every time it is clocked while 0
+    old_irq: bool,
+    /// Current level of PPU A12
+    a12: bool,
+    /// Cycle A12 went low
+    a12_fall: usize,
+    /// CPU cycles seen by `clock`
+    cycle: usize,
+}
+
+state!(Mmc3<'a> {
+    prg,
+    chr,
+    bank_select,
+    banks,
+    mirroring,
+    ram_protect,
+    irq_latch,
+    irq_counter,
+    irq_reload,
+    irq_enabled,
+    irq,
+    a12,
+    a12_fall,
+    cycle
+});
+
+impl<'a> Mmc3<'a> {
+    pub fn new(prg: PRG<'a>, chr: CHR<'a>, mirroring: Mirroring, old_irq: bool) -> Mmc3<'a> {
+        Mmc3 {
+            prg,
+            chr,
+            bank_select: 0,
+            banks: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring,
+            ram_protect: 0x80,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq: false,
+            old_irq,
+            a12: false,
+            a12_fall: 0,
+            cycle: 0,
+        }
+    }
+    fn prg_bank(&self, addr: u16) -> usize {
+        let second_last = self.prg.banks(0x2000).saturating_sub(2);
+        let swap = self.bank_select & 0x40 != 0;
+        match (addr >> 13) & 0x3 {
+            0 if swap => second_last,
+            0 => self.banks[6] as usize,
+            1 => self.banks[7] as usize,
+            2 if swap => self.banks[6] as usize,
+            2 => second_last,
+            _ => second_last + 1,
+        }
+    }
+    fn chr_bank(&self, addr: u16) -> usize {
+        // A12 inversion swaps the 2 KB and the 1 KB halves
+        let addr = if self.bank_select & 0x80 != 0 {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+        match addr >> 10 {
+            0 => self.banks[0] as usize & 0xFE,
+            1 => self.banks[0] as usize | 1,
+            2 => self.banks[1] as usize & 0xFE,
+            3 => self.banks[1] as usize | 1,
+            n => self.banks[n as usize - 2] as usize,
+        }
+    }
+    fn ram_readable(&self) -> bool {
+        self.ram_protect & 0x80 != 0
+    }
+    fn ram_writable(&self) -> bool {
+        self.ram_protect & 0xC0 == 0x80
+    }
+    /// Watch PPU A12, the IRQ counter is clocked by its rising edges after
+    /// it has been low for a few CPU cycles
+    fn watch_a12(&mut self, addr: u16) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.a12 && self.cycle - self.a12_fall >= 3 {
+            self.clock_irq();
+        }
+        if !a12 && self.a12 {
+            self.a12_fall = self.cycle;
+        }
+        self.a12 = a12;
+    }
+    fn clock_irq(&mut self) {
+        let before = self.irq_counter;
+        let reload = self.irq_reload;
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        let trigger = if self.old_irq {
+            self.irq_counter == 0 && (before != 0 || reload)
+        } else {
+            self.irq_counter == 0
+        };
+        if trigger && self.irq_enabled {
+            self.irq = true;
+        }
+    }
+}
+
+impl<'a> Mapper for Mmc3<'a> {
+    fn cpu_loadb(&mut self, addr: u16) -> u8 {
+        self.cpu_peekb(addr).unwrap_or_else(|| open_bus(addr))
+    }
+    fn cpu_peekb(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.ram_readable() => Some(self.prg.ram(addr as usize & 0x1FFF)),
+            0x8000..=0xFFFF => Some(self.prg.rom(self.prg_bank(addr), 0x2000, addr)),
+            _ => None,
+        }
+    }
+    fn cpu_storeb(&mut self, addr: u16, val: u8) {
+        match (addr, addr & 1) {
+            (0x6000..=0x7FFF, _) => {
+                if self.ram_writable() {
+                    self.prg.set_ram(addr as usize & 0x1FFF, val);
+                }
+            }
+            (0x8000..=0x9FFF, 0) => self.bank_select = val,
+            (0x8000..=0x9FFF, _) => self.banks[self.bank_select as usize & 0x7] = val,
+            // Boards with four screen VRAM ignore it
+            (0xA000..=0xBFFF, 0) if self.mirroring != Mirroring::FourScreen => {
+                self.mirroring = if val & 1 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                };
+            }
+            (0xA000..=0xBFFF, 0) => {}
+            (0xA000..=0xBFFF, _) => self.ram_protect = val,
+            (0xC000..=0xDFFF, 0) => self.irq_latch = val,
+            (0xC000..=0xDFFF, _) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xE000..=0xFFFF, 0) => {
+                self.irq_enabled = false;
+                self.irq = false;
+            }
+            (0xE000..=0xFFFF, _) => self.irq_enabled = true,
+            _ => {}
+        }
+    }
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.chr.loadb(self.chr_bank(addr), 0x400, addr)
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.chr.storeb(self.chr_bank(addr), 0x400, addr, val)
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+    /// Nametable fetches pull A12 low too, between pattern fetches
+    fn ppu_loadb(&mut self, addr: u16, ciram: &NameTable) -> u8 {
+        self.watch_a12(addr);
+        match addr {
+            0x0000..=0x1FFF => self.chr_loadb(addr),
+            _ => ciram.loadb(addr, self.mirroring),
+        }
+    }
+    fn ppu_storeb(&mut self, addr: u16, val: u8, ciram: &mut NameTable) {
+        self.watch_a12(addr);
+        match addr {
+            0x0000..=0x1FFF => self.chr_storeb(addr, val),
+            _ => ciram.storeb(addr, self.mirroring, val),
+        }
+    }
+    fn ppu_addr(&mut self, addr: u16) {
+        self.watch_a12(addr);
+    }
+    fn clock(&mut self) {
+        self.cycle += 1;
+    }
+    fn irq(&self) -> bool {
+        self.irq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::numbered;
+    use super::*;
+    use crate::ppu::{Screen, PPU};
+
+    fn prg_banks(mmc3: &Mmc3) -> [u8; 4] {
+        let mut banks = [0; 4];
+        for (i, bank) in banks.iter_mut().enumerate() {
+            *bank = mmc3.cpu_peekb(0x8000 + i as u16 * 0x2000).unwrap();
+        }
+        banks
+    }
+
+    fn chr_banks(mmc3: &mut Mmc3) -> [u8; 8] {
+        let mut banks = [0; 8];
+        for (i, bank) in banks.iter_mut().enumerate() {
+            *bank = mmc3.chr_loadb(i as u16 * 0x400);
+        }
+        banks
+    }
+
+    /// Write `vals` to R0-R7 with `mode` in the upper bits of $8000
+    fn set_banks(mmc3: &mut Mmc3, mode: u8, vals: [u8; 8]) {
+        for (r, &val) in vals.iter().enumerate() {
+            mmc3.cpu_storeb(0x8000, mode | r as u8);
+            mmc3.cpu_storeb(0x8001, val);
+        }
+    }
+
+    #[test]
+    fn prg_modes() {
+        let prg = numbered::<0x10000>(0x2000);
+        let chr = [0; 0x2000];
+        let mut mmc3 = Mmc3::new(
+            PRG::new(&prg, &mut [], 0),
+            CHR::new(&chr, &mut []),
+            Mirroring::Vertical,
+            false,
+        );
+        set_banks(&mut mmc3, 0x00, [0, 0, 0, 0, 0, 0, 2, 3]);
+        assert_eq!(prg_banks(&mmc3), [2, 3, 6, 7]);
+        mmc3.cpu_storeb(0x8000, 0x40);
+        assert_eq!(prg_banks(&mmc3), [6, 3, 2, 7]);
+    }
+
+    #[test]
+    fn chr_modes() {
+        let chr = numbered::<0x10000>(0x400);
+        let mut mmc3 = Mmc3::new(
+            PRG::new(&[0; 0x8000], &mut [], 0),
+            CHR::new(&chr, &mut []),
+            Mirroring::Vertical,
+            false,
+        );
+        // 2 KB banks ignore the low bit
+        set_banks(&mut mmc3, 0x00, [5, 6, 10, 11, 12, 13, 0, 1]);
+        assert_eq!(chr_banks(&mut mmc3), [4, 5, 6, 7, 10, 11, 12, 13]);
+        mmc3.cpu_storeb(0x8000, 0x80);
+        assert_eq!(chr_banks(&mut mmc3), [10, 11, 12, 13, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn ram_protect() {
+        let mut ram = [0; 0x2000];
+        let mut mmc3 = Mmc3::new(
+            PRG::new(&[0; 0x8000], &mut ram, 0),
+            CHR::new(&[0; 0x2000], &mut []),
+            Mirroring::Vertical,
+            false,
+        );
+        mmc3.cpu_storeb(0x6000, 1);
+        assert_eq!(mmc3.cpu_peekb(0x6000), Some(1));
+        mmc3.cpu_storeb(0xA001, 0xC0);
+        mmc3.cpu_storeb(0x6000, 2);
+        assert_eq!(mmc3.cpu_peekb(0x6000), Some(1));
+        mmc3.cpu_storeb(0xA001, 0x00);
+        assert_eq!(mmc3.cpu_peekb(0x6000), None);
+    }
+
+    fn irq_setup(latch: u8, old_irq: bool) -> Mmc3<'static> {
+        let mut mmc3 = Mmc3::new(
+            PRG::new(&[0; 0x8000], &mut [], 0),
+            CHR::new(&[0; 0x2000], &mut []),
+            Mirroring::Vertical,
+            old_irq,
+        );
+        mmc3.cpu_storeb(0xC000, latch);
+        mmc3.cpu_storeb(0xC001, 0);
+        mmc3.cpu_storeb(0xE001, 0);
+        mmc3
+    }
+
+    #[test]
+    fn irq_reload() {
+        let mut mmc3 = irq_setup(2, false);
+        // Reloaded by the first clock, then counts down to 0
+        mmc3.clock_irq();
+        mmc3.clock_irq();
+        assert!(!mmc3.irq());
+        mmc3.clock_irq();
+        assert!(mmc3.irq());
+        // Acknowledged by disabling it
+        mmc3.cpu_storeb(0xE000, 0);
+        mmc3.cpu_storeb(0xE001, 0);
+        assert!(!mmc3.irq());
+        // Reloaded from 0, a new latch is used
+        mmc3.cpu_storeb(0xC000, 1);
+        mmc3.clock_irq();
+        assert!(!mmc3.irq());
+        mmc3.clock_irq();
+        assert!(mmc3.irq());
+    }
+
+    #[test]
+    fn irq_with_latch_zero() {
+        // Every clock on the MMC3B/C
+        let mut mmc3 = irq_setup(0, false);
+        for _ in 0..3 {
+            mmc3.clock_irq();
+            assert!(mmc3.irq());
+            mmc3.cpu_storeb(0xE000, 0);
+            mmc3.cpu_storeb(0xE001, 0);
+        }
+        // Only after writing $C001 on the MMC3A
+        let mut mmc3 = irq_setup(0, true);
+        mmc3.clock_irq();
+        assert!(mmc3.irq());
+        mmc3.cpu_storeb(0xE000, 0);
+        mmc3.cpu_storeb(0xE001, 0);
+        mmc3.clock_irq();
+        assert!(!mmc3.irq());
+    }
+
+    struct NoScreen;
+
+    impl Screen for NoScreen {
+        fn render_pixel(&mut self, _x: u16, _y: u16, _pixel: (u8, u8, u8)) {}
+    }
+
+    /// Run a frame from VBlank with `ctrl` written to PPUCTRL and rendering
+    /// on, return the scanline and dot the IRQ was raised at
+    fn irq_position(ctrl: u8, oam: u8) -> Option<(u16, usize)> {
+        let mut ppu = PPU::new();
+        let mut mmc3 = irq_setup(3, false);
+        ppu.oam = [oam; 0x100];
+        while ppu.scanline != 250 {
+            ppu.step(&mut mmc3, &mut NoScreen);
+        }
+        ppu.reg_storeb(&mut mmc3, 0x2000, ctrl);
+        ppu.reg_storeb(&mut mmc3, 0x2001, 0x18);
+        // The CPU runs a cycle every 3 dots
+        for dot in 0..341 * 262 {
+            ppu.step(&mut mmc3, &mut NoScreen);
+            if dot % 3 == 2 {
+                mmc3.clock();
+            }
+            if mmc3.irq() {
+                return Some((ppu.scanline, ppu.cycles));
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn irq_timing() {
+        // Background at $0000 and sprites at $1000, A12 rises once per
+        // line during sprite fetches. Reloaded on the pre-render line.
+        let (scanline, dot) = irq_position(0x08, 0x00).unwrap();
+        assert_eq!(scanline, 2);
+        assert!((257..=320).contains(&dot));
+    }
+
+    #[test]
+    fn irq_timing_8x16() {
+        // Empty sprite slots fetch tile $FF, in 8x16 mode from $1000
+        let (scanline, dot) = irq_position(0x20, 0xFF).unwrap();
+        assert_eq!(scanline, 2);
+        assert!((257..=320).contains(&dot));
+    }
+
+    #[test]
+    fn no_irq_with_a12_low() {
+        assert_eq!(irq_position(0x00, 0x00), None);
+    }
+
+    #[test]
+    fn nametable_accesses_pull_a12_low() {
+        let mut ppu = PPU::new();
+        let mut mmc3 = irq_setup(1, false);
+        let wait = |mmc3: &mut Mmc3| (0..3).for_each(|_| mmc3.clock());
+        wait(&mut mmc3);
+        ppu.reg_storeb(&mut mmc3, 0x2006, 0x1F);
+        ppu.reg_storeb(&mut mmc3, 0x2006, 0xFF);
+        // Reads $1FFF then $2000
+        ppu.reg_loadb(&mut mmc3, 0x2007);
+        ppu.reg_loadb(&mut mmc3, 0x2007);
+        wait(&mut mmc3);
+        assert!(!mmc3.irq());
+        ppu.reg_storeb(&mut mmc3, 0x2006, 0x10);
+        ppu.reg_storeb(&mut mmc3, 0x2006, 0x00);
+        assert!(mmc3.irq());
+    }
+}
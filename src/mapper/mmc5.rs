@@ -0,0 +1,670 @@
+use super::{open_bus, Mapper, CHR, PRG};
+use crate::apu::{pulse_out, tnd_out, Pulse};
+use crate::ppu::{Mirroring, NameTable};
+
+/// CPU cycles between the 240 Hz clocks of the pulse envelopes and length counters
+const AUDIO_FRAME: usize = 7457;
+
+/// What the PPU is fetching, worked out from the number of reads since the
+/// scanline started: 32 tiles of 4 reads, 8 sprites of 4 reads, then 2 tiles
+/// of the next scanline and 2 unused nametable reads.
+#[derive(Clone, Copy, PartialEq)]
+enum Fetch {
+    Background,
+    Sprite,
+    /// Not rendering, e.g. $2007 accesses
+    Cpu,
+}
+
+/// Pulse channels and PCM of the MMC5, $5000-$5015.
+/// see http://wiki.nesdev.com/w/index.php/MMC5_audio
+struct Audio {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    /// $5010 I--- ---M
+    /// I: PCM IRQ enable, M: PCM is read from $8000-$BFFF instead of written to $5011
+    pcm_control: u8,
+    pcm: u8,
+    pcm_irq: bool,
+    /// Odd CPU cycle, the pulse timers are clocked on even ones
+    odd: bool,
+    /// CPU cycles until the next envelope and length counter clock
+    frame: usize,
+}
+
+state!(Audio {
+    pulse1,
+    pulse2,
+    pcm_control,
+    pcm,
+    pcm_irq,
+    odd,
+    frame
+});
+
+impl Audio {
+    fn new() -> Audio {
+        Audio {
+            pulse1: Pulse::without_sweep(),
+            pulse2: Pulse::without_sweep(),
+            pcm_control: 0,
+            pcm: 0,
+            pcm_irq: false,
+            odd: false,
+            frame: AUDIO_FRAME,
+        }
+    }
+    fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            // $5001 and $5005 would be the sweep units
+            0x5001 | 0x5005 => {}
+            0x5000..=0x5003 => self.pulse1.write(addr & 0x3, val),
+            0x5004..=0x5007 => self.pulse2.write(addr & 0x3, val),
+            0x5010 => self.pcm_control = val & 0x81,
+            0x5011 => {
+                if self.pcm_control & 0x01 == 0 && val != 0 {
+                    self.pcm = val;
+                }
+            }
+            0x5015 => {
+                self.pulse1.length.set_enabled(val & 0x01 != 0);
+                self.pulse2.length.set_enabled(val & 0x02 != 0);
+            }
+            _ => {}
+        }
+    }
+    /// Read $5010, acknowledges the PCM IRQ
+    fn read_control(&mut self) -> u8 {
+        let val = (self.irq() as u8) << 7 | (self.pcm_control & 0x01);
+        self.pcm_irq = false;
+        val
+    }
+    /// Read $5015
+    fn read_status(&self) -> u8 {
+        self.pulse1.length.active() as u8 | (self.pulse2.length.active() as u8) << 1
+    }
+    /// In read mode the PCM latches what the CPU reads from $8000-$BFFF,
+    /// a zero raises the IRQ instead
+    fn snoop(&mut self, val: u8) {
+        if self.pcm_control & 0x01 != 0 {
+            if val == 0 {
+                self.pcm_irq = true;
+            } else {
+                self.pcm = val;
+            }
+        }
+    }
+    fn irq(&self) -> bool {
+        self.pcm_irq && self.pcm_control & 0x80 != 0
+    }
+    fn clock(&mut self) {
+        if !self.odd {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd = !self.odd;
+        self.frame -= 1;
+        if self.frame == 0 {
+            self.frame = AUDIO_FRAME;
+            self.pulse1.envelope.clock();
+            self.pulse2.envelope.clock();
+            self.pulse1.length.clock();
+            self.pulse2.length.clock();
+        }
+    }
+    fn output(&self) -> f32 {
+        let pulse = self.pulse1.output() + self.pulse2.output();
+        // 8 bit PCM through a DAC about as loud as the 7 bit DMC
+        pulse_out(pulse as f32) + tnd_out(self.pcm as f32 / 2.0)
+    }
+}
+
+/// Mapper 5, Nintendo ExROM boards with the MMC5.
+/// see http://wiki.nesdev.com/w/index.php/MMC5
+pub struct Mmc5<'a> {
+    pub(super) prg: PRG<'a>,
+    chr: CHR<'a>,
+    /// $5100 PRG mode, 0(32 KB) 1(16 KB) 2(16 KB + 8 KB) 3(8 KB)
+    prg_mode: u8,
+    /// $5101 CHR mode, 0(8 KB) 1(4 KB) 2(2 KB) 3(1 KB)
+    chr_mode: u8,
+    /// $5102 and $5103, PRG-RAM is writable when they are 2 and 1
+    ram_protect: [u8; 2],
+    /// $5104 0(nametable) 1(extended attributes) 2(CPU RAM) 3(CPU ROM)
+    exram_mode: u8,
+    /// $5105 DDCC BBAA, source of each nametable
+    /// 0(CIRAM page 0) 1(CIRAM page 1) 2(ExRAM) 3(fill mode)
+    nametables: u8,
+    /// $5106
+    fill_tile: u8,
+    /// $5107
+    fill_attr: u8,
+    /// $5113-$5117 8 KB PRG banks, bit 7 selects ROM in $8000-$DFFF
+    prg_banks: [u8; 5],
+    /// $5120-$512B 1 KB CHR banks, with the 2 bits of $5130 on top
+    chr_banks: [u16; 12],
+    /// $5130
+    chr_upper: u8,
+    /// $5128-$512B were written after $5120-$5127
+    chr_last_b: bool,
+    /// $5200 ER-T TTTT
+    /// E: vertical split enable, R: split on the right side, T: split tile
+    split_control: u8,
+    /// $5201
+    split_scroll: u8,
+    /// $5202 4 KB CHR bank of the split
+    split_bank: u8,
+    /// $5203
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    /// The PPU is rendering a frame
+    in_frame: bool,
+    /// Scanlines since the frame started
+    scanline: u8,
+    /// $5205 and $5206
+    factors: [u8; 2],
+    exram: [u8; 0x400],
+    /// PPUCTRL sprite size, snooped from $2000 writes
+    sprite_8x16: bool,
+    /// Last nametable address read and how many times in a row
+    last_nametable: u16,
+    repeats: u8,
+    /// PPU reads since the scanline started
+    fetches: usize,
+    /// CPU cycles since the last PPU read
+    idle: u8,
+    /// ExRAM byte of the tile being fetched in extended attribute mode
+    ext_attr: u8,
+    audio: Audio,
+}
+
+state!(Mmc5<'a> {
+    prg,
+    chr,
+    prg_mode,
+    chr_mode,
+    ram_protect,
+    exram_mode,
+    nametables,
+    fill_tile,
+    fill_attr,
+    prg_banks,
+    chr_banks,
+    chr_upper,
+    chr_last_b,
+    split_control,
+    split_scroll,
+    split_bank,
+    irq_compare,
+    irq_enabled,
+    irq_pending,
+    in_frame,
+    scanline,
+    factors,
+    exram,
+    sprite_8x16,
+    last_nametable,
+    repeats,
+    fetches,
+    idle,
+    ext_attr,
+    audio
+});
+
+impl<'a> Mmc5<'a> {
+    pub fn new(prg: PRG<'a>, chr: CHR<'a>) -> Mmc5<'a> {
+        Mmc5 {
+            prg,
+            chr,
+            prg_mode: 3,
+            chr_mode: 0,
+            ram_protect: [0; 2],
+            exram_mode: 0,
+            nametables: 0,
+            fill_tile: 0,
+            fill_attr: 0,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            chr_last_b: false,
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline: 0,
+            factors: [0xFF; 2],
+            exram: [0; 0x400],
+            sprite_8x16: false,
+            last_nametable: 0,
+            repeats: 0,
+            fetches: 0,
+            idle: 0,
+            ext_attr: 0,
+            audio: Audio::new(),
+        }
+    }
+    /// 8 KB bank of the window `addr` is in, bit 7 set for ROM
+    fn prg_bank(&self, addr: u16) -> u8 {
+        let banks = &self.prg_banks;
+        let n = ((addr >> 13) & 0x3) as u8;
+        if addr < 0x8000 {
+            return banks[0] & 0x7F;
+        }
+        match (self.prg_mode, n) {
+            (0, _) => banks[4] & 0xFC | n | 0x80,
+            (1, 0..=1) | (2, 0..=1) => banks[2] & 0xFE | n,
+            (1, _) => banks[4] & 0xFE | (n & 1) | 0x80,
+            (2, 2) => banks[3],
+            (_, 3) => banks[4] | 0x80,
+            (_, _) => banks[1 + n as usize],
+        }
+    }
+    fn prg_ram_addr(bank: u8, addr: u16) -> usize {
+        (bank as usize & 0x0F) * 0x2000 + (addr as usize & 0x1FFF)
+    }
+    fn ram_writable(&self) -> bool {
+        self.ram_protect == [2, 1]
+    }
+    /// CHR bank and window size for `addr`. In 8x16 sprite mode sprites use
+    /// $5120-$5127 and backgrounds $5128-$512B, in 8x8 mode every fetch uses
+    /// the set written last.
+    fn chr_bank(&self, addr: u16, fetch: Fetch) -> (usize, usize) {
+        let set_b = match fetch {
+            _ if !self.sprite_8x16 => self.chr_last_b,
+            Fetch::Sprite => false,
+            Fetch::Background => true,
+            Fetch::Cpu => self.chr_last_b,
+        };
+        let addr = addr as usize;
+        let (reg, size) = match (self.chr_mode, set_b) {
+            (0, false) => (7, 0x2000),
+            (0, true) => (11, 0x2000),
+            (1, false) => (3 + 4 * (addr >> 12), 0x1000),
+            (1, true) => (11, 0x1000),
+            (2, false) => (1 + 2 * (addr >> 11), 0x800),
+            (2, true) => (9 + 2 * ((addr >> 11) & 1), 0x800),
+            (_, false) => (addr >> 10, 0x400),
+            (_, true) => (8 + ((addr >> 10) & 0x3), 0x400),
+        };
+        (self.chr_banks[reg] as usize, size)
+    }
+    fn chr_read(&self, addr: u16, fetch: Fetch) -> u8 {
+        let (bank, size) = self.chr_bank(addr, fetch);
+        self.chr.loadb(bank, size, addr)
+    }
+    /// Source of the nametable `addr` is in
+    fn nametable(&self, addr: u16) -> u8 {
+        (self.nametables >> (((addr >> 10) & 0x3) * 2)) & 0x3
+    }
+    fn nametable_read(&self, addr: u16, ciram: &NameTable) -> u8 {
+        match self.nametable(addr) {
+            0 | 1 => ciram.loadb(addr, self.mirroring()),
+            2 if self.exram_mode <= 1 => self.exram[addr as usize & 0x3FF],
+            2 => 0,
+            _ if addr & 0x3FF >= 0x3C0 => self.fill_attr * 0x55,
+            _ => self.fill_tile,
+        }
+    }
+    /// Three reads in a row of the same nametable address happen at the
+    /// end of every rendered scanline
+    fn watch_fetch(&mut self, addr: u16) {
+        self.idle = 0;
+        if (0x2000..=0x2FFF).contains(&addr) && addr == self.last_nametable {
+            self.repeats += 1;
+            if self.repeats == 2 {
+                self.start_scanline();
+            }
+        } else {
+            self.repeats = 0;
+        }
+        self.last_nametable = addr;
+    }
+    fn start_scanline(&mut self) {
+        if self.in_frame {
+            self.scanline = self.scanline.wrapping_add(1);
+            if self.scanline == self.irq_compare {
+                self.irq_pending = true;
+            }
+        } else {
+            self.in_frame = true;
+            self.scanline = 0;
+            self.irq_pending = false;
+        }
+        self.fetches = 0;
+    }
+    fn fetch(&self) -> Fetch {
+        match self.fetches {
+            _ if !self.in_frame => Fetch::Cpu,
+            128..=159 => Fetch::Sprite,
+            _ => Fetch::Background,
+        }
+    }
+    /// Tile column and scanline of the background tile being fetched
+    /// if it's inside the split region
+    fn split_tile(&self) -> Option<(u16, u16)> {
+        if self.split_control & 0x80 == 0 || self.exram_mode > 1 {
+            return None;
+        }
+        let (column, line) = match self.fetches {
+            0..=127 => (self.fetches as u16 / 4 + 2, self.scanline as u16),
+            // The first 2 tiles of the next scanline
+            160..=167 => ((self.fetches as u16 - 160) / 4, self.scanline as u16 + 1),
+            _ => return None,
+        };
+        let threshold = (self.split_control & 0x1F) as u16;
+        let inside = if self.split_control & 0x40 != 0 {
+            column >= threshold
+        } else {
+            column < threshold
+        };
+        if inside {
+            Some((column & 0x1F, line))
+        } else {
+            None
+        }
+    }
+    /// The split region is drawn from ExRAM with its own scroll and CHR bank
+    fn split_read(&self, addr: u16, column: u16, line: u16) -> u8 {
+        let y = (self.split_scroll as u16 + line) % 240;
+        match self.fetches % 4 {
+            0 => self.exram[((y >> 3) * 32 + column) as usize],
+            1 => {
+                let attr = self.exram[(0x3C0 + (y >> 5) * 8 + (column >> 2)) as usize];
+                let shift = ((y & 0x10) >> 2) | (column & 0x2);
+                ((attr >> shift) & 0x3) * 0x55
+            }
+            _ => self
+                .chr
+                .loadb(self.split_bank as usize, 0x1000, (addr & !0x7) | (y & 0x7)),
+        }
+    }
+    /// In extended attribute mode every tile has its own palette and 4 KB CHR bank
+    fn ext_attr_read(&mut self, addr: u16, ciram: &NameTable) -> u8 {
+        match self.fetches % 4 {
+            0 => {
+                self.ext_attr = self.exram[addr as usize & 0x3FF];
+                self.nametable_read(addr, ciram)
+            }
+            1 => (self.ext_attr >> 6) * 0x55,
+            _ => {
+                let bank = (self.ext_attr as usize & 0x3F) | (self.chr_upper as usize) << 6;
+                self.chr.loadb(bank, 0x1000, addr)
+            }
+        }
+    }
+}
+
+impl<'a> Mapper for Mmc5<'a> {
+    fn cpu_loadb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x5010 => self.audio.read_control(),
+            0x5015 => self.audio.read_status(),
+            0x5204 => {
+                let val = (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6;
+                self.irq_pending = false;
+                val
+            }
+            _ => {
+                let val = self.cpu_peekb(addr).unwrap_or_else(|| open_bus(addr));
+                match addr {
+                    0x8000..=0xBFFF => self.audio.snoop(val),
+                    // The NMI vector fetch ends the frame
+                    0xFFFA | 0xFFFB => self.in_frame = false,
+                    _ => {}
+                }
+                val
+            }
+        }
+    }
+    fn cpu_peekb(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5205 => Some((self.factors[0] as u16 * self.factors[1] as u16) as u8),
+            0x5206 => Some(((self.factors[0] as u16 * self.factors[1] as u16) >> 8) as u8),
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => Some(self.exram[addr as usize & 0x3FF]),
+            0x6000..=0xFFFF => {
+                let bank = self.prg_bank(addr);
+                if bank & 0x80 != 0 {
+                    Some(self.prg.rom(bank as usize & 0x7F, 0x2000, addr))
+                } else {
+                    Some(self.prg.ram(Self::prg_ram_addr(bank, addr)))
+                }
+            }
+            _ => None,
+        }
+    }
+    fn cpu_storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x5000..=0x5015 => self.audio.write(addr, val),
+            0x5100 => self.prg_mode = val & 0x3,
+            0x5101 => self.chr_mode = val & 0x3,
+            0x5102 => self.ram_protect[0] = val & 0x3,
+            0x5103 => self.ram_protect[1] = val & 0x3,
+            0x5104 => self.exram_mode = val & 0x3,
+            0x5105 => self.nametables = val,
+            0x5106 => self.fill_tile = val,
+            0x5107 => self.fill_attr = val & 0x3,
+            0x5113..=0x5117 => self.prg_banks[(addr - 0x5113) as usize] = val,
+            0x5120..=0x512B => {
+                let reg = (addr - 0x5120) as usize;
+                self.chr_banks[reg] = val as u16 | (self.chr_upper as u16) << 8;
+                self.chr_last_b = reg >= 8;
+            }
+            0x5130 => self.chr_upper = val & 0x3,
+            0x5200 => self.split_control = val,
+            0x5201 => self.split_scroll = val,
+            0x5202 => self.split_bank = val,
+            0x5203 => self.irq_compare = val,
+            0x5204 => self.irq_enabled = val & 0x80 != 0,
+            0x5205 => self.factors[0] = val,
+            0x5206 => self.factors[1] = val,
+            0x5C00..=0x5FFF => {
+                let index = addr as usize & 0x3FF;
+                match self.exram_mode {
+                    // Only writable while rendering, zero is written otherwise
+                    0 | 1 => self.exram[index] = if self.in_frame { val } else { 0 },
+                    2 => self.exram[index] = val,
+                    _ => {}
+                }
+            }
+            0x6000..=0xFFFF => {
+                let bank = self.prg_bank(addr);
+                if bank & 0x80 == 0 && self.ram_writable() {
+                    self.prg.set_ram(Self::prg_ram_addr(bank, addr), val);
+                }
+            }
+            _ => {}
+        }
+    }
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.chr_read(addr, Fetch::Cpu)
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        let (bank, size) = self.chr_bank(addr, Fetch::Cpu);
+        self.chr.storeb(bank, size, addr, val)
+    }
+    /// CIRAM pages of $5105, the ExRAM and fill mode nametables are read elsewhere
+    fn mirroring(&self) -> Mirroring {
+        let page = |table: u8| (self.nametables >> (table * 2)) & 0x1;
+        Mirroring::Custom([page(0), page(1), page(2), page(3)])
+    }
+    fn ppu_loadb(&mut self, addr: u16, ciram: &NameTable) -> u8 {
+        self.watch_fetch(addr);
+        let fetch = self.fetch();
+        let val = match (fetch, self.split_tile()) {
+            (Fetch::Background, Some((column, line))) => self.split_read(addr, column, line),
+            (Fetch::Background, None) if self.exram_mode == 1 => self.ext_attr_read(addr, ciram),
+            _ => match addr {
+                0x0000..=0x1FFF => self.chr_read(addr, fetch),
+                _ => self.nametable_read(addr, ciram),
+            },
+        };
+        self.fetches += 1;
+        val
+    }
+    fn ppu_storeb(&mut self, addr: u16, val: u8, ciram: &mut NameTable) {
+        match addr {
+            0x0000..=0x1FFF => self.chr_storeb(addr, val),
+            _ => match self.nametable(addr) {
+                0 | 1 => ciram.storeb(addr, self.mirroring(), val),
+                2 if self.exram_mode <= 1 => self.exram[addr as usize & 0x3FF] = val,
+                _ => {}
+            },
+        }
+    }
+    fn ppu_reg_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x2000 => self.sprite_8x16 = val & 0x20 != 0,
+            0x2001 if val & 0x18 == 0 => self.in_frame = false,
+            _ => {}
+        }
+    }
+    fn clock(&mut self) {
+        // The PPU reads every other dot while rendering
+        self.idle = self.idle.saturating_add(1);
+        if self.idle >= 3 {
+            self.in_frame = false;
+        }
+        self.audio.clock();
+    }
+    fn irq(&self) -> bool {
+        (self.irq_pending && self.irq_enabled) || self.audio.irq()
+    }
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::numbered;
+    use super::*;
+
+    fn prg_banks(mmc5: &Mmc5) -> [u8; 4] {
+        let mut banks = [0; 4];
+        for (i, bank) in banks.iter_mut().enumerate() {
+            *bank = mmc5.cpu_peekb(0x8000 + i as u16 * 0x2000).unwrap();
+        }
+        banks
+    }
+
+    #[test]
+    fn prg_modes() {
+        let prg = numbered::<0x20000>(0x2000);
+        let mut mmc5 = Mmc5::new(PRG::new(&prg, &mut [], 0), CHR::new(&[0; 0x2000], &mut []));
+        // Mode 3 at power on, the last bank at $E000
+        assert_eq!(mmc5.cpu_peekb(0xE000), Some(0x0F));
+        for (i, &bank) in [0x81, 0x82, 0x83, 0x84].iter().enumerate() {
+            mmc5.cpu_storeb(0x5114 + i as u16, bank);
+        }
+        assert_eq!(prg_banks(&mmc5), [1, 2, 3, 4]);
+        mmc5.cpu_storeb(0x5100, 2);
+        assert_eq!(prg_banks(&mmc5), [2, 3, 3, 4]);
+        mmc5.cpu_storeb(0x5100, 1);
+        assert_eq!(prg_banks(&mmc5), [2, 3, 4, 5]);
+        mmc5.cpu_storeb(0x5100, 0);
+        assert_eq!(prg_banks(&mmc5), [4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn ram_protect_and_banks() {
+        let prg = numbered::<0x20000>(0x2000);
+        let mut ram = [0; 0x10000];
+        let mut mmc5 = Mmc5::new(PRG::new(&prg, &mut ram, 0), CHR::new(&[0; 0x2000], &mut []));
+        mmc5.cpu_storeb(0x6000, 0x12);
+        assert_eq!(mmc5.cpu_peekb(0x6000), Some(0));
+        mmc5.cpu_storeb(0x5102, 2);
+        mmc5.cpu_storeb(0x6000, 0x12);
+        assert_eq!(mmc5.cpu_peekb(0x6000), Some(0));
+        mmc5.cpu_storeb(0x5103, 1);
+        mmc5.cpu_storeb(0x6000, 0x12);
+        assert_eq!(mmc5.cpu_peekb(0x6000), Some(0x12));
+        // Bit 7 clear maps RAM in the ROM area
+        mmc5.cpu_storeb(0x5114, 0x00);
+        assert_eq!(mmc5.cpu_peekb(0x8000), Some(0x12));
+        mmc5.cpu_storeb(0x5114, 0x01);
+        mmc5.cpu_storeb(0x8000, 0x34);
+        mmc5.cpu_storeb(0x5113, 0x01);
+        assert_eq!(mmc5.cpu_peekb(0x6000), Some(0x34));
+        // The ROM is not writable
+        mmc5.cpu_storeb(0xE000, 0x56);
+        assert_eq!(mmc5.cpu_peekb(0xE000), Some(0x0F));
+    }
+
+    #[test]
+    fn multiplier() {
+        let mut mmc5 = Mmc5::new(
+            PRG::new(&[0; 0x8000], &mut [], 0),
+            CHR::new(&[0; 0x2000], &mut []),
+        );
+        assert_eq!(mmc5.cpu_loadb(0x5205), 0x01);
+        assert_eq!(mmc5.cpu_loadb(0x5206), 0xFE);
+        mmc5.cpu_storeb(0x5205, 200);
+        mmc5.cpu_storeb(0x5206, 100);
+        assert_eq!(mmc5.cpu_loadb(0x5205), 0x20);
+        assert_eq!(mmc5.cpu_loadb(0x5206), 0x4E);
+    }
+
+    #[test]
+    fn chr_sets() {
+        let chr = numbered::<0x4000>(0x400);
+        let mut mmc5 = Mmc5::new(PRG::new(&[0; 0x8000], &mut [], 0), CHR::new(&chr, &mut []));
+        mmc5.cpu_storeb(0x5101, 3);
+        for reg in 0..12 {
+            mmc5.cpu_storeb(0x5120 + reg, reg as u8);
+        }
+        // 8x8 sprites, every fetch uses the set written last
+        assert_eq!(mmc5.chr_read(0x0400, Fetch::Sprite), 9);
+        assert_eq!(mmc5.chr_read(0x1400, Fetch::Background), 9);
+        mmc5.cpu_storeb(0x5121, 1);
+        assert_eq!(mmc5.chr_read(0x0400, Fetch::Background), 1);
+        assert_eq!(mmc5.chr_read(0x1400, Fetch::Sprite), 5);
+        // 8x16 sprites, sprites use $5120-$5127 and backgrounds $5128-$512B
+        mmc5.ppu_reg_write(0x2000, 0x20);
+        mmc5.cpu_storeb(0x5129, 9);
+        assert_eq!(mmc5.chr_read(0x1400, Fetch::Sprite), 5);
+        assert_eq!(mmc5.chr_read(0x1400, Fetch::Background), 9);
+        assert_eq!(mmc5.chr_loadb(0x1400), 9);
+        mmc5.cpu_storeb(0x5125, 5);
+        assert_eq!(mmc5.chr_loadb(0x1400), 5);
+    }
+
+    /// Reads the PPU makes at the start of a scanline
+    fn scanline(mmc5: &mut Mmc5, ciram: &NameTable) {
+        mmc5.ppu_loadb(0x23C0, ciram);
+        for _ in 0..3 {
+            mmc5.ppu_loadb(0x2000, ciram);
+        }
+    }
+
+    #[test]
+    fn scanline_irq() {
+        let mut mmc5 = Mmc5::new(
+            PRG::new(&[0; 0x8000], &mut [], 0),
+            CHR::new(&[0; 0x2000], &mut []),
+        );
+        let ciram = NameTable::new();
+        mmc5.cpu_storeb(0x5203, 2);
+        scanline(&mut mmc5, &ciram);
+        assert_eq!(mmc5.cpu_loadb(0x5204), 0x40);
+        scanline(&mut mmc5, &ciram);
+        scanline(&mut mmc5, &ciram);
+        // Pending but not enabled
+        assert!(!mmc5.irq());
+        mmc5.cpu_storeb(0x5204, 0x80);
+        assert!(mmc5.irq());
+        // Acknowledged by reading $5204
+        assert_eq!(mmc5.cpu_loadb(0x5204), 0xC0);
+        assert!(!mmc5.irq());
+        scanline(&mut mmc5, &ciram);
+        assert!(!mmc5.irq());
+        // The frame ends when the PPU stops reading
+        for _ in 0..3 {
+            mmc5.clock();
+        }
+        assert_eq!(mmc5.cpu_loadb(0x5204), 0x00);
+    }
+}
@@ -0,0 +1,447 @@
+mod discrete;
+mod fds;
+mod fme7;
+mod mmc1;
+mod mmc3;
+mod mmc5;
+mod namco163;
+mod nrom;
+mod vrc;
+mod vrc6;
+mod vrc7;
+
+use self::{
+    discrete::{Board, Discrete},
+    fds::Fds,
+    fme7::Fme7,
+    mmc1::Mmc1,
+    mmc3::Mmc3,
+    mmc5::Mmc5,
+    namco163::Namco163,
+    nrom::Nrom,
+    vrc::Vrc,
+    vrc6::Vrc6,
+    vrc7::Vrc7,
+};
+use crate::ppu::{Mirroring, NameTable};
+use crate::rom::{Rom, RomError};
+use crate::state::{crc32, Reader, State, Writer};
+
+/// Cartridge board seen from the CPU ($4020-$FFFF) and the PPU ($0000-$3EFF).
+/// see http://wiki.nesdev.com/w/index.php/Mapper
+pub trait Mapper {
+    /// CPU read of $4020-$FFFF
+    fn cpu_loadb(&mut self, addr: u16) -> u8;
+    /// CPU read without side effects, None if it can't be done
+    fn cpu_peekb(&self, addr: u16) -> Option<u8>;
+    /// CPU write of $4020-$FFFF
+    fn cpu_storeb(&mut self, addr: u16, val: u8);
+    /// PPU read of the pattern tables $0000-$1FFF
+    fn chr_loadb(&mut self, addr: u16) -> u8;
+    /// PPU write of the pattern tables $0000-$1FFF
+    fn chr_storeb(&mut self, addr: u16, val: u8);
+    /// How the nametables are mapped onto the console's VRAM
+    fn mirroring(&self) -> Mirroring;
+    /// PPU read of $0000-$3EFF, boards with their own nametable memory override it
+    fn ppu_loadb(&mut self, addr: u16, ciram: &NameTable) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.chr_loadb(addr),
+            _ => ciram.loadb(addr, self.mirroring()),
+        }
+    }
+    /// PPU write of $0000-$3EFF
+    fn ppu_storeb(&mut self, addr: u16, val: u8, ciram: &mut NameTable) {
+        match addr {
+            0x0000..=0x1FFF => self.chr_storeb(addr, val),
+            _ => ciram.storeb(addr, self.mirroring(), val),
+        }
+    }
+    /// The PPU drives `addr` on its address bus without reading or writing,
+    /// e.g. after the second write of $2006
+    fn ppu_addr(&mut self, _addr: u16) {}
+    /// CPU write of a PPU register $2000-$2007, for boards snooping the data bus
+    fn ppu_reg_write(&mut self, _addr: u16, _val: u8) {}
+    /// Called once every CPU cycle
+    fn clock(&mut self) {}
+    /// Level of the cartridge IRQ output
+    fn irq(&self) -> bool {
+        false
+    }
+    /// Level of the expansion audio, on the scale of the APU mixer output
+    fn audio(&self) -> f32 {
+        0.0
+    }
+}
+
+/// All supported boards. The crate has no allocator, so instead of a boxed
+/// trait object the board is an enum dispatching to the actual mapper.
+pub enum Cartridge<'a> {
+    Nrom(Nrom<'a>),
+    Mmc1(Mmc1<'a>),
+    Mmc3(Mmc3<'a>),
+    Mmc5(Mmc5<'a>),
+    Discrete(Discrete<'a>),
+    Vrc(Vrc<'a>),
+    Vrc6(Vrc6<'a>),
+    Vrc7(Vrc7<'a>),
+    Namco163(Namco163<'a>),
+    Fme7(Fme7<'a>),
+    Fds(Fds<'a>),
+}
+
+macro_rules! dispatch {
+    ($self:ident, $mapper:ident => $e:expr) => {
+        match $self {
+            Cartridge::Nrom($mapper) => $e,
+            Cartridge::Mmc1($mapper) => $e,
+            Cartridge::Mmc3($mapper) => $e,
+            Cartridge::Mmc5($mapper) => $e,
+            Cartridge::Discrete($mapper) => $e,
+            Cartridge::Vrc($mapper) => $e,
+            Cartridge::Vrc6($mapper) => $e,
+            Cartridge::Vrc7($mapper) => $e,
+            Cartridge::Namco163($mapper) => $e,
+            Cartridge::Fme7($mapper) => $e,
+            Cartridge::Fds($mapper) => $e,
+        }
+    };
+}
+
+impl<'a> Cartridge<'a> {
+    /// Build the board selected by the mapper number of the header, its
+    /// PRG-RAM and CHR-RAM are taken from the start of `ram`
+    pub fn new(rom: Rom<'a>, ram: &'a mut [u8]) -> Result<Cartridge<'a>, RomError> {
+        let mirroring = if rom.header.four_screen() {
+            Mirroring::FourScreen
+        } else if rom.header.vertical_mirror() {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let mapper = rom.header.mapper();
+        let (ram_size, battery_size, chr_ram_size) = ram_sizes(&rom);
+        let (prg_ram, chr_ram) = split_ram(ram, ram_size, chr_ram_size)?;
+        let submapper = rom.header.submapper();
+        let prg = PRG::new(rom.prg, prg_ram, battery_size);
+        let chr = CHR::new(rom.chr, chr_ram);
+        Ok(match mapper {
+            0 => Cartridge::Nrom(Nrom::new(prg, chr, mirroring)),
+            1 => Cartridge::Mmc1(Mmc1::new(prg, chr)),
+            // Submapper 4 is the MMC3A/NEC IRQ behavior
+            4 => Cartridge::Mmc3(Mmc3::new(prg, chr, mirroring, submapper == 4)),
+            5 => Cartridge::Mmc5(Mmc5::new(prg, chr)),
+            19 => Cartridge::Namco163(Namco163::new(prg, chr)),
+            21 | 22 | 23 | 25 => Cartridge::Vrc(Vrc::new(prg, chr, mapper, submapper)),
+            24 => Cartridge::Vrc6(Vrc6::new(prg, chr, false)),
+            26 => Cartridge::Vrc6(Vrc6::new(prg, chr, true)),
+            69 => Cartridge::Fme7(Fme7::new(prg, chr)),
+            85 => Cartridge::Vrc7(Vrc7::new(prg, chr, submapper)),
+            id => match Board::from_mapper(id, submapper, rom.chr.len()) {
+                Some(board) => {
+                    Cartridge::Discrete(Discrete::new(board, prg, chr, mirroring, submapper))
+                }
+                None => return Err(RomError::UnsupportedMapper(id)),
+            },
+        })
+    }
+    /// Famicom Disk System with the 8 KB disk BIOS and a .fds image,
+    /// `ram` needs `FDS_RAM_SIZE` bytes
+    pub fn fds(
+        bios: &'a [u8],
+        disk: &'a mut [u8],
+        ram: &'a mut [u8],
+    ) -> Result<Cartridge<'a>, RomError> {
+        Fds::new(bios, disk, ram).map(Cartridge::Fds)
+    }
+    /// CRC-32 of PRG-ROM, and of the disk info block of the first side for
+    /// the disk system, whose PRG-ROM is the BIOS
+    pub fn checksum(&self) -> u32 {
+        let crc = dispatch!(self, m => crc32(0, m.prg.rom));
+        match self {
+            Cartridge::Fds(fds) => fds.checksum(crc),
+            _ => crc,
+        }
+    }
+    /// Battery backed PRG-RAM, None if the board has no battery
+    pub fn save_ram(&self) -> Option<&[u8]> {
+        dispatch!(self, m => m.prg.save_ram())
+    }
+    /// Restore battery backed PRG-RAM saved by a previous run
+    pub fn load_save_ram(&mut self, data: &[u8]) {
+        dispatch!(self, m => m.prg.load_save_ram(data))
+    }
+    /// Whether the game wrote to the save RAM since the last call
+    pub fn take_save_ram_dirty(&mut self) -> bool {
+        dispatch!(self, m => m.prg.take_dirty())
+    }
+    /// The disk drive, if the cartridge is the Famicom Disk System
+    pub fn disk_drive(&self) -> Option<&Fds<'a>> {
+        match self {
+            Cartridge::Fds(fds) => Some(fds),
+            _ => None,
+        }
+    }
+    pub fn disk_drive_mut(&mut self) -> Option<&mut Fds<'a>> {
+        match self {
+            Cartridge::Fds(fds) => Some(fds),
+            _ => None,
+        }
+    }
+}
+
+/// Only the state of the board the ROM selects is saved
+impl<'a> State for Cartridge<'a> {
+    fn save(&self, w: &mut Writer) {
+        dispatch!(self, m => m.save(w))
+    }
+    fn load(&mut self, r: &mut Reader) {
+        dispatch!(self, m => m.load(r))
+    }
+}
+
+impl<'a> Mapper for Cartridge<'a> {
+    fn cpu_loadb(&mut self, addr: u16) -> u8 {
+        dispatch!(self, m => m.cpu_loadb(addr))
+    }
+    fn cpu_peekb(&self, addr: u16) -> Option<u8> {
+        dispatch!(self, m => m.cpu_peekb(addr))
+    }
+    fn cpu_storeb(&mut self, addr: u16, val: u8) {
+        dispatch!(self, m => m.cpu_storeb(addr, val))
+    }
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        dispatch!(self, m => m.chr_loadb(addr))
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        dispatch!(self, m => m.chr_storeb(addr, val))
+    }
+    fn mirroring(&self) -> Mirroring {
+        dispatch!(self, m => m.mirroring())
+    }
+    fn ppu_loadb(&mut self, addr: u16, ciram: &NameTable) -> u8 {
+        dispatch!(self, m => m.ppu_loadb(addr, ciram))
+    }
+    fn ppu_storeb(&mut self, addr: u16, val: u8, ciram: &mut NameTable) {
+        dispatch!(self, m => m.ppu_storeb(addr, val, ciram))
+    }
+    fn ppu_addr(&mut self, addr: u16) {
+        dispatch!(self, m => m.ppu_addr(addr))
+    }
+    fn clock(&mut self) {
+        dispatch!(self, m => m.clock())
+    }
+    fn ppu_reg_write(&mut self, addr: u16, val: u8) {
+        dispatch!(self, m => m.ppu_reg_write(addr, val))
+    }
+    fn irq(&self) -> bool {
+        dispatch!(self, m => m.irq())
+    }
+    fn audio(&self) -> f32 {
+        dispatch!(self, m => m.audio())
+    }
+}
+
+/// Largest PRG-RAM and CHR-RAM of supported boards, bigger sizes in NES 2.0
+/// headers are cut down
+const PRG_RAM_MAX: usize = 0x8000;
+const CHR_RAM_MAX: usize = 0x8000;
+
+/// The disk system has 32 KB of PRG-RAM for the programs loaded from disk
+/// and 8 KB of CHR-RAM
+pub const FDS_RAM_SIZE: usize = 0x8000 + 0x2000;
+
+/// Sizes of PRG-RAM, of the battery backed part of it and of CHR-RAM
+pub fn ram_sizes(rom: &Rom) -> (usize, usize, usize) {
+    // SXROM and the ExROM boards of the MMC5 are the only common boards
+    // with more than 8 KB of PRG-RAM, iNES headers don't tell the size
+    let ram_size = match rom.header.mapper() {
+        _ if rom.header.nes2() => rom.header.prg_ram_bytes() + rom.header.prg_nvram_bytes(),
+        1 if rom.prg.len() >= 0x80000 => 0x8000,
+        5 => 0x8000,
+        _ => 0x2000,
+    };
+    let ram_size = ram_size.min(PRG_RAM_MAX);
+    // NES 2.0 tells how much of it the battery keeps, iNES all or nothing
+    let battery_size = if rom.header.nes2() {
+        rom.header.prg_nvram_bytes().min(ram_size)
+    } else if rom.header.save_ram() {
+        ram_size
+    } else {
+        0
+    };
+    // Boards without CHR-ROM have 8 KB of CHR-RAM unless NES 2.0 says otherwise,
+    // none of the supported boards has both
+    let chr_ram_size = match rom.header.chr_ram_bytes() + rom.header.chr_nvram_bytes() {
+        _ if !rom.chr.is_empty() => 0,
+        0 => 0x2000,
+        size => size.min(CHR_RAM_MAX),
+    };
+    (ram_size, battery_size, chr_ram_size)
+}
+
+/// Cut PRG-RAM and CHR-RAM out of the RAM given by the frontend
+fn split_ram(
+    ram: &mut [u8],
+    prg_size: usize,
+    chr_size: usize,
+) -> Result<(&mut [u8], &mut [u8]), RomError> {
+    if ram.len() < prg_size + chr_size {
+        return Err(RomError::RamTooSmall {
+            expected: prg_size + chr_size,
+            found: ram.len(),
+        });
+    }
+    let (prg, rest) = ram.split_at_mut(prg_size);
+    Ok((prg, &mut rest[..chr_size]))
+}
+
+/// Value left on the data bus by reads nothing responds to,
+/// usually the high byte of the address
+pub fn open_bus(addr: u16) -> u8 {
+    (addr >> 8) as u8
+}
+
+/// PRG-ROM and PRG-RAM of a cartridge
+pub struct PRG<'a> {
+    rom: &'a [u8],
+    /// Usually mapped at $6000-$7FFF
+    ram: &'a mut [u8],
+    /// The first `battery_size` bytes of RAM are kept by a battery
+    battery_size: usize,
+    /// Battery backed RAM changed since it was last saved
+    dirty: bool,
+}
+
+impl<'a> State for PRG<'a> {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(self.ram);
+    }
+    fn load(&mut self, r: &mut Reader) {
+        r.bytes(self.ram);
+        // The save RAM no longer matches the .sav file
+        self.dirty = self.battery_size > 0;
+    }
+}
+
+impl<'a> PRG<'a> {
+    /// `ram` is cleared, it's all the PRG-RAM of the board
+    pub fn new(rom: &'a [u8], ram: &'a mut [u8], battery_size: usize) -> PRG<'a> {
+        ram.iter_mut().for_each(|b| *b = 0);
+        PRG {
+            rom,
+            battery_size: battery_size.min(ram.len()),
+            ram,
+            dirty: false,
+        }
+    }
+    /// Number of `size` bytes banks in PRG-ROM
+    pub fn banks(&self, size: usize) -> usize {
+        (self.rom.len() / size).max(1)
+    }
+    /// Read `addr` through a `size` bytes window mapped to ROM bank `bank`,
+    /// out of range banks wrap around
+    pub fn rom(&self, bank: usize, size: usize, addr: u16) -> u8 {
+        self.rom[(bank * size + (addr as usize & (size - 1))) % self.rom.len()]
+    }
+    pub fn ram_size(&self) -> usize {
+        self.ram.len()
+    }
+    /// Read PRG-RAM, `addr` wraps around its size
+    pub fn ram(&self, addr: usize) -> u8 {
+        if self.ram.is_empty() {
+            return 0;
+        }
+        self.ram[addr % self.ram.len()]
+    }
+    pub fn set_ram(&mut self, addr: usize, val: u8) {
+        if !self.ram.is_empty() {
+            let addr = addr % self.ram.len();
+            if addr < self.battery_size && self.ram[addr] != val {
+                self.dirty = true;
+            }
+            self.ram[addr] = val;
+        }
+    }
+    pub fn save_ram(&self) -> Option<&[u8]> {
+        if self.battery_size > 0 {
+            Some(&self.ram[..self.battery_size])
+        } else {
+            None
+        }
+    }
+    /// Copy `data` to the battery backed RAM, extra bytes are ignored
+    pub fn load_save_ram(&mut self, data: &[u8]) {
+        let size = data.len().min(self.battery_size);
+        self.ram[..size].copy_from_slice(&data[..size]);
+        self.dirty = false;
+    }
+    pub fn take_dirty(&mut self) -> bool {
+        let dirty = self.dirty;
+        self.dirty = false;
+        dirty
+    }
+}
+
+/// CHR memory of a cartridge, either CHR-ROM or CHR-RAM
+pub struct CHR<'a> {
+    rom: &'a [u8],
+    /// Empty on boards with CHR-ROM
+    ram: &'a mut [u8],
+}
+
+impl<'a> State for CHR<'a> {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(self.ram);
+    }
+    fn load(&mut self, r: &mut Reader) {
+        r.bytes(self.ram);
+    }
+}
+
+impl<'a> CHR<'a> {
+    /// CHR-RAM is used instead of `rom` unless `ram` is empty, it's cleared
+    pub fn new(rom: &'a [u8], ram: &'a mut [u8]) -> CHR<'a> {
+        ram.iter_mut().for_each(|b| *b = 0);
+        CHR { rom, ram }
+    }
+    fn len(&self) -> usize {
+        if !self.ram.is_empty() {
+            self.ram.len()
+        } else {
+            self.rom.len()
+        }
+    }
+    /// Read `addr` through a `size` bytes window mapped to bank `bank`,
+    /// out of range banks wrap around
+    pub fn loadb(&self, bank: usize, size: usize, addr: u16) -> u8 {
+        if self.len() == 0 {
+            return 0;
+        }
+        let offset = (bank * size + (addr as usize & (size - 1))) % self.len();
+        if !self.ram.is_empty() {
+            self.ram[offset]
+        } else {
+            self.rom[offset]
+        }
+    }
+    /// Write CHR-RAM, writes to CHR-ROM are ignored
+    pub fn storeb(&mut self, bank: usize, size: usize, addr: u16, val: u8) {
+        let offset = bank * size + (addr as usize & (size - 1));
+        if !self.ram.is_empty() {
+            let len = self.ram.len();
+            self.ram[offset % len] = val;
+        } else {
+            warn!("Write to CHR-ROM {:05X} = {:02X}", offset, val);
+        }
+    }
+}
+
+/// ROM whose bytes hold the number of the `size` bytes bank they are in
+#[cfg(test)]
+fn numbered<const N: usize>(size: usize) -> [u8; N] {
+    let mut rom = [0; N];
+    for (i, byte) in rom.iter_mut().enumerate() {
+        *byte = (i / size) as u8;
+    }
+    rom
+}
@@ -0,0 +1,242 @@
+use super::{open_bus, Mapper, CHR, PRG};
+use crate::apu::N163Audio;
+use crate::ppu::{Mirroring, NameTable};
+
+/// Mapper 19, Namco 163. Pattern tables and nametables can each map CHR-ROM
+/// or the console's VRAM, the chip also counts CPU cycles and has wavetable audio.
+/// see http://wiki.nesdev.com/w/index.php/INES_Mapper_019
+pub struct Namco163<'a> {
+    pub(super) prg: PRG<'a>,
+    chr: CHR<'a>,
+    /// $8000-$B800 1 KB pattern table banks, $C000-$D800 nametables
+    chr_banks: [u8; 12],
+    /// $E000-$F000 8 KB banks at $8000, $A000 and $C000
+    prg_banks: [u8; 3],
+    /// $E000 bit 6
+    sound_disabled: bool,
+    /// $E800 HL-- ----
+    /// H: no VRAM at $1000-$1FFF, L: no VRAM at $0000-$0FFF
+    vram_disabled: u8,
+    /// $F800 KKKK PPPP
+    /// K: 0100 allows PRG-RAM writes, P: protect a 2 KB window of PRG-RAM
+    write_protect: u8,
+    /// $5000-$5800 15 bit up counter
+    irq_counter: u16,
+    irq_enabled: bool,
+    audio: N163Audio,
+}
+
+state!(Namco163<'a> {
+    prg,
+    chr,
+    chr_banks,
+    prg_banks,
+    sound_disabled,
+    vram_disabled,
+    write_protect,
+    irq_counter,
+    irq_enabled,
+    audio
+});
+
+impl<'a> Namco163<'a> {
+    pub fn new(prg: PRG<'a>, chr: CHR<'a>) -> Namco163<'a> {
+        Namco163 {
+            prg,
+            chr,
+            chr_banks: [0; 12],
+            prg_banks: [0; 3],
+            sound_disabled: false,
+            vram_disabled: 0,
+            write_protect: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            audio: N163Audio::new(),
+        }
+    }
+    fn prg_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x8000..=0xDFFF => self.prg_banks[(addr as usize - 0x8000) >> 13] as usize,
+            _ => self.prg.banks(0x2000) - 1,
+        }
+    }
+    /// VRAM page selected by bank register `reg`, None for CHR-ROM.
+    /// Values $E0 and up select VRAM, unless it is disabled for the pattern table.
+    fn vram_page(&self, reg: usize) -> Option<Mirroring> {
+        let bank = self.chr_banks[reg];
+        let disabled = match reg {
+            0..=3 => self.vram_disabled & 0x40 != 0,
+            4..=7 => self.vram_disabled & 0x80 != 0,
+            _ => false,
+        };
+        match bank {
+            0xE0..=0xFF if !disabled => Some(if bank & 1 == 0 {
+                Mirroring::OneScreenLower
+            } else {
+                Mirroring::OneScreenUpper
+            }),
+            _ => None,
+        }
+    }
+    /// Bank register of PPU address `addr`, nametables above $3000 mirror $2000
+    fn chr_register(addr: u16) -> usize {
+        match addr {
+            0x0000..=0x1FFF => addr as usize >> 10,
+            _ => 8 + ((addr as usize >> 10) & 0x3),
+        }
+    }
+    fn ram_writable(&self, addr: u16) -> bool {
+        let window = (addr >> 11) & 0x3;
+        self.write_protect & 0xF0 == 0x40 && self.write_protect & (1 << window) == 0
+    }
+}
+
+impl<'a> Mapper for Namco163<'a> {
+    fn cpu_loadb(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4800..=0x4FFF => self.audio.read_data(),
+            _ => self.cpu_peekb(addr).unwrap_or_else(|| open_bus(addr)),
+        }
+    }
+    fn cpu_peekb(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5000..=0x57FF => Some(self.irq_counter as u8),
+            0x5800..=0x5FFF => Some((self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7),
+            0x6000..=0x7FFF => Some(self.prg.ram(addr as usize & 0x1FFF)),
+            0x8000..=0xFFFF => Some(self.prg.rom(self.prg_bank(addr), 0x2000, addr)),
+            _ => None,
+        }
+    }
+    fn cpu_storeb(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4800..=0x4FFF => self.audio.write_data(val),
+            0x5000..=0x57FF => self.irq_counter = (self.irq_counter & 0x7F00) | val as u16,
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0xFF) | (val as u16 & 0x7F) << 8;
+                self.irq_enabled = val & 0x80 != 0;
+            }
+            0x6000..=0x7FFF => {
+                if self.ram_writable(addr) {
+                    self.prg.set_ram(addr as usize & 0x1FFF, val);
+                }
+            }
+            0x8000..=0xDFFF => self.chr_banks[(addr as usize - 0x8000) >> 11] = val,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = val & 0x3F;
+                self.sound_disabled = val & 0x40 != 0;
+            }
+            0xE800..=0xEFFF => {
+                self.prg_banks[1] = val & 0x3F;
+                self.vram_disabled = val & 0xC0;
+            }
+            0xF000..=0xF7FF => self.prg_banks[2] = val & 0x3F,
+            0xF800..=0xFFFF => {
+                self.write_protect = val;
+                self.audio.set_address(val);
+            }
+            _ => {}
+        }
+    }
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        let bank = self.chr_banks[Self::chr_register(addr)] as usize;
+        self.chr.loadb(bank, 0x400, addr)
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        let bank = self.chr_banks[Self::chr_register(addr)] as usize;
+        self.chr.storeb(bank, 0x400, addr, val)
+    }
+    /// CIRAM pages of the nametable registers,
+    /// `ppu_loadb` reads CHR-ROM instead for values below $E0
+    fn mirroring(&self) -> Mirroring {
+        let page = |reg: usize| self.chr_banks[reg] & 1;
+        Mirroring::Custom([page(8), page(9), page(10), page(11)])
+    }
+    fn ppu_loadb(&mut self, addr: u16, ciram: &NameTable) -> u8 {
+        match self.vram_page(Self::chr_register(addr)) {
+            Some(page) => ciram.loadb(addr, page),
+            None => self.chr_loadb(addr),
+        }
+    }
+    fn ppu_storeb(&mut self, addr: u16, val: u8, ciram: &mut NameTable) {
+        match self.vram_page(Self::chr_register(addr)) {
+            Some(page) => ciram.storeb(addr, page, val),
+            None => self.chr_storeb(addr, val),
+        }
+    }
+    fn clock(&mut self) {
+        if self.irq_enabled && self.irq_counter < 0x7FFF {
+            self.irq_counter += 1;
+        }
+        self.audio.clock();
+    }
+    fn irq(&self) -> bool {
+        self.irq_enabled && self.irq_counter == 0x7FFF
+    }
+    fn audio(&self) -> f32 {
+        if self.sound_disabled {
+            0.0
+        } else {
+            self.audio.output()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_write_protect() {
+        let mut ram = [0; 0x2000];
+        let mut n163 = Namco163::new(
+            PRG::new(&[0; 0x8000], &mut ram, 0),
+            CHR::new(&[0; 0x2000], &mut []),
+        );
+        let windows = [0x6000, 0x6800, 0x7000, 0x7800];
+        let write = |n163: &mut Namco163, val: u8| {
+            for &addr in windows.iter() {
+                n163.cpu_storeb(addr, val);
+            }
+        };
+        let read = |n163: &Namco163| {
+            let mut vals = [0; 4];
+            for (val, &addr) in vals.iter_mut().zip(windows.iter()) {
+                *val = n163.cpu_peekb(addr).unwrap();
+            }
+            vals
+        };
+        // Writes need 0100 in the upper bits of $F800
+        write(&mut n163, 1);
+        assert_eq!(read(&n163), [0; 4]);
+        n163.cpu_storeb(0xF800, 0x40);
+        write(&mut n163, 2);
+        assert_eq!(read(&n163), [2; 4]);
+        n163.cpu_storeb(0xF800, 0x4A);
+        write(&mut n163, 3);
+        assert_eq!(read(&n163), [3, 2, 3, 2]);
+        n163.cpu_storeb(0xF800, 0x50);
+        write(&mut n163, 4);
+        assert_eq!(read(&n163), [3, 2, 3, 2]);
+    }
+
+    #[test]
+    fn irq_counter() {
+        let mut n163 = Namco163::new(
+            PRG::new(&[0; 0x8000], &mut [], 0),
+            CHR::new(&[0; 0x2000], &mut []),
+        );
+        n163.cpu_storeb(0x5000, 0xFD);
+        n163.cpu_storeb(0x5800, 0xFF);
+        n163.clock();
+        assert!(!n163.irq());
+        n163.clock();
+        assert!(n163.irq());
+        // The counter stops at $7FFF
+        n163.clock();
+        assert_eq!(n163.cpu_peekb(0x5000), Some(0xFF));
+        assert_eq!(n163.cpu_peekb(0x5800), Some(0xFF));
+        // Acknowledged by writing the counter
+        n163.cpu_storeb(0x5800, 0x7F);
+        assert!(!n163.irq());
+    }
+}
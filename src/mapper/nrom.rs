@@ -0,0 +1,50 @@
+use super::{open_bus, Mapper, CHR, PRG};
+use crate::ppu::Mirroring;
+
+/// Mapper 0, no bank switching.
+/// 16 KB PRG-ROM is mirrored at $C000, 32 KB fills $8000-$FFFF.
+/// see http://wiki.nesdev.com/w/index.php/NROM
+pub struct Nrom<'a> {
+    pub(super) prg: PRG<'a>,
+    chr: CHR<'a>,
+    mirroring: Mirroring,
+}
+
+state!(Nrom<'a> { prg, chr, mirroring });
+
+impl<'a> Nrom<'a> {
+    pub fn new(prg: PRG<'a>, chr: CHR<'a>, mirroring: Mirroring) -> Nrom<'a> {
+        Nrom {
+            prg,
+            chr,
+            mirroring,
+        }
+    }
+}
+
+impl<'a> Mapper for Nrom<'a> {
+    fn cpu_loadb(&mut self, addr: u16) -> u8 {
+        self.cpu_peekb(addr).unwrap_or_else(|| open_bus(addr))
+    }
+    fn cpu_peekb(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => Some(self.prg.ram(addr as usize & 0x1FFF)),
+            0x8000..=0xFFFF => Some(self.prg.rom(0, 0x8000, addr)),
+            _ => None,
+        }
+    }
+    fn cpu_storeb(&mut self, addr: u16, val: u8) {
+        if let 0x6000..=0x7FFF = addr {
+            self.prg.set_ram(addr as usize & 0x1FFF, val);
+        }
+    }
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.chr.loadb(0, 0x2000, addr)
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.chr.storeb(0, 0x2000, addr, val)
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
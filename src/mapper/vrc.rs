@@ -0,0 +1,400 @@
+use super::{open_bus, Mapper, CHR, PRG};
+use crate::ppu::Mirroring;
+
+/// IRQ counter shared by the VRC4, VRC6 and VRC7.
+/// It counts CPU cycles, or scanlines through a prescaler dividing by 113.667.
+/// see http://wiki.nesdev.com/w/index.php/VRC_IRQ
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    /// PPU dots left until the next scanline clock
+    prescaler: i16,
+    /// A bit of the control register, the enable bit after an acknowledge
+    enable_after_ack: bool,
+    enabled: bool,
+    /// Clock the counter every CPU cycle instead of every scanline
+    cycle_mode: bool,
+    irq: bool,
+}
+
+state!(VrcIrq {
+    latch,
+    counter,
+    prescaler,
+    enable_after_ack,
+    enabled,
+    cycle_mode,
+    irq
+});
+
+impl VrcIrq {
+    pub fn new() -> VrcIrq {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: 341,
+            enable_after_ack: false,
+            enabled: false,
+            cycle_mode: false,
+            irq: false,
+        }
+    }
+    pub fn set_latch(&mut self, val: u8) {
+        self.latch = val;
+    }
+    /// VRC4 writes the latch 4 bits at a time
+    pub fn set_latch_low(&mut self, val: u8) {
+        self.latch = (self.latch & 0xF0) | (val & 0x0F);
+    }
+    pub fn set_latch_high(&mut self, val: u8) {
+        self.latch = (self.latch & 0x0F) | (val << 4);
+    }
+    /// Control ---- -MEA
+    /// M: cycle mode, E: enable, A: enable after acknowledge
+    pub fn set_control(&mut self, val: u8) {
+        self.enable_after_ack = val & 0x01 != 0;
+        self.enabled = val & 0x02 != 0;
+        self.cycle_mode = val & 0x04 != 0;
+        self.irq = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = 341;
+        }
+    }
+    pub fn acknowledge(&mut self) {
+        self.irq = false;
+        self.enabled = self.enable_after_ack;
+    }
+    /// Called once every CPU cycle
+    pub fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock_counter();
+        } else {
+            self.prescaler -= 3;
+            if self.prescaler <= 0 {
+                self.prescaler += 341;
+                self.clock_counter();
+            }
+        }
+    }
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.irq = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+    pub fn irq(&self) -> bool {
+        self.irq
+    }
+}
+
+/// Mirroring values of the VRC4, VRC6 and VRC7 mirroring registers
+pub fn vrc_mirroring(val: u8) -> Mirroring {
+    match val & 0x3 {
+        0 => Mirroring::Vertical,
+        1 => Mirroring::Horizontal,
+        2 => Mirroring::OneScreenLower,
+        _ => Mirroring::OneScreenUpper,
+    }
+}
+
+/// Mappers 21, 22, 23 and 25, Konami VRC2 and VRC4.
+/// The boards differ in the CPU address lines wired to the register select
+/// pins of the chip, NES 2.0 submappers tell them apart. Without one the
+/// lines of all variants of the mapper are combined.
+/// see http://wiki.nesdev.com/w/index.php/VRC2_and_VRC4
+pub struct Vrc<'a> {
+    pub(super) prg: PRG<'a>,
+    chr: CHR<'a>,
+    /// VRC2 has no IRQ, no PRG swap mode and only H/V mirroring
+    vrc4: bool,
+    /// Address lines of register select bit 0 and bit 1
+    lines: [u16; 2],
+    /// VRC2a ignores the low bit of the CHR banks
+    chr_shift: u8,
+    prg_banks: [u8; 2],
+    /// $9002 bit 1, $8000 is fixed to the second last bank and $C000 is switchable
+    prg_swap: bool,
+    chr_banks: [u16; 8],
+    mirroring: Mirroring,
+    irq: VrcIrq,
+}
+
+state!(Vrc<'a> { prg, chr, prg_banks, prg_swap, chr_banks, mirroring, irq });
+
+impl<'a> Vrc<'a> {
+    pub fn new(prg: PRG<'a>, chr: CHR<'a>, mapper: u16, submapper: u8) -> Vrc<'a> {
+        // (VRC4, A0 lines, A1 lines, CHR shift)
+        let (vrc4, lines, chr_shift) = match (mapper, submapper) {
+            // VRC4a
+            (21, 1) => (true, [0x02, 0x04], 0),
+            // VRC4c
+            (21, 2) => (true, [0x40, 0x80], 0),
+            (21, _) => (true, [0x42, 0x84], 0),
+            // VRC2a
+            (22, _) => (false, [0x02, 0x01], 1),
+            // VRC4f
+            (23, 1) => (true, [0x01, 0x02], 0),
+            // VRC4e
+            (23, 2) => (true, [0x04, 0x08], 0),
+            // VRC2b
+            (23, 3) => (false, [0x01, 0x02], 0),
+            (23, _) => (true, [0x05, 0x0A], 0),
+            // VRC4b
+            (25, 1) => (true, [0x02, 0x01], 0),
+            // VRC4d
+            (25, 2) => (true, [0x08, 0x04], 0),
+            // VRC2c
+            (25, 3) => (false, [0x02, 0x01], 0),
+            (_, _) => (true, [0x0A, 0x05], 0),
+        };
+        Vrc {
+            prg,
+            chr,
+            vrc4,
+            lines,
+            chr_shift,
+            prg_banks: [0, 0],
+            prg_swap: false,
+            chr_banks: [0; 8],
+            mirroring: Mirroring::Vertical,
+            irq: VrcIrq::new(),
+        }
+    }
+    /// Translate `addr` to the register it selects, $x000-$x003
+    fn register(&self, addr: u16) -> u16 {
+        let a0 = (addr & self.lines[0] != 0) as u16;
+        let a1 = (addr & self.lines[1] != 0) as u16;
+        (addr & 0xF000) | a1 << 1 | a0
+    }
+    fn prg_bank(&self, addr: u16) -> usize {
+        let second_last = self.prg.banks(0x2000).saturating_sub(2);
+        match (addr >> 13) & 0x3 {
+            0 if self.prg_swap => second_last,
+            0 => self.prg_banks[0] as usize,
+            1 => self.prg_banks[1] as usize,
+            2 if self.prg_swap => self.prg_banks[0] as usize,
+            2 => second_last,
+            _ => second_last + 1,
+        }
+    }
+    fn chr_bank(&self, addr: u16) -> usize {
+        (self.chr_banks[(addr >> 10) as usize & 0x7] >> self.chr_shift) as usize
+    }
+}
+
+impl<'a> Mapper for Vrc<'a> {
+    fn cpu_loadb(&mut self, addr: u16) -> u8 {
+        self.cpu_peekb(addr).unwrap_or_else(|| open_bus(addr))
+    }
+    fn cpu_peekb(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF => Some(self.prg.ram(addr as usize & 0x1FFF)),
+            0x8000..=0xFFFF => Some(self.prg.rom(self.prg_bank(addr), 0x2000, addr)),
+            _ => None,
+        }
+    }
+    fn cpu_storeb(&mut self, addr: u16, val: u8) {
+        if addr < 0x8000 {
+            if addr >= 0x6000 {
+                self.prg.set_ram(addr as usize & 0x1FFF, val);
+            }
+            return;
+        }
+        match self.register(addr) {
+            0x8000..=0x8003 => self.prg_banks[0] = val & 0x1F,
+            0x9000 if self.vrc4 => self.mirroring = vrc_mirroring(val),
+            0x9000..=0x9003 if !self.vrc4 => {
+                self.mirroring = if val & 1 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                };
+            }
+            0x9002 => self.prg_swap = val & 0x02 != 0,
+            0xA000..=0xA003 => self.prg_banks[1] = val & 0x1F,
+            reg @ 0xB000..=0xEFFF => {
+                // Two registers per bank, low 4 bits then high 5 bits
+                let bank = ((reg - 0xB000) >> 12) as usize * 2 + (reg as usize & 0x2) / 2;
+                let old = self.chr_banks[bank];
+                self.chr_banks[bank] = if reg & 1 == 0 {
+                    (old & 0x1F0) | (val as u16 & 0x0F)
+                } else {
+                    (old & 0x00F) | (val as u16 & 0x1F) << 4
+                };
+            }
+            0xF000 if self.vrc4 => self.irq.set_latch_low(val),
+            0xF001 if self.vrc4 => self.irq.set_latch_high(val),
+            0xF002 if self.vrc4 => self.irq.set_control(val),
+            0xF003 if self.vrc4 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.chr.loadb(self.chr_bank(addr), 0x400, addr)
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.chr.storeb(self.chr_bank(addr), 0x400, addr, val)
+    }
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+    fn clock(&mut self) {
+        self.irq.clock();
+    }
+    fn irq(&self) -> bool {
+        self.irq.irq()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::numbered;
+    use super::*;
+
+    #[test]
+    fn address_lines() {
+        let chr = numbered::<0x8000>(0x400);
+        // (mapper, submapper, A0 line, A1 line, CHR banks 0 and 1)
+        let boards = [
+            (21, 1, 0x02, 0x04, [16, 5]),
+            (21, 2, 0x40, 0x80, [16, 5]),
+            (21, 0, 0x02, 0x04, [16, 5]),
+            (21, 0, 0x40, 0x80, [16, 5]),
+            (22, 0, 0x02, 0x01, [8, 2]),
+            (23, 1, 0x01, 0x02, [16, 5]),
+            (23, 2, 0x04, 0x08, [16, 5]),
+            (23, 3, 0x01, 0x02, [16, 5]),
+            (23, 0, 0x01, 0x02, [16, 5]),
+            (23, 0, 0x04, 0x08, [16, 5]),
+            (25, 1, 0x02, 0x01, [16, 5]),
+            (25, 2, 0x08, 0x04, [16, 5]),
+            (25, 3, 0x02, 0x01, [16, 5]),
+            (25, 0, 0x02, 0x01, [16, 5]),
+            (25, 0, 0x08, 0x04, [16, 5]),
+        ];
+        for &(mapper, submapper, a0, a1, banks) in boards.iter() {
+            let mut vrc = Vrc::new(
+                PRG::new(&[0; 0x8000], &mut [], 0),
+                CHR::new(&chr, &mut []),
+                mapper,
+                submapper,
+            );
+            // $B001 high bits of bank 0, $B002 low bits of bank 1
+            vrc.cpu_storeb(0xB000 | a0, 0x01);
+            vrc.cpu_storeb(0xB000 | a1, 0x05);
+            assert_eq!(
+                [vrc.chr_loadb(0x0000), vrc.chr_loadb(0x0400)],
+                banks,
+                "mapper {} submapper {}",
+                mapper,
+                submapper
+            );
+        }
+    }
+
+    #[test]
+    fn other_boards_lines_ignored() {
+        let chr = numbered::<0x8000>(0x400);
+        let mut vrc = Vrc::new(
+            PRG::new(&[0; 0x8000], &mut [], 0),
+            CHR::new(&chr, &mut []),
+            21,
+            1,
+        );
+        // A6 is register select on the VRC4c only, this is $B000
+        vrc.cpu_storeb(0xB040, 0x05);
+        assert_eq!([vrc.chr_loadb(0x0000), vrc.chr_loadb(0x0400)], [5, 0]);
+    }
+
+    fn prg_banks(vrc: &Vrc) -> [u8; 4] {
+        let mut banks = [0; 4];
+        for (i, bank) in banks.iter_mut().enumerate() {
+            *bank = vrc.cpu_peekb(0x8000 + i as u16 * 0x2000).unwrap();
+        }
+        banks
+    }
+
+    #[test]
+    fn prg_swap_mode() {
+        let prg = numbered::<0x20000>(0x2000);
+        // VRC4a
+        let mut vrc = Vrc::new(
+            PRG::new(&prg, &mut [], 0),
+            CHR::new(&[0; 0x2000], &mut []),
+            21,
+            1,
+        );
+        vrc.cpu_storeb(0x8000, 3);
+        vrc.cpu_storeb(0xA000, 4);
+        assert_eq!(prg_banks(&vrc), [3, 4, 14, 15]);
+        vrc.cpu_storeb(0x9004, 0x02);
+        assert_eq!(prg_banks(&vrc), [14, 4, 3, 15]);
+        // VRC2b has no swap mode, $9002 is a mirroring register
+        let mut vrc = Vrc::new(
+            PRG::new(&prg, &mut [], 0),
+            CHR::new(&[0; 0x2000], &mut []),
+            23,
+            3,
+        );
+        vrc.cpu_storeb(0x8000, 3);
+        vrc.cpu_storeb(0x9002, 0x03);
+        assert_eq!(prg_banks(&vrc), [3, 0, 14, 15]);
+        assert!(vrc.mirroring() == Mirroring::Horizontal);
+    }
+
+    /// VRC4a with the IRQ latch set and enabled with `control`
+    fn irq_setup(latch: u8, control: u8) -> Vrc<'static> {
+        let mut vrc = Vrc::new(
+            PRG::new(&[0; 0x8000], &mut [], 0),
+            CHR::new(&[0; 0x2000], &mut []),
+            21,
+            1,
+        );
+        vrc.cpu_storeb(0xF000, latch & 0x0F);
+        vrc.cpu_storeb(0xF002, latch >> 4);
+        vrc.cpu_storeb(0xF004, control);
+        vrc
+    }
+
+    #[test]
+    fn irq_cycle_mode() {
+        let mut vrc = irq_setup(0xFE, 0x07);
+        vrc.clock();
+        assert!(!vrc.irq());
+        vrc.clock();
+        assert!(vrc.irq());
+        // Acknowledged, stays enabled and counts from the latch again
+        vrc.cpu_storeb(0xF006, 0);
+        assert!(!vrc.irq());
+        vrc.clock();
+        vrc.clock();
+        assert!(vrc.irq());
+        // Disabled by an acknowledge with A clear
+        let mut vrc = irq_setup(0xFF, 0x06);
+        vrc.clock();
+        assert!(vrc.irq());
+        vrc.cpu_storeb(0xF006, 0);
+        for _ in 0..0x100 {
+            vrc.clock();
+        }
+        assert!(!vrc.irq());
+    }
+
+    #[test]
+    fn irq_scanline_mode() {
+        // The prescaler clocks the counter every 341 dots, 113.667 CPU cycles
+        let mut vrc = irq_setup(0xFF, 0x02);
+        for _ in 0..113 {
+            vrc.clock();
+        }
+        assert!(!vrc.irq());
+        vrc.clock();
+        assert!(vrc.irq());
+    }
+}
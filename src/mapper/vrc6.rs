@@ -0,0 +1,134 @@
+use super::vrc::{vrc_mirroring, VrcIrq};
+use super::{open_bus, Mapper, CHR, PRG};
+use crate::apu::Vrc6Audio;
+use crate::ppu::Mirroring;
+
+/// Mappers 24 and 26, Konami VRC6. Mapper 26 swaps the A0 and A1 lines.
+/// see http://wiki.nesdev.com/w/index.php/VRC6
+pub struct Vrc6<'a> {
+    pub(super) prg: PRG<'a>,
+    chr: CHR<'a>,
+    swap_lines: bool,
+    /// $8000 16 KB bank at $8000
+    prg_16k: u8,
+    /// $C000 8 KB bank at $C000
+    prg_8k: u8,
+    /// $D000-$E003 1 KB CHR registers
+    chr_banks: [u8; 8],
+    /// $B003 W-AN MMPP
+    /// W: PRG-RAM enable, A: CHR A10 rule, N: nametables from CHR-ROM,
+    /// M: mirroring, P: PPU banking mode
+    control: u8,
+    irq: VrcIrq,
+    audio: Vrc6Audio,
+}
+
+state!(Vrc6<'a> { prg, chr, prg_16k, prg_8k, chr_banks, control, irq, audio });
+
+impl<'a> Vrc6<'a> {
+    pub fn new(prg: PRG<'a>, chr: CHR<'a>, swap_lines: bool) -> Vrc6<'a> {
+        Vrc6 {
+            prg,
+            chr,
+            swap_lines,
+            prg_16k: 0,
+            prg_8k: 0,
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+            audio: Vrc6Audio::new(),
+        }
+    }
+    /// Translate `addr` to the register it selects, $x000-$x003
+    fn register(&self, addr: u16) -> u16 {
+        if self.swap_lines {
+            (addr & 0xF000) | (addr & 0x1) << 1 | (addr & 0x2) >> 1
+        } else {
+            addr & 0xF003
+        }
+    }
+    fn prg_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x8000..=0xBFFF => self.prg_16k as usize * 2 + ((addr >> 13) & 1) as usize,
+            0xC000..=0xDFFF => self.prg_8k as usize,
+            _ => self.prg.banks(0x2000) - 1,
+        }
+    }
+    /// 1 KB CHR bank of `addr`. Modes 1-3 use 2 KB banks in some windows,
+    /// the A10 rule picks whether their low bit comes from PPU A10.
+    fn chr_bank(&self, addr: u16) -> usize {
+        let window = (addr >> 10) as usize & 0x7;
+        let a10 = (addr >> 10) as u8 & 1;
+        let half = |reg: usize| {
+            let bank = self.chr_banks[reg];
+            if self.control & 0x20 != 0 {
+                (bank & 0xFE | a10) as usize
+            } else {
+                bank as usize
+            }
+        };
+        match (self.control & 0x3, window) {
+            (0, n) => self.chr_banks[n] as usize,
+            (1, n) => half(n / 2),
+            (_, n @ 0..=3) => self.chr_banks[n] as usize,
+            (_, n) => half(4 + (n - 4) / 2),
+        }
+    }
+    fn ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+}
+
+impl<'a> Mapper for Vrc6<'a> {
+    fn cpu_loadb(&mut self, addr: u16) -> u8 {
+        self.cpu_peekb(addr).unwrap_or_else(|| open_bus(addr))
+    }
+    fn cpu_peekb(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.ram_enabled() => Some(self.prg.ram(addr as usize & 0x1FFF)),
+            0x8000..=0xFFFF => Some(self.prg.rom(self.prg_bank(addr), 0x2000, addr)),
+            _ => None,
+        }
+    }
+    fn cpu_storeb(&mut self, addr: u16, val: u8) {
+        if addr < 0x8000 {
+            if addr >= 0x6000 && self.ram_enabled() {
+                self.prg.set_ram(addr as usize & 0x1FFF, val);
+            }
+            return;
+        }
+        match self.register(addr) {
+            0x8000..=0x8003 => self.prg_16k = val & 0x0F,
+            0xB003 => self.control = val,
+            reg @ 0x9000..=0xB002 => self.audio.write(reg, val),
+            0xC000..=0xC003 => self.prg_8k = val & 0x1F,
+            reg @ 0xD000..=0xE003 => {
+                self.chr_banks[((reg - 0xD000) >> 12) as usize * 4 + (reg as usize & 0x3)] = val
+            }
+            0xF000 => self.irq.set_latch(val),
+            0xF001 => self.irq.set_control(val),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.chr.loadb(self.chr_bank(addr), 0x400, addr)
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.chr.storeb(self.chr_bank(addr), 0x400, addr, val)
+    }
+    /// Nametables from CHR-ROM aren't supported, no game uses them
+    fn mirroring(&self) -> Mirroring {
+        vrc_mirroring(self.control >> 2)
+    }
+    fn clock(&mut self) {
+        self.irq.clock();
+        self.audio.clock();
+    }
+    fn irq(&self) -> bool {
+        self.irq.irq()
+    }
+    fn audio(&self) -> f32 {
+        self.audio.output()
+    }
+}
@@ -0,0 +1,124 @@
+use super::vrc::{vrc_mirroring, VrcIrq};
+use super::{open_bus, Mapper, CHR, PRG};
+use crate::apu::Vrc7Audio;
+use crate::ppu::Mirroring;
+
+/// Mapper 85, Konami VRC7. VRC7a selects the odd registers with A4,
+/// VRC7b with A3, NES 2.0 submappers 2 and 1 tell them apart.
+/// see http://wiki.nesdev.com/w/index.php/VRC7
+pub struct Vrc7<'a> {
+    pub(super) prg: PRG<'a>,
+    chr: CHR<'a>,
+    /// Address lines selecting the odd registers
+    line: u16,
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    /// $E000 RS-- --MM
+    /// R: PRG-RAM enable, S: silence the audio, M: mirroring
+    control: u8,
+    irq: VrcIrq,
+    audio: Vrc7Audio,
+}
+
+state!(Vrc7<'a> { prg, chr, prg_banks, chr_banks, control, irq, audio });
+
+impl<'a> Vrc7<'a> {
+    pub fn new(prg: PRG<'a>, chr: CHR<'a>, submapper: u8) -> Vrc7<'a> {
+        let line = match submapper {
+            1 => 0x08,
+            2 => 0x10,
+            _ => 0x18,
+        };
+        Vrc7 {
+            prg,
+            chr,
+            line,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+            audio: Vrc7Audio::new(),
+        }
+    }
+    /// Translate `addr` to the register it selects, $x000 or $x010
+    fn register(&self, addr: u16) -> u16 {
+        (addr & 0xF000) | if addr & self.line != 0 { 0x10 } else { 0 }
+    }
+    fn prg_bank(&self, addr: u16) -> usize {
+        match (addr >> 13) & 0x3 {
+            3 => self.prg.banks(0x2000) - 1,
+            n => self.prg_banks[n as usize] as usize,
+        }
+    }
+    fn chr_bank(&self, addr: u16) -> usize {
+        self.chr_banks[(addr >> 10) as usize & 0x7] as usize
+    }
+    fn ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+}
+
+impl<'a> Mapper for Vrc7<'a> {
+    fn cpu_loadb(&mut self, addr: u16) -> u8 {
+        self.cpu_peekb(addr).unwrap_or_else(|| open_bus(addr))
+    }
+    fn cpu_peekb(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x6000..=0x7FFF if self.ram_enabled() => Some(self.prg.ram(addr as usize & 0x1FFF)),
+            0x8000..=0xFFFF => Some(self.prg.rom(self.prg_bank(addr), 0x2000, addr)),
+            _ => None,
+        }
+    }
+    fn cpu_storeb(&mut self, addr: u16, val: u8) {
+        if addr < 0x8000 {
+            if addr >= 0x6000 && self.ram_enabled() {
+                self.prg.set_ram(addr as usize & 0x1FFF, val);
+            }
+            return;
+        }
+        // The audio ports decode A5 as well
+        match addr & 0xF030 {
+            0x9010 => return self.audio.write_address(val),
+            0x9030 => return self.audio.write_data(val),
+            _ => {}
+        }
+        match self.register(addr) {
+            0x8000 => self.prg_banks[0] = val & 0x3F,
+            0x8010 => self.prg_banks[1] = val & 0x3F,
+            0x9000 => self.prg_banks[2] = val & 0x3F,
+            reg @ 0xA000..=0xD010 => {
+                let bank = ((reg - 0xA000) >> 12) as usize * 2 + (reg as usize & 0x10) / 0x10;
+                self.chr_banks[bank] = val;
+            }
+            0xE000 => self.control = val,
+            0xE010 => self.irq.set_latch(val),
+            0xF000 => self.irq.set_control(val),
+            0xF010 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+    fn chr_loadb(&mut self, addr: u16) -> u8 {
+        self.chr.loadb(self.chr_bank(addr), 0x400, addr)
+    }
+    fn chr_storeb(&mut self, addr: u16, val: u8) {
+        self.chr.storeb(self.chr_bank(addr), 0x400, addr, val)
+    }
+    fn mirroring(&self) -> Mirroring {
+        vrc_mirroring(self.control)
+    }
+    fn clock(&mut self) {
+        self.irq.clock();
+        self.audio.clock();
+    }
+    fn irq(&self) -> bool {
+        self.irq.irq()
+    }
+    /// Setting the S bit of $E000 silences the synthesizer
+    fn audio(&self) -> f32 {
+        if self.control & 0x40 != 0 {
+            0.0
+        } else {
+            self.audio.output()
+        }
+    }
+}
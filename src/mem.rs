@@ -1,8 +1,9 @@
-use crate::apu::APU;
+use crate::apu::{Speaker, APU};
 use crate::input::Input;
-use crate::mapper::PRG;
-use crate::ppu::PPU;
-use crate::rom::Rom;
+use crate::interrupt::{Interrupt, Irq};
+use crate::mapper::{open_bus, Cartridge, Mapper};
+use crate::ppu::{Screen, PPU};
+use crate::rom::{Rom, RomError};
 use mos6502::Memory;
 
 pub struct NESMemory<'a> {
@@ -13,19 +14,40 @@ pub struct NESMemory<'a> {
     ram: [u8; 0x800],
     /// $2000 	$2008 		Registers   PPU
     /// $2008 	$4000 	R 	Registers   8Bit Mirror of 2000-2008
-    pub ppu: PPU<'a>,
+    pub ppu: PPU,
     /// $4000 	$4020 		Registers   APU
     pub apu: APU,
     pub input: Input,
     /// $4020 	$6000		Expansion ROM
     /// $6000 	$8000 		SAVERAM
-    sram: [u8; 0x2000],
     /// $8000 	$C000 		PRG-ROM
     /// $C000 	$10000 		PRG-ROM
-    prg: PRG<'a>,
+    /// PPU $0000-$3EFF is also routed through the cartridge
+    pub cart: Cartridge<'a>,
+    /// NMI and IRQ lines of the CPU
+    pub interrupt: Interrupt,
     cycles: usize,
+    /// CPU cycles the PPU has already been run for
+    synced: usize,
+    /// CPU cycle OAM DMA finishes at
+    oam_dma_end: usize,
+    /// Controller port read by the last instruction
+    joypad_read: Option<u16>,
 }
 
+state!(NESMemory<'a> {
+    ram,
+    ppu,
+    apu,
+    input,
+    cart,
+    interrupt,
+    cycles,
+    synced,
+    oam_dma_end,
+    joypad_read
+});
+
 impl<'a> Memory for NESMemory<'a> {
     fn reset(&mut self) {
         self.ram = [0; 0x800];
@@ -33,44 +55,59 @@ impl<'a> Memory for NESMemory<'a> {
         //self.ppu.reset();
         self.apu.reset();
         self.input.reset();
+        self.interrupt.reset();
         self.cycles = 7;
+        self.synced = 7;
     }
     fn loadb(&mut self, addr: u16) -> u8 {
         match addr {
             0x0000...0x1FFF => self.ram[addr as usize & 0x7ff],
-            0x2000...0x3FFF => self.ppu.reg_loadb(addr),
+            0x2000...0x3FFF => {
+                let val = self.ppu.reg_loadb(&mut self.cart, addr);
+                // reading PPUSTATUS ends VBlank
+                self.interrupt.set_nmi(self.ppu.nmi_output());
+                val
+            }
             0x4000...0x4013 => self.apu.loadb(addr),
-            0x4014 => unimplemented!("Read DMA"),
+            // OAMDMA is write only
+            0x4014 => open_bus(addr),
             0x4015 => self.apu.get_channel(),
-            0x4016 => self.input.load1(),
-            0x4017 => self.input.load2(),
-            0x4018...0x401F => unimplemented!("TEST MODE"),
-            0x4020...0x5FFF => unimplemented!("Read Expansion ROM"),
-            0x6000...0x7FFF => self.sram[addr as usize & 0x1FFF],
-            0x8000...0xFFFF => self.prg[addr],
+            0x4016 => {
+                self.joypad_read = Some(addr);
+                self.input.load1()
+            }
+            0x4017 => {
+                self.joypad_read = Some(addr);
+                self.input.load2()
+            }
+            // CPU test mode, disabled on retail consoles
+            0x4018...0x401F => open_bus(addr),
+            0x4020...0xFFFF => self.cart.cpu_loadb(addr),
         }
     }
     fn try_loadb(&self, addr: u16) -> Option<u8> {
         match addr {
             0x0000...0x1FFF => Some(self.ram[addr as usize & 0x7ff]),
-            0x2000...0x5FFF => None,
-            0x6000...0x7FFF => Some(self.sram[addr as usize & 0x1FFF]),
-            0x8000...0xFFFF => Some(self.prg[addr]),
+            0x2000...0x401F => None,
+            0x4020...0xFFFF => self.cart.cpu_peekb(addr),
         }
     }
     fn storeb(&mut self, addr: u16, val: u8) {
         match addr {
             0x0000...0x1FFF => self.ram[addr as usize & 0x7ff] = val,
-            0x2000...0x3FFF => self.ppu.reg_storeb(addr, val),
+            0x2000...0x3FFF => {
+                self.ppu.reg_storeb(&mut self.cart, addr, val);
+                self.cart.ppu_reg_write(addr & 0x2007, val);
+                // writing PPUCTRL may enable NMI in VBlank
+                self.interrupt.set_nmi(self.ppu.nmi_output());
+            }
             0x4000...0x4013 => self.apu.storeb(addr, val),
             0x4014 => self.dma(val),
             0x4015 => self.apu.set_channel(val),
             0x4016 => self.input.set(val),
             0x4017 => self.apu.set_mode(val),
-            0x4018...0x401F => unimplemented!("TEST MODE"),
-            0x4020...0x5FFF => unimplemented!("Write Expansion ROM"),
-            0x6000...0x7FFF => self.sram[addr as usize & 0x1FFF] = val,
-            0x8000...0xFFFF => unimplemented!("Write to PGR"),
+            0x4018...0x401F => {}
+            0x4020...0xFFFF => self.cart.cpu_storeb(addr, val),
         }
     }
     fn add_cycles(&mut self, val: usize) {
@@ -82,30 +119,246 @@ impl<'a> Memory for NESMemory<'a> {
 }
 
 impl<'a> NESMemory<'a> {
-    pub fn new(buffer: &'a [u8]) -> NESMemory<'a> {
-        let rom = Rom::load(&buffer);
-        let (prg, chr, header) = rom.split();
-        info!("Load Rom:{}", header);
+    pub fn new(
+        buffer: &'a [u8],
+        ram: &'a mut [u8],
+        sample_rate: u32,
+    ) -> Result<NESMemory<'a>, RomError> {
+        let rom = Rom::load(&buffer)?;
+        info!("Load Rom:{}", rom.header);
+        Ok(NESMemory::with_cartridge(
+            Cartridge::new(rom, ram)?,
+            sample_rate,
+        ))
+    }
+
+    pub fn with_cartridge(cart: Cartridge<'a>, sample_rate: u32) -> NESMemory<'a> {
         NESMemory {
             ram: [0; 0x800],
-            ppu: PPU::new(chr),
-            apu: APU::new(),
+            ppu: PPU::new(),
+            apu: APU::new(sample_rate),
             input: Input::new(),
-            sram: [0; 0x2000],
-            prg,
+            cart,
+            interrupt: Interrupt::new(),
             cycles: 7,
+            synced: 7,
+            oam_dma_end: 0,
+            joypad_read: None,
         }
     }
 
-    fn dma(&mut self, addr_high: u8) {
-        let start = (addr_high as usize) << 8;
-        let dst = match addr_high {
-            0x00...0x1F => &self.ram[start..(start + 0x100)],
-            0x60...0x7F => &self.sram[start..(start + 0x100)],
-            0x80...0xFF => &self.prg[start as u16..(start as u16 + 0x100)],
-            _ => unimplemented!(),
+    /// Catch the PPU and APU up with the CPU, 3 dots per CPU cycle.
+    /// Return true if the PPU started a new frame meanwhile.
+    pub fn sync<S: Screen, A: Speaker>(&mut self, screen: &mut S, speaker: &mut A) -> bool {
+        let mut frame = false;
+        let instruction_end = self.cycles;
+        while self.synced < self.cycles {
+            for _ in 0..3 {
+                frame |= self.ppu.step(&mut self.cart, screen);
+                self.interrupt.set_nmi(self.ppu.nmi_output());
+            }
+            self.apu.step(speaker, self.cart.audio());
+            if let Some(addr) = self.apu.dmc_request() {
+                self.dmc_dma(addr, self.synced + 1 == instruction_end);
+            }
+            self.interrupt.set_irq(Irq::FRAME, self.apu.frame_irq());
+            self.interrupt.set_irq(Irq::DMC, self.apu.dmc_irq());
+            self.cart.clock();
+            self.interrupt.set_irq(Irq::MAPPER, self.cart.irq());
+            self.synced += 1;
+        }
+        self.joypad_read = None;
+        frame
+    }
+
+    /// Fetch a DMC sample byte, the CPU is halted meanwhile.
+    /// see http://wiki.nesdev.com/w/index.php/APU_DMC#Memory_reader
+    fn dmc_dma(&mut self, addr: u16, last_cycle: bool) {
+        let val = self.loadb(addr);
+        self.apu.dmc_fill(val);
+        let stall = match self.oam_dma_end.saturating_sub(self.synced) {
+            0 => 4,
+            // DMC DMA on the second-to-last or last cycle of OAM DMA
+            1 => 3,
+            2 => 1,
+            _ => 2,
         };
-        self.ppu.oam.copy_from_slice(dst);
-        self.add_cycles(if self.cycles % 2 == 0 { 513 } else { 514 });
+        self.cycles += stall;
+        // The halted CPU repeats its read while waiting. If it was reading a
+        // controller on the last cycle of the instruction, the repeated read
+        // clocks the shift register again and a bit is lost.
+        if last_cycle {
+            match self.joypad_read {
+                Some(0x4016) => {
+                    self.input.load1();
+                }
+                Some(0x4017) => {
+                    self.input.load2();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn dma(&mut self, addr_high: u8) {
+        let start = (addr_high as u16) << 8;
+        for i in 0..0x100 {
+            // Registers aren't read for real, DMA from them has no side effects
+            let addr = start + i as u16;
+            self.ppu.oam[i] = self.try_loadb(addr).unwrap_or_else(|| open_bus(addr));
+        }
+        let stall = if self.cycles % 2 == 0 { 513 } else { 514 };
+        self.oam_dma_end = self.cycles + stall;
+        self.add_cycles(stall);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::Buttons;
+
+    struct NoScreen;
+
+    impl Screen for NoScreen {
+        fn render_pixel(&mut self, _x: u16, _y: u16, _pixel: (u8, u8, u8)) {}
+    }
+
+    struct NoSpeaker;
+
+    impl Speaker for NoSpeaker {
+        fn sample_rate(&self) -> u32 {
+            44100
+        }
+        fn push_sample(&mut self, _sample: f32) {}
+    }
+
+    const ROM_SIZE: usize = 16 + 0x4000 + 0x2000;
+
+    /// NROM with its reset vector at $8000
+    fn rom() -> [u8; ROM_SIZE] {
+        let mut rom = [0; ROM_SIZE];
+        rom[..8].copy_from_slice(b"NES\x1a\x01\x01\x00\x00");
+        rom[16 + 0x3FFC..16 + 0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        rom
+    }
+
+    /// Let the CPU take `cycles` more cycles and catch up with it
+    fn run(mem: &mut NESMemory, cycles: usize) -> bool {
+        mem.add_cycles(cycles);
+        mem.sync(&mut NoScreen, &mut NoSpeaker)
+    }
+
+    #[test]
+    fn three_dots_per_cpu_cycle() {
+        let (rom, mut ram) = (rom(), [0; 0x2000]);
+        let mut mem = NESMemory::new(&rom, &mut ram, 44100).unwrap();
+        assert!(!run(&mut mem, 10));
+        assert_eq!((mem.ppu.scanline, mem.ppu.cycles), (0, 30));
+        assert!(!run(&mut mem, 341));
+        assert_eq!((mem.ppu.scanline, mem.ppu.cycles), (3, 30));
+    }
+
+    #[test]
+    fn sync_reports_the_frame_end() {
+        let (rom, mut ram) = (rom(), [0; 0x2000]);
+        let mut mem = NESMemory::new(&rom, &mut ram, 44100).unwrap();
+        // 89342 dots are 29780.67 CPU cycles
+        assert!(!run(&mut mem, 29780));
+        assert!(run(&mut mem, 1));
+        assert_eq!((mem.ppu.scanline, mem.ppu.cycles), (0, 1));
+    }
+
+    #[test]
+    fn nmi_when_enabled_in_vblank() {
+        let (rom, mut ram) = (rom(), [0; 0x2000]);
+        let mut mem = NESMemory::new(&rom, &mut ram, 44100).unwrap();
+        // Into VBlank with NMI disabled
+        run(&mut mem, 241 * 341 / 3 + 2);
+        assert!(!mem.interrupt.take_nmi());
+        mem.storeb(0x2000, 0x80);
+        assert!(mem.interrupt.take_nmi());
+        // Every time it's enabled again
+        mem.storeb(0x2000, 0x00);
+        mem.storeb(0x2000, 0x80);
+        assert!(mem.interrupt.take_nmi());
+        // Reading PPUSTATUS ends VBlank, no more NMI
+        mem.loadb(0x2002);
+        mem.storeb(0x2000, 0x00);
+        mem.storeb(0x2000, 0x80);
+        assert!(!mem.interrupt.take_nmi());
+    }
+
+    /// Play a 17 byte sample at the fastest rate with the IRQ enabled
+    fn start_dmc(mem: &mut NESMemory) {
+        // No frame IRQ in the way
+        mem.storeb(0x4017, 0x40);
+        mem.storeb(0x4010, 0x8F);
+        mem.storeb(0x4013, 0x01);
+        mem.storeb(0x4015, 0x10);
+    }
+
+    #[test]
+    fn dmc_dma_stalls_the_cpu() {
+        let (rom, mut ram) = (rom(), [0; 0x2000]);
+        let mut mem = NESMemory::new(&rom, &mut ram, 44100).unwrap();
+        start_dmc(&mut mem);
+        run(&mut mem, 1);
+        assert_eq!(mem.get_cycles(), 7 + 1 + 4);
+        // Only 2 cycles during OAM DMA, which takes 514 cycles on odd cycles.
+        // At the slowest rate a single byte is fetched meanwhile.
+        let mut ram = [0; 0x2000];
+        let mut mem = NESMemory::new(&rom, &mut ram, 44100).unwrap();
+        start_dmc(&mut mem);
+        mem.storeb(0x4010, 0x80);
+        mem.storeb(0x4014, 0x02);
+        run(&mut mem, 0);
+        assert_eq!(mem.get_cycles(), 7 + 514 + 2);
+    }
+
+    #[test]
+    fn dmc_status_and_irq() {
+        let (rom, mut ram) = (rom(), [0; 0x2000]);
+        let mut mem = NESMemory::new(&rom, &mut ram, 44100).unwrap();
+        start_dmc(&mut mem);
+        // A byte is played every 8 * 54 cycles
+        run(&mut mem, 1);
+        assert_eq!(mem.loadb(0x4015) & 0x90, 0x10);
+        run(&mut mem, 15 * 432);
+        assert_eq!(mem.loadb(0x4015) & 0x90, 0x10);
+        assert!(!mem.interrupt.irq());
+        run(&mut mem, 2 * 432);
+        assert_eq!(mem.loadb(0x4015) & 0x90, 0x80);
+        assert!(mem.interrupt.irq());
+        // Reading $4015 doesn't acknowledge it, writing does
+        assert_eq!(mem.loadb(0x4015) & 0x80, 0x80);
+        mem.storeb(0x4015, 0x00);
+        run(&mut mem, 1);
+        assert_eq!(mem.loadb(0x4015) & 0x80, 0);
+        assert!(!mem.interrupt.irq());
+    }
+
+    /// Hold A and B on controller 1 and read A
+    fn read_a(mem: &mut NESMemory) {
+        mem.input.set_buttons(0, Buttons::A | Buttons::B);
+        mem.storeb(0x4016, 1);
+        mem.storeb(0x4016, 0);
+        assert_eq!(mem.loadb(0x4016), 0x41);
+    }
+
+    #[test]
+    fn dmc_dma_clocks_the_controller_again() {
+        let (rom, mut ram) = (rom(), [0; 0x2000]);
+        let mut mem = NESMemory::new(&rom, &mut ram, 44100).unwrap();
+        read_a(&mut mem);
+        run(&mut mem, 1);
+        assert_eq!(mem.loadb(0x4016), 0x41);
+        // The DMC fetch on the last cycle of the read repeats it, B is lost
+        let mut ram = [0; 0x2000];
+        let mut mem = NESMemory::new(&rom, &mut ram, 44100).unwrap();
+        start_dmc(&mut mem);
+        read_a(&mut mem);
+        run(&mut mem, 1);
+        assert_eq!(mem.loadb(0x4016), 0x40);
     }
 }
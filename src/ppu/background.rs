@@ -0,0 +1,118 @@
+use super::PPU;
+use crate::mapper::Mapper;
+
+/// Latches and shift registers of the background fetch pipeline.
+/// see http://wiki.nesdev.com/w/index.php/PPU_rendering
+pub struct Background {
+    /// Nametable byte of the tile being fetched
+    tile: u8,
+    /// 2 bit palette of the tile, already picked out of the attribute byte
+    attr: u8,
+    /// Low bit plane of the tile
+    low: u8,
+    /// High bit plane of the tile
+    high: u8,
+    /// The high byte is the tile being drawn, the low byte is the next tile
+    pattern_low: u16,
+    pattern_high: u16,
+    /// Attribute bits expanded to 8 pixels, same layout as the pattern shifters
+    attr_low: u16,
+    attr_high: u16,
+}
+
+state!(Background {
+    tile,
+    attr,
+    low,
+    high,
+    pattern_low,
+    pattern_high,
+    attr_low,
+    attr_high
+});
+
+impl Background {
+    pub fn new() -> Background {
+        Background {
+            tile: 0,
+            attr: 0,
+            low: 0,
+            high: 0,
+            pattern_low: 0,
+            pattern_high: 0,
+            attr_low: 0,
+            attr_high: 0,
+        }
+    }
+    /// Move the latched tile into the low byte of the shift registers
+    fn reload(&mut self) {
+        self.pattern_low = (self.pattern_low & 0xFF00) | self.low as u16;
+        self.pattern_high = (self.pattern_high & 0xFF00) | self.high as u16;
+        self.attr_low = (self.attr_low & 0xFF00) | if self.attr & 1 != 0 { 0xFF } else { 0 };
+        self.attr_high = (self.attr_high & 0xFF00) | if self.attr & 2 != 0 { 0xFF } else { 0 };
+    }
+    fn shift(&mut self) {
+        self.pattern_low <<= 1;
+        self.pattern_high <<= 1;
+        self.attr_low <<= 1;
+        self.attr_high <<= 1;
+    }
+    /// 4 bit palette index of the current pixel, 0 if transparent
+    pub fn pixel(&self, fine_x: u8) -> u8 {
+        let bit = 0x8000 >> fine_x;
+        let pattern =
+            (self.pattern_low & bit != 0) as u8 | ((self.pattern_high & bit != 0) as u8) << 1;
+        if pattern == 0 {
+            return 0;
+        }
+        let attr = (self.attr_low & bit != 0) as u8 | ((self.attr_high & bit != 0) as u8) << 1;
+        attr << 2 | pattern
+    }
+}
+
+impl PPU {
+    /// Run the background half of the pipeline for current dot of a render scanline.
+    /// Must only be called when rendering is enabled.
+    pub(super) fn fetch_background<M: Mapper>(&mut self, mapper: &mut M) {
+        let dot = self.cycles;
+        if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+            self.background.shift();
+        }
+        if (1..=256).contains(&dot) || (321..=336).contains(&dot) {
+            match (dot - 1) % 8 {
+                0 => {
+                    self.background.reload();
+                    self.background.tile = self.loadb(mapper, self.regs.tile_addr());
+                }
+                2 => {
+                    let attr = self.loadb(mapper, self.regs.attr_addr());
+                    self.background.attr = (attr >> self.regs.attr_shift()) & 0x3;
+                }
+                4 => self.background.low = self.loadb(mapper, self.bg_pattern_addr()),
+                6 => self.background.high = self.loadb(mapper, self.bg_pattern_addr() + 8),
+                7 => self.regs.increment_x(),
+                _ => {}
+            }
+        }
+        match dot {
+            256 => self.regs.increment_y(),
+            257 => {
+                self.background.reload();
+                self.regs.copy_x();
+            }
+            337 => {
+                self.background.reload();
+                self.background.tile = self.loadb(mapper, self.regs.tile_addr());
+            }
+            // Unused nametable fetch
+            339 => self.background.tile = self.loadb(mapper, self.regs.tile_addr()),
+            _ => {}
+        }
+        if self.scanline == super::LAST_SCANLINE && (280..=304).contains(&dot) {
+            self.regs.copy_y();
+        }
+    }
+    fn bg_pattern_addr(&self) -> u16 {
+        self.regs.bg_chr_addr() + ((self.background.tile as u16) << 4) + self.regs.fine_y()
+    }
+}
@@ -1,26 +1,29 @@
+mod background;
 mod nametable;
 mod palette;
 mod reg;
 mod screen;
-use self::{nametable::*, palette::Palette, reg::Regs};
-use crate::mapper::CHR;
+mod sprite;
+use self::{background::Background, palette::Palette, reg::Regs, sprite::Sprites};
+use crate::mapper::Mapper;
+pub use nametable::{Mirroring, NameTable};
 pub use screen::Screen;
 
 pub const SCREEN_WIDTH: usize = 256;
 pub const SCREEN_HEIGHT: usize = 240;
 
-pub const CYCLES_PER_SCANLINE: usize = 114; // 29781 cycles per frame / 261 scanlines = 113.667
 pub const VBLANK_SCANLINE: u16 = 240;
 pub const LAST_SCANLINE: u16 = 261;
+pub const DOTS_PER_SCANLINE: usize = 341;
 
-pub struct PPU<'a> {
+/// PPU address space:
+/// 0x0000-0x1000 pattern table 0 图样表 on the cartridge
+/// 0x1000-0x2000 pattern table 1
+/// 0x2000-0x3F00 name tables, the cartridge decides how they are mapped
+/// 0x3F00-0x4000 palette
+pub struct PPU {
     /// CPU:0x2000-0x2007
     pub regs: Regs,
-    /// PPU
-    /// 0x1000 pattern table 图样表CHR-ROM
-    /// 0x0000-0x1000 pattern table 0  
-    /// 0x1000-0x2000 pattern table 1
-    chr: CHR<'a>,
     /// 0x3C0 name table名称表 32*30 8*8 => 256*240
     /// 0x40 attribute table属性表
     pub nametables: NameTable,
@@ -36,41 +39,289 @@ pub struct PPU<'a> {
     /// Vertical blanking lines (241-260) 20行的VBlank线.
     pub scanline: u16,
     pub cycles: usize,
+    /// The pre-render scanline is one dot shorter on odd frames
+    odd_frame: bool,
+    background: Background,
+    sprites: Sprites,
 }
 
-impl<'a> PPU<'a> {
-    pub fn loadb(&self, addr: u16) -> u8 {
+state!(PPU {
+    regs,
+    nametables,
+    palette,
+    oam,
+    scanline,
+    cycles,
+    odd_frame,
+    background,
+    sprites
+});
+
+impl PPU {
+    pub fn loadb<M: Mapper>(&self, mapper: &mut M, addr: u16) -> u8 {
         // 0x4000-0xC000 is mirror of 0x0000-0x4000
         let addr = addr & 0x3FFF;
         match addr {
-            0x0000...0x1FFF => self.chr[addr],
-            0x2000...0x2FFF => self.nametables[addr],
             // 0x3000-0x3F00 is mirror of 0x2000-0x2F00
-            0x3000...0x3EFF => self.loadb(addr - 0x1000),
+            0x0000...0x3EFF => mapper.ppu_loadb(addr, &self.nametables),
             // 0x3F20-0x4000 is mirror of 0x3F00-0x3F20
             0x3F00...0x3FFF => self.palette[addr],
             _ => unreachable!(),
         }
     }
-    pub fn storeb(&mut self, addr: u16, val: u8) {
+    pub fn storeb<M: Mapper>(&mut self, mapper: &mut M, addr: u16, val: u8) {
         let addr = addr & 0x3FFF;
         match addr {
-            0x0000...0x1FFF => self.chr.storeb(addr, val),
-            0x2000...0x2FFF => self.nametables[addr] = val,
-            0x3000...0x3EFF => self.storeb(addr - 0x1000, val),
+            0x0000...0x3EFF => mapper.ppu_storeb(addr, val, &mut self.nametables),
             0x3F00...0x3FFF => self.palette[addr] = val,
             _ => unreachable!(),
         }
     }
-    pub fn new(chr: CHR<'a>) -> PPU {
+    pub fn new() -> PPU {
         PPU {
-            chr,
             regs: Regs::new(),
             nametables: NameTable::new(),
             palette: Palette::new(),
             oam: [0u8; 0x100],
             scanline: 0,
             cycles: 0,
+            odd_frame: false,
+            background: Background::new(),
+            sprites: Sprites::new(),
+        }
+    }
+    /// Advance the PPU by one dot.
+    /// Return true when the PPU wraps around to the first visible scanline.
+    pub fn step<M: Mapper, S: Screen>(&mut self, mapper: &mut M, screen: &mut S) -> bool {
+        let dot = self.cycles;
+        let render_line = self.scanline < VBLANK_SCANLINE || self.scanline == LAST_SCANLINE;
+        if render_line && self.regs.rendering() {
+            self.fetch_background(mapper);
+            self.fetch_sprites(mapper);
+        }
+        if self.scanline < VBLANK_SCANLINE && (1..=SCREEN_WIDTH).contains(&dot) {
+            self.render_pixel(screen);
+        }
+        if self.scanline == VBLANK_SCANLINE + 1 && dot == 1 {
+            self.regs.vblank_start();
+        }
+        if self.scanline == LAST_SCANLINE && dot == 1 {
+            self.regs.vblank_end();
+            self.regs.clear_sprite_flags();
+        }
+        self.cycles += 1;
+        if self.scanline == LAST_SCANLINE
+            && self.cycles == DOTS_PER_SCANLINE - 1
+            && self.odd_frame
+            && self.regs.rendering()
+        {
+            // skip the last dot of pre-render scanline
+            self.cycles = DOTS_PER_SCANLINE;
+        }
+        if self.cycles == DOTS_PER_SCANLINE {
+            self.cycles = 0;
+            self.scanline += 1;
+            if self.scanline > LAST_SCANLINE {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+                return true;
+            }
+        }
+        false
+    }
+    /// Level of the NMI output, active while in VBlank with NMI enabled.
+    /// Enabling NMI during VBlank raises another NMI.
+    pub fn nmi_output(&self) -> bool {
+        self.regs.vblank() && self.regs.nmi_enable()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NoScreen;
+
+    impl Screen for NoScreen {
+        fn render_pixel(&mut self, _x: u16, _y: u16, _pixel: (u8, u8, u8)) {}
+    }
+
+    /// 8 KB of CHR-RAM with horizontal mirroring
+    struct Board {
+        chr: [u8; 0x2000],
+    }
+
+    impl Mapper for Board {
+        fn cpu_loadb(&mut self, _addr: u16) -> u8 {
+            0
+        }
+        fn cpu_peekb(&self, _addr: u16) -> Option<u8> {
+            None
+        }
+        fn cpu_storeb(&mut self, _addr: u16, _val: u8) {}
+        fn chr_loadb(&mut self, addr: u16) -> u8 {
+            self.chr[addr as usize]
+        }
+        fn chr_storeb(&mut self, addr: u16, val: u8) {
+            self.chr[addr as usize] = val;
+        }
+        fn mirroring(&self) -> Mirroring {
+            Mirroring::Horizontal
+        }
+    }
+
+    fn setup() -> (PPU, Board) {
+        (PPU::new(), Board { chr: [0; 0x2000] })
+    }
+
+    /// Dots run until the PPU starts the next frame
+    fn frame_dots(ppu: &mut PPU, board: &mut Board) -> usize {
+        let mut dots = 1;
+        while !ppu.step(board, &mut NoScreen) {
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn frame_length() {
+        let (mut ppu, mut board) = setup();
+        assert_eq!(frame_dots(&mut ppu, &mut board), 341 * 262);
+        assert_eq!(frame_dots(&mut ppu, &mut board), 341 * 262);
+    }
+
+    #[test]
+    fn odd_frame_skips_a_dot_when_rendering() {
+        let (mut ppu, mut board) = setup();
+        ppu.reg_storeb(&mut board, 0x2001, 0x08);
+        assert_eq!(frame_dots(&mut ppu, &mut board), 341 * 262);
+        assert_eq!(frame_dots(&mut ppu, &mut board), 341 * 262 - 1);
+        assert_eq!(frame_dots(&mut ppu, &mut board), 341 * 262);
+        // Only if rendering is on when the dot would be skipped
+        assert!(ppu.odd_frame);
+        ppu.reg_storeb(&mut board, 0x2001, 0x00);
+        assert_eq!(frame_dots(&mut ppu, &mut board), 341 * 262);
+    }
+
+    /// Run until the PPU is about to run `dot` of `scanline`
+    fn run_to(ppu: &mut PPU, board: &mut Board, scanline: u16, dot: usize) {
+        while (ppu.scanline, ppu.cycles) != (scanline, dot) {
+            ppu.step(board, &mut NoScreen);
         }
     }
+
+    fn status(ppu: &mut PPU, board: &mut Board) -> u8 {
+        ppu.reg_loadb(board, 0x2002)
+    }
+
+    /// Sprites with tile 0 at `y` and X 20, the others are off screen
+    fn place_sprites(ppu: &mut PPU, sprites: core::ops::Range<usize>, y: u8) {
+        ppu.oam = [0xFF; 0x100];
+        for n in sprites {
+            ppu.oam[n * 4..n * 4 + 4].copy_from_slice(&[y, 0, 0, 20]);
+        }
+    }
+
+    /// Tile 0 of the first pattern table is opaque, the second one is blank.
+    /// Sprites on line 11, with the background and sprites shown everywhere.
+    fn sprite_setup() -> (PPU, Board) {
+        let (mut ppu, mut board) = setup();
+        board.chr[..8].copy_from_slice(&[0xFF; 8]);
+        place_sprites(&mut ppu, 0..1, 10);
+        ppu.reg_storeb(&mut board, 0x2001, 0x1E);
+        (ppu, board)
+    }
+
+    #[test]
+    fn sprite_zero_hit() {
+        let (mut ppu, mut board) = sprite_setup();
+        run_to(&mut ppu, &mut board, 11, 0);
+        assert_eq!(status(&mut ppu, &mut board) & 0x40, 0);
+        run_to(&mut ppu, &mut board, 11, 30);
+        assert_eq!(status(&mut ppu, &mut board) & 0x40, 0x40);
+        // Until the pre-render line
+        run_to(&mut ppu, &mut board, 261, 1);
+        assert_eq!(status(&mut ppu, &mut board) & 0x40, 0x40);
+        run_to(&mut ppu, &mut board, 261, 2);
+        assert_eq!(status(&mut ppu, &mut board) & 0x40, 0);
+    }
+
+    #[test]
+    fn no_sprite_zero_hit_on_transparent_background() {
+        let (mut ppu, mut board) = sprite_setup();
+        ppu.reg_storeb(&mut board, 0x2000, 0x10);
+        run_to(&mut ppu, &mut board, 240, 0);
+        assert_eq!(status(&mut ppu, &mut board) & 0x40, 0);
+        // Nor with another sprite than sprite 0
+        let (mut ppu, mut board) = sprite_setup();
+        place_sprites(&mut ppu, 1..2, 10);
+        run_to(&mut ppu, &mut board, 240, 0);
+        assert_eq!(status(&mut ppu, &mut board) & 0x40, 0);
+    }
+
+    #[test]
+    fn sprite_overflow() {
+        let (mut ppu, mut board) = sprite_setup();
+        place_sprites(&mut ppu, 0..8, 10);
+        run_to(&mut ppu, &mut board, 240, 0);
+        assert_eq!(status(&mut ppu, &mut board) & 0x20, 0);
+        let (mut ppu, mut board) = sprite_setup();
+        place_sprites(&mut ppu, 0..9, 10);
+        // Found while evaluating line 10 for line 11
+        run_to(&mut ppu, &mut board, 10, 0);
+        assert_eq!(status(&mut ppu, &mut board) & 0x20, 0);
+        run_to(&mut ppu, &mut board, 11, 0);
+        assert_eq!(status(&mut ppu, &mut board) & 0x20, 0x20);
+        run_to(&mut ppu, &mut board, 261, 2);
+        assert_eq!(status(&mut ppu, &mut board) & 0x20, 0);
+    }
+
+    #[test]
+    fn sprite_overflow_hardware_bug() {
+        // After 8 sprites the tile byte of sprite 9 is compared as its Y
+        let (mut ppu, mut board) = sprite_setup();
+        place_sprites(&mut ppu, 0..8, 10);
+        ppu.oam[9 * 4 + 1] = 10;
+        run_to(&mut ppu, &mut board, 11, 0);
+        assert_eq!(status(&mut ppu, &mut board) & 0x20, 0x20);
+        // So a 9th sprite on the line can be missed
+        let (mut ppu, mut board) = sprite_setup();
+        place_sprites(&mut ppu, 0..8, 10);
+        ppu.oam[9 * 4] = 10;
+        run_to(&mut ppu, &mut board, 240, 0);
+        assert_eq!(status(&mut ppu, &mut board) & 0x20, 0);
+    }
+
+    #[test]
+    fn status_across_vblank() {
+        let (mut ppu, mut board) = setup();
+        run_to(&mut ppu, &mut board, 241, 1);
+        assert_eq!(status(&mut ppu, &mut board) & 0x80, 0);
+        ppu.step(&mut board, &mut NoScreen);
+        // Reading it ends VBlank
+        assert_eq!(status(&mut ppu, &mut board) & 0x80, 0x80);
+        assert_eq!(status(&mut ppu, &mut board) & 0x80, 0);
+        run_to(&mut ppu, &mut board, 260, 0);
+        assert_eq!(status(&mut ppu, &mut board) & 0x80, 0);
+        // Otherwise it lasts until the pre-render line
+        let (mut ppu, mut board) = setup();
+        run_to(&mut ppu, &mut board, 261, 1);
+        assert!(ppu.regs.vblank());
+        ppu.step(&mut board, &mut NoScreen);
+        assert_eq!(status(&mut ppu, &mut board) & 0x80, 0);
+    }
+
+    #[test]
+    fn nmi_output() {
+        let (mut ppu, mut board) = setup();
+        ppu.reg_storeb(&mut board, 0x2000, 0x80);
+        run_to(&mut ppu, &mut board, 241, 2);
+        assert!(ppu.nmi_output());
+        ppu.reg_storeb(&mut board, 0x2000, 0x00);
+        assert!(!ppu.nmi_output());
+        ppu.reg_storeb(&mut board, 0x2000, 0x80);
+        assert!(ppu.nmi_output());
+        status(&mut ppu, &mut board);
+        assert!(!ppu.nmi_output());
+    }
 }
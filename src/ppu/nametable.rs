@@ -1,29 +1,84 @@
-use core::ops::{Index, IndexMut};
+use crate::state::{Reader, State, Writer};
+
+/// How the 4 nametables $2000, $2400, $2800 and $2C00 are mapped
+/// onto the 2 KB of VRAM (CIRAM) inside the console, and the 2 KB more
+/// of four screen boards.
+/// see http://wiki.nesdev.com/w/index.php/Mirroring
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mirroring {
+    /// $2000 = $2400 and $2800 = $2C00, used by vertical scrolling games
+    Horizontal,
+    /// $2000 = $2800 and $2400 = $2C00, used by horizontal scrolling games
+    Vertical,
+    /// All nametables use the first 1 KB
+    OneScreenLower,
+    /// All nametables use the second 1 KB
+    OneScreenUpper,
+    /// Every nametable has its own 1 KB, the board adds 2 KB of VRAM
+    FourScreen,
+    /// 1 KB page of each nametable, set at runtime by the mapper.
+    /// Pages 0 and 1 are CIRAM, 2 and 3 the VRAM of four screen boards.
+    Custom([u8; 4]),
+}
+
+impl State for Mirroring {
+    fn save(&self, w: &mut Writer) {
+        let (kind, pages) = match self {
+            Mirroring::Horizontal => (0u8, [0; 4]),
+            Mirroring::Vertical => (1, [0; 4]),
+            Mirroring::OneScreenLower => (2, [0; 4]),
+            Mirroring::OneScreenUpper => (3, [0; 4]),
+            Mirroring::FourScreen => (4, [0; 4]),
+            Mirroring::Custom(pages) => (5, *pages),
+        };
+        kind.save(w);
+        pages.save(w);
+    }
+    fn load(&mut self, r: &mut Reader) {
+        let (mut kind, mut pages) = (0u8, [0u8; 4]);
+        kind.load(r);
+        pages.load(r);
+        *self = match kind {
+            0 => Mirroring::Horizontal,
+            1 => Mirroring::Vertical,
+            2 => Mirroring::OneScreenLower,
+            3 => Mirroring::OneScreenUpper,
+            4 => Mirroring::FourScreen,
+            _ => Mirroring::Custom(pages),
+        };
+    }
+}
+
+/// CIRAM followed by the extra VRAM of four screen boards. That VRAM is on
+/// the cartridge, it lives here so that any board can map it with `Mirroring`.
 pub struct NameTable {
-    inner: [u8; 0x800],
+    inner: [u8; 0x1000],
 }
+
+state!(NameTable { inner });
 impl NameTable {
     pub fn new() -> NameTable {
         NameTable {
-            inner: [0u8; 0x800],
+            inner: [0u8; 0x1000],
         }
     }
-    pub fn addr(&self, addr: u16) -> usize {
-        let addr = addr as usize;
-        addr & 0x3FF + if addr < 0x2800 { 0 } else { 0x400 }
+    /// Offset in VRAM of a $2000-$3EFF address
+    pub fn addr(addr: u16, mirroring: Mirroring) -> usize {
+        let table = (addr >> 10) & 0x3;
+        let page = match mirroring {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical => table & 0x1,
+            Mirroring::OneScreenLower => 0,
+            Mirroring::OneScreenUpper => 1,
+            Mirroring::FourScreen => table,
+            Mirroring::Custom(pages) => (pages[table as usize] & 0x3) as u16,
+        };
+        ((page as usize) << 10) | (addr as usize & 0x3FF)
     }
-}
-
-impl Index<u16> for NameTable {
-    type Output = u8;
-    fn index(&self, index: u16) -> &u8 {
-        &self.inner[self.addr(index)]
+    pub fn loadb(&self, addr: u16, mirroring: Mirroring) -> u8 {
+        self.inner[Self::addr(addr, mirroring)]
     }
-}
-
-impl IndexMut<u16> for NameTable {
-    fn index_mut(&mut self, index: u16) -> &mut u8 {
-        let addr = self.addr(index);
-        &mut self.inner[addr]
+    pub fn storeb(&mut self, addr: u16, mirroring: Mirroring, val: u8) {
+        self.inner[Self::addr(addr, mirroring)] = val;
     }
 }
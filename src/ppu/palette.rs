@@ -2,6 +2,8 @@ use core::ops::{Index, IndexMut};
 pub struct Palette {
     inner: [u8; 0x20],
 }
+
+state!(Palette { inner });
 impl Index<u16> for Palette {
     type Output = u8;
     fn index(&self, index: u16) -> &u8 {
@@ -32,8 +34,8 @@ impl Palette {
     pub fn get_color_by_index(&self, index: u8) -> Option<(u8, u8, u8)> {
         match index {
             0x10 | 0x14 | 0x18 | 0x1C => None,
-            0x00 | 0x04 | 0x08 | 0x0C => Some(PALETTE[self.inner[0] as usize]),
-            _ => Some(PALETTE[self.inner[index as usize] as usize]),
+            0x00 | 0x04 | 0x08 | 0x0C => Some(PALETTE[self.inner[0] as usize & 0x3F]),
+            _ => Some(PALETTE[self.inner[index as usize] as usize & 0x3F]),
         }
     }
 }
@@ -1,4 +1,5 @@
 use super::PPU;
+use crate::mapper::{open_bus, Mapper};
 bitflags! {
     /// PPUCTRL $2000  VPHB SINN Write Only
     struct PPUCTRL:u8{
@@ -19,6 +20,9 @@ bitflags! {
         const NL = 1 << 0;
     }
 }
+
+state_bits!(PPUCTRL);
+
 impl Regs {
     #[inline]
     pub fn nmi_enable(&self) -> bool {
@@ -48,10 +52,6 @@ impl Regs {
             0x0
         }
     }
-    #[inline]
-    pub fn nametable_addr(&self) -> u16 {
-        0x2000 + ((self.ctrl.bits() as u16 & 0x3) << 10)
-    }
 }
 bitflags! {
     /// PPUMASK $2001  BGRs bMmG Write Only
@@ -76,6 +76,8 @@ bitflags! {
     }
 }
 
+state_bits!(PPUMASK);
+
 bitflags! {
     /// PPUSTATUS $2002 VSO- ---- Read Only
     struct PPUSTATUS:u8{
@@ -88,6 +90,31 @@ bitflags! {
     }
 }
 
+state_bits!(PPUSTATUS);
+
+impl Regs {
+    #[inline]
+    pub fn show_background(&self) -> bool {
+        self.mask.contains(PPUMASK::BE)
+    }
+    #[inline]
+    pub fn show_sprites(&self) -> bool {
+        self.mask.contains(PPUMASK::SE)
+    }
+    #[inline]
+    pub fn show_background_left(&self) -> bool {
+        self.mask.contains(PPUMASK::BL)
+    }
+    #[inline]
+    pub fn show_sprites_left(&self) -> bool {
+        self.mask.contains(PPUMASK::SL)
+    }
+    #[inline]
+    pub fn rendering(&self) -> bool {
+        self.mask.intersects(PPUMASK::BE | PPUMASK::SE)
+    }
+}
+
 impl Regs {
     #[inline]
     pub fn vblank_start(&mut self) {
@@ -101,6 +128,18 @@ impl Regs {
     pub fn vblank(&self) -> bool {
         self.status.contains(PPUSTATUS::V)
     }
+    #[inline]
+    pub fn set_sprite_zero_hit(&mut self) {
+        self.status.insert(PPUSTATUS::S)
+    }
+    #[inline]
+    pub fn set_sprite_overflow(&mut self) {
+        self.status.insert(PPUSTATUS::O)
+    }
+    #[inline]
+    pub fn clear_sprite_flags(&mut self) {
+        self.status.remove(PPUSTATUS::S | PPUSTATUS::O)
+    }
 }
 
 pub struct Regs {
@@ -136,6 +175,18 @@ pub struct Regs {
     ppudata_buffer: u8,
 }
 
+state!(Regs {
+    ctrl,
+    mask,
+    status,
+    oam_addr,
+    v,
+    t,
+    x,
+    w,
+    ppudata_buffer
+});
+
 impl Regs {
     pub fn new() -> Regs {
         Regs {
@@ -152,23 +203,88 @@ impl Regs {
     }
 }
 
-impl<'a> PPU<'a> {
-    pub fn reg_loadb(&mut self, addr: u16) -> u8 {
+/// Scrolling operations on `v` done by the rendering pipeline
+impl Regs {
+    #[inline]
+    pub fn fine_x(&self) -> u8 {
+        self.x
+    }
+    #[inline]
+    pub fn fine_y(&self) -> u16 {
+        (self.v >> 12) & 0x7
+    }
+    /// Address of the nametable byte of current tile
+    #[inline]
+    pub fn tile_addr(&self) -> u16 {
+        0x2000 | (self.v & 0x0FFF)
+    }
+    /// Address of the attribute byte of current tile
+    #[inline]
+    pub fn attr_addr(&self) -> u16 {
+        0x23C0 | (self.v & 0x0C00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07)
+    }
+    /// Position of current tile's 2 bits inside the attribute byte
+    #[inline]
+    pub fn attr_shift(&self) -> u8 {
+        (((self.v >> 4) & 0x4) | (self.v & 0x2)) as u8
+    }
+    pub fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            // switch horizontal nametable
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+    pub fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+        } else {
+            self.v &= !0x7000;
+            let mut y = (self.v & 0x03E0) >> 5;
+            if y == 29 {
+                y = 0;
+                // switch vertical nametable
+                self.v ^= 0x0800;
+            } else if y == 31 {
+                // coarse Y out of bounds wraps without switching nametable
+                y = 0;
+            } else {
+                y += 1;
+            }
+            self.v = (self.v & !0x03E0) | (y << 5);
+        }
+    }
+    /// OAMADDR is set to 0 during sprite fetches
+    #[inline]
+    pub fn clear_oam_addr(&mut self) {
+        self.oam_addr = 0;
+    }
+    #[inline]
+    pub fn copy_x(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+    #[inline]
+    pub fn copy_y(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+}
+
+impl PPU {
+    pub fn reg_loadb<M: Mapper>(&mut self, mapper: &mut M, addr: u16) -> u8 {
         assert!(addr >= 0x2000);
         assert!(addr < 0x4000);
         match addr & 0x7 {
-            0 => unimplemented!("Write Only"),
-            1 => unimplemented!("Write Only"),
+            // Write only
+            0 | 1 | 3 | 5 | 6 => open_bus(addr),
             2 => {
                 let data = self.regs.status.bits();
                 self.regs.vblank_end();
                 self.regs.w = false;
                 data
             }
-            3 => unimplemented!("Write Only"),
             4 => self.oam[self.regs.oam_addr as usize],
-            5 => unimplemented!("Write Only"),
-            6 => unimplemented!("Write Only"),
             7 => {
                 let addr = self.regs.v;
                 self.regs.v = self
@@ -181,17 +297,17 @@ impl<'a> PPU<'a> {
                     });
                 if addr < 0x3F00 {
                     let data = self.regs.ppudata_buffer;
-                    self.regs.ppudata_buffer = self.loadb(addr);
+                    self.regs.ppudata_buffer = self.loadb(mapper, addr);
                     data
                 } else {
-                    self.regs.ppudata_buffer = self.loadb(addr - 0x1000);
-                    self.loadb(addr)
+                    self.regs.ppudata_buffer = self.loadb(mapper, addr - 0x1000);
+                    self.loadb(mapper, addr)
                 }
             }
             _ => unreachable!(),
         }
     }
-    pub fn reg_storeb(&mut self, addr: u16, val: u8) {
+    pub fn reg_storeb<M: Mapper>(&mut self, mapper: &mut M, addr: u16, val: u8) {
         assert!(addr >= 0x2000);
         assert!(addr < 0x4000);
         match addr & 0x7 {
@@ -200,7 +316,8 @@ impl<'a> PPU<'a> {
                 self.regs.t = (self.regs.t & (!(0x3 << 10))) | ((val as u16 & 0x3) << 10);
             }
             1 => self.regs.mask = PPUMASK::from_bits_truncate(val),
-            2 => unimplemented!("READ ONLY"),
+            // PPUSTATUS is read only
+            2 => {}
             3 => self.regs.oam_addr = val,
             4 => {
                 self.oam[self.regs.oam_addr as usize] = val;
@@ -208,7 +325,7 @@ impl<'a> PPU<'a> {
             }
             5 => {
                 if !self.regs.w {
-                    self.regs.t = (self.regs.t & (!0x11)) | (val as u16 >> 3);
+                    self.regs.t = (self.regs.t & (!0x1F)) | (val as u16 >> 3);
                     self.regs.x = val & 0x7;
                 } else {
                     self.regs.t = (self.regs.t & 0xC1F)
@@ -223,12 +340,13 @@ impl<'a> PPU<'a> {
                 } else {
                     self.regs.t = (self.regs.t & 0xFF00) | (val as u16);
                     self.regs.v = self.regs.t;
+                    mapper.ppu_addr(self.regs.v);
                 }
                 self.regs.w = !self.regs.w;
             }
             7 => {
                 let addr = self.regs.v;
-                self.storeb(addr, val);
+                self.storeb(mapper, addr, val);
                 self.regs.v = self
                     .regs
                     .v
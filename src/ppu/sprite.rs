@@ -0,0 +1,235 @@
+use super::PPU;
+use crate::mapper::Mapper;
+
+bitflags! {
+    /// Byte 2 of an OAM entry  VHP- --PP
+    struct Attr:u8{
+        /// 7 flip sprite vertically
+        const V = 1 << 7;
+        /// 6 flip sprite horizontally
+        const H = 1 << 6;
+        /// 5 priority 0(in front of background) 1(behind background)
+        const P = 1 << 5;
+        /// 0,1 palette of sprite (4 to 7)
+        const PH = 1 << 1;
+        const PL = 1 << 0;
+    }
+}
+
+state_bits!(Attr);
+
+/// One of the 8 sprite output units
+#[derive(Clone, Copy)]
+struct Slot {
+    x: u8,
+    attr: Attr,
+    /// Bit planes of current row, already flipped horizontally
+    low: u8,
+    high: u8,
+}
+
+state!(Slot { x, attr, low, high });
+
+impl Slot {
+    fn empty() -> Slot {
+        Slot {
+            x: 0xFF,
+            attr: Attr::empty(),
+            low: 0,
+            high: 0,
+        }
+    }
+}
+
+/// Opaque sprite pixel selected by the priority mux
+pub struct SpritePixel {
+    /// 4 bit index into the sprite palettes
+    pub index: u8,
+    /// Sprite is behind the background
+    pub behind: bool,
+    /// Pixel belongs to sprite 0
+    pub zero: bool,
+}
+
+/// Sprite evaluation and output units.
+/// see http://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+pub struct Sprites {
+    /// Secondary OAM, sprites found for the next scanline
+    secondary: [u8; 0x20],
+    /// Number of sprites in secondary OAM
+    found: usize,
+    /// Sprite 0 is in secondary OAM
+    zero_found: bool,
+    /// Dot of current scanline where the evaluation sets the overflow flag
+    overflow_dot: Option<usize>,
+    slots: [Slot; 8],
+    /// Number of sprites drawn on current scanline
+    count: usize,
+    /// Sprite 0 is drawn on current scanline
+    zero: bool,
+}
+
+state!(Sprites {
+    secondary,
+    found,
+    zero_found,
+    overflow_dot,
+    slots,
+    count,
+    zero
+});
+
+impl Sprites {
+    pub fn new() -> Sprites {
+        Sprites {
+            secondary: [0xFF; 0x20],
+            found: 0,
+            zero_found: false,
+            overflow_dot: None,
+            slots: [Slot::empty(); 8],
+            count: 0,
+            zero: false,
+        }
+    }
+    /// Front most opaque sprite pixel at `x` of current scanline
+    pub fn pixel(&self, x: u16) -> Option<SpritePixel> {
+        for (i, slot) in self.slots[..self.count].iter().enumerate() {
+            let dx = x.wrapping_sub(slot.x as u16);
+            if dx >= 8 {
+                continue;
+            }
+            let bit = 7 - dx;
+            let pattern = (slot.low >> bit) & 1 | ((slot.high >> bit) & 1) << 1;
+            if pattern != 0 {
+                return Some(SpritePixel {
+                    index: (slot.attr.bits() & 0x3) << 2 | pattern,
+                    behind: slot.attr.contains(Attr::P),
+                    zero: i == 0 && self.zero,
+                });
+            }
+        }
+        None
+    }
+}
+
+impl PPU {
+    /// Run the sprite half of the pipeline for current dot of a render scanline.
+    /// Must only be called when rendering is enabled.
+    pub(super) fn fetch_sprites<M: Mapper>(&mut self, mapper: &mut M) {
+        let dot = self.cycles;
+        if dot == 65 {
+            if self.scanline == super::LAST_SCANLINE {
+                // No sprite is evaluated on pre-render scanline
+                self.sprites.found = 0;
+                self.sprites.zero_found = false;
+                self.sprites.overflow_dot = None;
+            } else {
+                self.evaluate_sprites();
+            }
+        }
+        if self.sprites.overflow_dot == Some(dot) {
+            self.regs.set_sprite_overflow();
+        }
+        if (257..=320).contains(&dot) {
+            self.regs.clear_oam_addr();
+            if dot == 257 {
+                self.sprites.count = self.sprites.found;
+                self.sprites.zero = self.sprites.zero_found;
+            }
+            let n = (dot - 257) / 8;
+            match (dot - 257) % 8 {
+                // Garbage nametable fetches, boards like the MMC5 count them
+                0 | 2 => {
+                    self.loadb(mapper, self.regs.tile_addr());
+                }
+                4 => {
+                    let addr = self.sp_pattern_addr(n);
+                    self.sprites.slots[n].low = self.loadb(mapper, addr);
+                }
+                6 => {
+                    let addr = self.sp_pattern_addr(n) + 8;
+                    self.sprites.slots[n].high = self.loadb(mapper, addr);
+                }
+                7 => self.load_slot(n),
+                _ => {}
+            }
+        }
+    }
+    /// Fill secondary OAM with sprites in range of the next scanline.
+    /// The whole evaluation of dots 65-256 is done at once, only the
+    /// overflow flag is delayed to the dot the hardware would set it.
+    fn evaluate_sprites(&mut self) {
+        let height = self.regs.sprite_height() as u16;
+        let scanline = self.scanline;
+        let in_range = |y: u8| scanline.wrapping_sub(y as u16) < height;
+        self.sprites.secondary = [0xFF; 0x20];
+        self.sprites.found = 0;
+        self.sprites.zero_found = false;
+        self.sprites.overflow_dot = None;
+        // Every OAM read takes 2 dots, copying the other 3 bytes takes 6 more
+        let mut dot = 65;
+        let mut n = 0;
+        while n < 64 && self.sprites.found < 8 {
+            if in_range(self.oam[n * 4]) {
+                let found = self.sprites.found;
+                self.sprites.secondary[found * 4..found * 4 + 4]
+                    .copy_from_slice(&self.oam[n * 4..n * 4 + 4]);
+                self.sprites.zero_found |= n == 0;
+                self.sprites.found += 1;
+                dot += 6;
+            }
+            dot += 2;
+            n += 1;
+        }
+        // Once 8 sprites are found, the PPU increments the byte index `m`
+        // together with the sprite index `n`, so tile, attribute and X bytes
+        // are compared as Y coordinates in a diagonal pattern.
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam[n * 4 + m]) {
+                self.sprites.overflow_dot = Some(dot);
+                break;
+            }
+            dot += 2;
+            n += 1;
+            m = (m + 1) & 0x3;
+        }
+    }
+    /// Pattern address of the row of `n`th sprite in secondary OAM.
+    /// Empty slots fetch tile $FF like the real PPU does.
+    fn sp_pattern_addr(&self, n: usize) -> u16 {
+        let entry = &self.sprites.secondary[n * 4..n * 4 + 4];
+        let attr = Attr::from_bits_truncate(entry[2]);
+        let tile = entry[1] as u16;
+        let mut row = if n < self.sprites.found {
+            self.scanline.wrapping_sub(entry[0] as u16)
+        } else {
+            0
+        };
+        if attr.contains(Attr::V) {
+            row = self.regs.sprite_height() as u16 - 1 - row;
+        }
+        if self.regs.sprite_height() == 16 {
+            let table = (tile & 1) << 12;
+            let tile = (tile & 0xFE) + (row >> 3);
+            table + (tile << 4) + (row & 0x7)
+        } else {
+            self.regs.sp_chr_addr() + (tile << 4) + row
+        }
+    }
+    /// Move the fetched sprite into its output unit
+    fn load_slot(&mut self, n: usize) {
+        let slot = &mut self.sprites.slots[n];
+        if n >= self.sprites.found {
+            *slot = Slot::empty();
+            return;
+        }
+        let entry = &self.sprites.secondary[n * 4..n * 4 + 4];
+        slot.attr = Attr::from_bits_truncate(entry[2]);
+        slot.x = entry[3];
+        if slot.attr.contains(Attr::H) {
+            slot.low = slot.low.reverse_bits();
+            slot.high = slot.high.reverse_bits();
+        }
+    }
+}
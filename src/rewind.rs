@@ -0,0 +1,345 @@
+//! Rewind history. Every few frames the machine is saved with `save_state`
+//! and the XOR of the previous snapshot and the new one goes into a ring of
+//! deltas. Most of the machine doesn't change in a few frames, so a delta is
+//! mostly zeros and compresses to a few hundred bytes: runs of zeros become a
+//! length, the bytes that changed are kept as they are.
+use crate::state::StateError;
+
+/// Captures and steps back through states, in a caller provided buffer
+pub struct Rewind<'b> {
+    /// State captured last, rewinding loads it
+    snapshot: &'b mut [u8],
+    /// The state being captured
+    next: &'b mut [u8],
+    /// Deltas, each one stored as length, data, length so the ring can be
+    /// walked from both ends
+    ring: &'b mut [u8],
+    /// Start of the oldest delta
+    tail: usize,
+    /// End of the newest delta
+    head: usize,
+    used: usize,
+    deltas: usize,
+    /// Whether `snapshot` holds a state yet
+    captured: bool,
+    interval: u32,
+    /// Since the last capture
+    frames: u32,
+}
+
+impl<'b> Rewind<'b> {
+    /// Capture states of `size` bytes every `interval` frames into `buf`.
+    /// It needs room for two states, the rest holds the deltas.
+    pub fn new(buf: &'b mut [u8], size: usize, interval: u32) -> Result<Rewind<'b>, StateError> {
+        if buf.len() < size * 2 {
+            return Err(StateError::BufferTooSmall);
+        }
+        let (snapshot, rest) = buf.split_at_mut(size);
+        let (next, ring) = rest.split_at_mut(size);
+        Ok(Rewind {
+            snapshot,
+            next,
+            ring,
+            tail: 0,
+            head: 0,
+            used: 0,
+            deltas: 0,
+            captured: false,
+            interval: interval.max(1),
+            frames: 0,
+        })
+    }
+    /// Drop the history, the next capture starts it again
+    pub fn clear(&mut self) {
+        self.tail = 0;
+        self.head = 0;
+        self.used = 0;
+        self.deltas = 0;
+        self.captured = false;
+        self.frames = 0;
+    }
+    /// Called once every frame, true when a state should be captured
+    pub fn tick(&mut self) -> bool {
+        self.frames += 1;
+        self.frames >= self.interval
+    }
+    /// Where to save the state to capture
+    pub fn next_mut(&mut self) -> &mut [u8] {
+        self.next
+    }
+    /// Keep the state saved into `next_mut`
+    pub fn push(&mut self) {
+        self.frames = 0;
+        if !self.captured {
+            self.snapshot.copy_from_slice(self.next);
+            self.captured = true;
+            return;
+        }
+        let mut size = 0;
+        encode(self.snapshot, self.next, |_| size += 1);
+        // Length before and after the data
+        let needed = size + 8;
+        while self.deltas > 0 && self.used + needed > self.ring.len() {
+            self.drop_oldest();
+        }
+        if needed <= self.ring.len() {
+            self.put_length(size);
+            let (ring, mut head) = (&mut *self.ring, self.head);
+            encode(self.snapshot, self.next, |byte| {
+                ring[head] = byte;
+                head = (head + 1) % ring.len();
+            });
+            self.head = head;
+            self.put_length(size);
+            self.used += needed;
+            self.deltas += 1;
+        }
+        self.snapshot.copy_from_slice(self.next);
+    }
+    /// Go back one capture, true if there is a state to load in `snapshot`.
+    /// Within a frame of the last capture, that's the one before it.
+    pub fn step(&mut self) -> bool {
+        if !self.captured {
+            return false;
+        }
+        if self.frames <= 1 {
+            if self.deltas == 0 {
+                return false;
+            }
+            self.pop_newest();
+        }
+        // A frame is run from the snapshot to draw it
+        self.frames = 1;
+        true
+    }
+    /// State captured last
+    pub fn snapshot(&self) -> &[u8] {
+        self.snapshot
+    }
+    fn pop_newest(&mut self) {
+        let size = self.get_length(self.head + self.ring.len() - 4);
+        let start = (self.head + self.ring.len() * 2 - 4 - size) % self.ring.len();
+        let ring = &*self.ring;
+        let mut pos = start;
+        decode(self.snapshot, || {
+            let byte = ring[pos];
+            pos = (pos + 1) % ring.len();
+            byte
+        });
+        self.head = (start + self.ring.len() - 4) % self.ring.len();
+        self.used -= size + 8;
+        self.deltas -= 1;
+    }
+    fn drop_oldest(&mut self) {
+        let size = self.get_length(self.tail);
+        self.tail = (self.tail + size + 8) % self.ring.len();
+        self.used -= size + 8;
+        self.deltas -= 1;
+    }
+    fn put_length(&mut self, size: usize) {
+        for byte in (size as u32).to_le_bytes().iter() {
+            self.ring[self.head] = *byte;
+            self.head = (self.head + 1) % self.ring.len();
+        }
+    }
+    fn get_length(&self, pos: usize) -> usize {
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.ring[(pos + i) % self.ring.len()];
+        }
+        u32::from_le_bytes(bytes) as usize
+    }
+}
+
+/// Write the XOR of `old` and `new` to `out` as pairs of runs: the number of
+/// zeros, then the number of changed bytes followed by them. Lengths are
+/// LEB128 so long runs of zeros stay short.
+fn encode(old: &[u8], new: &[u8], mut out: impl FnMut(u8)) {
+    let delta = |i: usize| old[i] ^ new[i];
+    let len = old.len();
+    let mut i = 0;
+    while i < len {
+        let zeros = i;
+        while i < len && delta(i) == 0 {
+            i += 1;
+        }
+        leb128(i - zeros, &mut out);
+        let changed = i;
+        // A lone zero is cheaper to keep than to start another pair for
+        while i < len && (delta(i) != 0 || (i + 1 < len && delta(i + 1) != 0)) {
+            i += 1;
+        }
+        leb128(i - changed, &mut out);
+        (changed..i).for_each(|j| out(delta(j)));
+    }
+}
+
+fn leb128(mut val: usize, out: &mut impl FnMut(u8)) {
+    while val >= 0x80 {
+        out(val as u8 | 0x80);
+        val >>= 7;
+    }
+    out(val as u8);
+}
+
+/// XOR a delta written by `encode` into `data`, `next` returns its bytes
+fn decode(data: &mut [u8], mut next: impl FnMut() -> u8) {
+    let mut i = 0;
+    while i < data.len() {
+        i += read_leb128(&mut next);
+        let changed = read_leb128(&mut next);
+        for byte in data[i..i + changed].iter_mut() {
+            *byte ^= next();
+        }
+        i += changed;
+    }
+}
+
+fn read_leb128(next: &mut impl FnMut() -> u8) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let byte = next();
+        val |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return val;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that `old` XOR the encoded delta gives `new`, return its size
+    fn round_trip<const N: usize>(old: &[u8; N], new: &[u8; N]) -> usize {
+        let mut delta = [0; 1024];
+        let mut size = 0;
+        encode(old, new, |byte| {
+            delta[size] = byte;
+            size += 1;
+        });
+        let mut data = *old;
+        let mut pos = 0;
+        decode(&mut data, || {
+            pos += 1;
+            delta[pos - 1]
+        });
+        assert_eq!(&data[..], &new[..]);
+        assert_eq!(pos, size);
+        size
+    }
+
+    #[test]
+    fn unchanged() {
+        // 300 zeros is 2 LEB128 bytes, then no changed bytes
+        assert_eq!(round_trip(&[7; 300], &[7; 300]), 3);
+    }
+
+    #[test]
+    fn all_changed() {
+        let old = [0x55; 200];
+        let mut new = [0; 200];
+        new.iter_mut()
+            .enumerate()
+            .for_each(|(i, b)| *b = i as u8 | 0x80);
+        assert_eq!(round_trip(&old, &new), 1 + 2 + 200);
+    }
+
+    #[test]
+    fn isolated_zeros() {
+        let old = [0; 8];
+        // The lone zero stays in the changed run, the pair ends it
+        let new = [1, 0, 2, 3, 0, 0, 4, 0];
+        assert_eq!(round_trip(&old, &new), (2 + 4) + (2 + 1) + 2);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(round_trip(&[], &[]), 0);
+    }
+
+    const SIZE: usize = 64;
+    const STATES: usize = 30;
+
+    /// Each state changes a few bytes of the one before
+    fn states() -> [[u8; SIZE]; STATES] {
+        let mut states = [[0; SIZE]; STATES];
+        for i in 1..STATES {
+            states[i] = states[i - 1];
+            states[i][i * 7 % SIZE] ^= i as u8;
+            states[i][i * 13 % SIZE] ^= 0xA5;
+            states[i][(i * 13 + 1) % SIZE] = i as u8;
+        }
+        states
+    }
+
+    fn capture(rewind: &mut Rewind, state: &[u8]) {
+        rewind.next_mut().copy_from_slice(state);
+        rewind.push();
+    }
+
+    #[test]
+    fn step_back_through_wrapped_ring() {
+        let states = states();
+        let mut buf = [0; SIZE * 2 + 100];
+        let mut rewind = Rewind::new(&mut buf, SIZE, 1).unwrap();
+        let mut wrapped = false;
+        for state in states.iter() {
+            capture(&mut rewind, state);
+            wrapped |= rewind.head < rewind.tail;
+        }
+        // Old deltas were dropped to make room and the newest ones wrap around
+        assert!(rewind.deltas < STATES - 1);
+        assert!(wrapped);
+        let kept = rewind.deltas;
+        for state in states[..STATES - 1].iter().rev().take(kept) {
+            assert!(rewind.step());
+            assert_eq!(rewind.snapshot(), &state[..]);
+        }
+        assert!(!rewind.step());
+        assert_eq!(rewind.snapshot(), &states[STATES - 1 - kept][..]);
+    }
+
+    #[test]
+    fn step_returns_to_last_capture_first() {
+        let states = states();
+        let mut buf = [0; SIZE * 2 + 1000];
+        let mut rewind = Rewind::new(&mut buf, SIZE, 4).unwrap();
+        capture(&mut rewind, &states[0]);
+        capture(&mut rewind, &states[1]);
+        // Frames ran since the capture, go back to it
+        assert!(!rewind.tick());
+        assert!(!rewind.tick());
+        assert!(rewind.step());
+        assert_eq!(rewind.snapshot(), &states[1][..]);
+        assert!(rewind.step());
+        assert_eq!(rewind.snapshot(), &states[0][..]);
+        assert!(!rewind.step());
+        // The history grows again from there
+        capture(&mut rewind, &states[2]);
+        assert!(rewind.step());
+        assert_eq!(rewind.snapshot(), &states[0][..]);
+    }
+
+    #[test]
+    fn no_history() {
+        let mut buf = [0; SIZE * 2];
+        let mut rewind = Rewind::new(&mut buf, SIZE, 1).unwrap();
+        assert!(!rewind.step());
+        capture(&mut rewind, &[1; SIZE]);
+        // No room for deltas, only the last capture is kept
+        capture(&mut rewind, &[2; SIZE]);
+        assert!(!rewind.step());
+        assert_eq!(rewind.snapshot(), &[2; SIZE][..]);
+        rewind.clear();
+        assert!(!rewind.step());
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buf = [0; SIZE * 2 - 1];
+        assert!(Rewind::new(&mut buf, SIZE, 1).is_err());
+    }
+}
@@ -1,5 +1,4 @@
 use core::fmt;
-use crate::mapper::{PRG,CHR};
 
 pub struct Rom<'a> {
     /// 16 bytes
@@ -14,56 +13,205 @@ pub struct Rom<'a> {
     // (or sometimes 127-byte) title at the end of the file.
 }
 
-impl<'a> Rom<'a> {
-    pub fn split(self) -> (PRG<'a>, CHR<'a>, NesHeader) {
-        let mapper = self.header.mapper();
-        (
-            PRG::new(self.prg, mapper),
-            CHR::new(self.chr, mapper),
-            self.header,
-        )
+/// Why a ROM or disk image can't be loaded
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RomError {
+    /// The file doesn't start with "NES\x1a"
+    InvalidMagic,
+    /// The file is shorter than the 16 bytes header
+    TruncatedHeader,
+    /// The file ends before the trainer or PRG-ROM told by the header
+    TruncatedPrg {
+        expected: usize,
+        found: usize,
+    },
+    /// The file ends before the CHR-ROM told by the header
+    TruncatedChr {
+        expected: usize,
+        found: usize,
+    },
+    UnsupportedMapper(u16),
+    /// Header sizes which can't be right, e.g. no PRG-ROM at all
+    InconsistentSizes,
+    /// The Famicom Disk System BIOS isn't 8 KB
+    InvalidBios,
+    /// The .fds image has no side, or a side without a disk info block
+    InvalidDisk,
+    /// The RAM given for the cartridge is smaller than its PRG-RAM and CHR-RAM
+    RamTooSmall {
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            RomError::InvalidMagic => write!(f, "invalid NES format header"),
+            RomError::TruncatedHeader => write!(f, "file is too small for a NES header"),
+            RomError::TruncatedPrg { expected, found } => write!(
+                f,
+                "PRG-ROM is truncated, expected {} bytes, found {}",
+                expected, found
+            ),
+            RomError::TruncatedChr { expected, found } => write!(
+                f,
+                "CHR-ROM is truncated, expected {} bytes, found {}",
+                expected, found
+            ),
+            RomError::UnsupportedMapper(id) => write!(f, "mapper {} is not supported", id),
+            RomError::InconsistentSizes => write!(f, "ROM sizes in the header are inconsistent"),
+            RomError::InvalidBios => write!(f, "disk BIOS must be 8 KB"),
+            RomError::InvalidDisk => write!(f, "invalid FDS disk image"),
+            RomError::RamTooSmall { expected, found } => write!(
+                f,
+                "cartridge RAM is too small, expected {} bytes, found {}",
+                expected, found
+            ),
+        }
     }
-    pub fn load(reader: &'a [u8]) -> Rom<'a> {
-        let mut bytes = 0;
-        let header = &reader[0..16];
-        assert_eq!(header[8], 0);
-        assert_eq!(header[9], 0);
-        assert_eq!(header[10], 0);
-        let header = NesHeader {
-            magic: [header[0], header[1], header[2], header[3]],
-            prg_rom_size: header[4],
-            chr_rom_size: header[5],
-            flags_6: header[6],
-            flags_7: header[7],
-            flags_8: header[8],
-            zero: [0; 7],
-        };
-        bytes += 16;
+}
+
+impl<'a> Rom<'a> {
+    pub fn load(reader: &'a [u8]) -> Result<Rom<'a>, RomError> {
+        if reader.len() < 16 {
+            return Err(RomError::TruncatedHeader);
+        }
+        let (header, mut data) = reader.split_at(16);
+        let header = NesHeader::parse(header);
         if header.magic != *b"NES\x1a" {
-            panic!("Can't open ROM, invalid NES Format Header");
-        } else {
-            if header.trainer() {
-                warn!("unsupport trainer");
-                bytes += 512;
-            }
-            let prg_bytes = header.prg_rom_size as usize * 16384;
-            let (prg, chr) = (&reader[bytes..]).split_at(prg_bytes);
-            assert!(chr.len() == header.chr_rom_size as usize * 8192);
-            Rom {
-                header,
-                // trainer,
-                prg,
-                chr,
+            return Err(RomError::InvalidMagic);
+        }
+        let prg_bytes = header.prg_rom_bytes();
+        let chr_bytes = header.chr_rom_bytes();
+        if prg_bytes == 0 {
+            return Err(RomError::InconsistentSizes);
+        }
+        if header.trainer() {
+            warn!("unsupport trainer");
+            if data.len() < 512 {
+                return Err(RomError::TruncatedPrg {
+                    expected: prg_bytes.saturating_add(512),
+                    found: data.len(),
+                });
             }
+            data = &data[512..];
+        }
+        if data.len() < prg_bytes {
+            return Err(RomError::TruncatedPrg {
+                expected: prg_bytes,
+                found: data.len(),
+            });
+        }
+        let (prg, chr) = data.split_at(prg_bytes);
+        // Miscellaneous ROMs or a title may follow CHR-ROM
+        if chr.len() < chr_bytes {
+            return Err(RomError::TruncatedChr {
+                expected: chr_bytes,
+                found: chr.len(),
+            });
         }
+        Ok(Rom {
+            header,
+            // trainer,
+            prg,
+            chr: &chr[..chr_bytes],
+        })
     }
 }
 
+/// CPU/PPU timing of the console the game runs on
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Timing {
+    /// RP2C02, North America, Japan, South Korea, Taiwan
+    Ntsc,
+    /// RP2C07, Western Europe, Australia
+    Pal,
+    /// Runs on both
+    Multiple,
+    /// UMC 6527P, Eastern Europe, Russia, Mainland China, India, Africa
+    Dendy,
+}
+
+/// Kind of console the game is made for
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Console {
+    /// Nintendo Entertainment System or Family Computer
+    Nes,
+    /// Nintendo Vs. System, arcade cabinet with RGB PPUs
+    VsSystem,
+    /// Nintendo PlayChoice-10
+    Playchoice10,
+    /// Extended console type of byte 13, e.g. 5 for VR Technology VT01
+    /// see http://wiki.nesdev.com/w/index.php/NES_2.0#Extended_Console_Type
+    Extended(u8),
+}
+
+/// PPU of a Vs. System game, they differ in palettes and register layout
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VsPpu {
+    /// RP2C03B, RP2C03G, RC2C03B or RC2C03C, the same palette as the RP2C02
+    Rp2c03,
+    /// RP2C04-0001 to -0004, scrambled palettes numbered 1 to 4
+    Rp2c04(u8),
+    /// RC2C05-01 to -05, $2000 and $2001 swapped, numbered 1 to 5
+    Rc2c05(u8),
+    Unknown(u8),
+}
+
+/// Vs. System hardware, mostly protection quirks of some games
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VsHardware {
+    Unisystem,
+    /// Vs. Unisystem with the RBI Baseball protection
+    RbiBaseball,
+    /// Vs. Unisystem with the TKO Boxing protection
+    TkoBoxing,
+    /// Vs. Unisystem with the Super Xevious protection
+    SuperXevious,
+    /// Vs. Unisystem with the Vs. Ice Climber Japan protection
+    IceClimber,
+    DualSystem,
+    /// Vs. Dual System with the Raid on Bungeling Bay protection
+    BungelingBay,
+    Unknown(u8),
+}
+
+/// Input device plugged in by default
+/// see http://wiki.nesdev.com/w/index.php/NES_2.0#Default_Expansion_Device
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ExpansionDevice {
+    Unspecified,
+    StandardControllers,
+    /// NES Four Score or Satellite
+    FourScore,
+    /// Famicom four players adapter
+    FamicomFourPlayers,
+    VsSystem,
+    /// Vs. System with reversed inputs
+    VsSystemReversed,
+    VsPinball,
+    VsZapper,
+    Zapper,
+    TwoZappers,
+    BandaiHyperShot,
+    PowerPadSideA,
+    PowerPadSideB,
+    FamilyTrainerSideA,
+    FamilyTrainerSideB,
+    /// Arkanoid Vaus controller, NES version
+    ArkanoidNes,
+    /// Arkanoid Vaus controller, Famicom version
+    ArkanoidFamicom,
+    /// Any other device number of byte 15
+    Other(u8),
+}
+
 pub struct NesHeader {
     /// "NES^Z"
     /// 'N' 'E' 'S' '\x1a'(EOF)
     pub magic: [u8; 4],
-    /// PRG-ROM size in 16 KiB units
+    /// PRG-ROM size in 16 KiB units, LSB of the NES 2.0 size
     pub prg_rom_size: u8,
     /// $5
     /// number of 8K units of CHR-ROM
@@ -93,20 +241,229 @@ pub struct NesHeader {
     ///     |||| ++++- Mapper number D8..D11
     ///     ++++------ Submapper number
     pub flags_8: u8,
-    pub zero: [u8; 7],
+    /// CCCC PPPP
+    ///
+    /// * C: CHR-ROM size MSB
+    /// * P: PRG-ROM size MSB
+    ///
+    /// An MSB of $F means the LSB is EEEE EEMM, a size of 2^E * (MM * 2 + 1) bytes
+    pub flags_9: u8,
+    /// NNNN RRRR
+    ///
+    /// * N: PRG-NVRAM (battery backed) shift count
+    /// * R: PRG-RAM shift count
+    ///
+    /// A shift count S means 64 << S bytes, 0 means none
+    pub flags_10: u8,
+    /// NNNN RRRR
+    ///
+    /// * N: CHR-NVRAM shift count
+    /// * R: CHR-RAM shift count
+    pub flags_11: u8,
+    /// ---- --TT
+    ///
+    /// * T: CPU/PPU timing, 0: NTSC, 1: PAL, 2: multiple regions, 3: Dendy
+    pub flags_12: u8,
+    /// HHHH PPPP
+    ///
+    /// * H: Vs. System hardware type
+    /// * P: Vs. System PPU type
+    ///
+    /// Or the extended console type in the low nibble
+    pub flags_13: u8,
+    /// ---- --RR
+    ///
+    /// * R: Number of miscellaneous ROMs after CHR-ROM
+    pub flags_14: u8,
+    /// --DD DDDD
+    ///
+    /// * D: Default expansion device
+    pub flags_15: u8,
 }
 
 impl NesHeader {
+    /// Header from the first 16 bytes of a file
+    fn parse(header: &[u8]) -> NesHeader {
+        NesHeader {
+            magic: [header[0], header[1], header[2], header[3]],
+            prg_rom_size: header[4],
+            chr_rom_size: header[5],
+            flags_6: header[6],
+            flags_7: header[7],
+            flags_8: header[8],
+            flags_9: header[9],
+            flags_10: header[10],
+            flags_11: header[11],
+            flags_12: header[12],
+            flags_13: header[13],
+            flags_14: header[14],
+            flags_15: header[15],
+        }
+    }
+
     /// Return the mapper ID.
     pub fn mapper(&self) -> u16 {
         let ines: u8 = (self.flags_7 & 0xf0) | (self.flags_6 >> 4);
         if self.nes2() {
             ines as u16 | ((self.flags_8 as u16 & 0x0F) << 8)
+        } else if self.flags_7 & 0x0C != 0 {
+            // Old dumping tools wrote text like "DiskDude!" from byte 7 on
+            (self.flags_6 >> 4) as u16
         } else {
             ines as u16
         }
     }
 
+    /// NES 2.0 submapper number, 0 for iNES headers
+    pub fn submapper(&self) -> u8 {
+        if self.nes2() {
+            self.flags_8 >> 4
+        } else {
+            0
+        }
+    }
+
+    /// PRG-ROM size in bytes
+    pub fn prg_rom_bytes(&self) -> usize {
+        self.rom_bytes(self.prg_rom_size, self.flags_9 & 0x0F, 0x4000)
+    }
+
+    /// CHR-ROM size in bytes, 0 for boards with CHR-RAM
+    pub fn chr_rom_bytes(&self) -> usize {
+        self.rom_bytes(self.chr_rom_size, self.flags_9 >> 4, 0x2000)
+    }
+
+    fn rom_bytes(&self, lsb: u8, msb: u8, unit: usize) -> usize {
+        if !self.nes2() {
+            lsb as usize * unit
+        } else if msb == 0x0F {
+            let multiplier = (lsb as usize & 0x3) * 2 + 1;
+            1usize
+                .checked_shl(lsb as u32 >> 2)
+                .and_then(|size| size.checked_mul(multiplier))
+                .unwrap_or(usize::MAX)
+        } else {
+            ((msb as usize) << 8 | lsb as usize) * unit
+        }
+    }
+
+    /// Volatile PRG-RAM size in bytes, 0 if absent or not told by an iNES header
+    pub fn prg_ram_bytes(&self) -> usize {
+        self.ram_bytes(self.flags_10 & 0x0F)
+    }
+
+    /// Battery backed PRG-RAM size in bytes
+    pub fn prg_nvram_bytes(&self) -> usize {
+        self.ram_bytes(self.flags_10 >> 4)
+    }
+
+    /// Volatile CHR-RAM size in bytes
+    pub fn chr_ram_bytes(&self) -> usize {
+        self.ram_bytes(self.flags_11 & 0x0F)
+    }
+
+    /// Battery backed CHR-RAM size in bytes
+    pub fn chr_nvram_bytes(&self) -> usize {
+        self.ram_bytes(self.flags_11 >> 4)
+    }
+
+    fn ram_bytes(&self, shift: u8) -> usize {
+        if self.nes2() && shift != 0 {
+            64 << shift
+        } else {
+            0
+        }
+    }
+
+    /// CPU/PPU timing, NTSC for iNES headers
+    pub fn timing(&self) -> Timing {
+        if !self.nes2() {
+            return Timing::Ntsc;
+        }
+        match self.flags_12 & 0x3 {
+            0 => Timing::Ntsc,
+            1 => Timing::Pal,
+            2 => Timing::Multiple,
+            _ => Timing::Dendy,
+        }
+    }
+
+    pub fn console(&self) -> Console {
+        match self.flags_7 & 0x3 {
+            0 => Console::Nes,
+            1 => Console::VsSystem,
+            2 => Console::Playchoice10,
+            _ if self.nes2() => Console::Extended(self.flags_13 & 0x0F),
+            // iNES sets both bits for nothing meaningful
+            _ => Console::Nes,
+        }
+    }
+
+    /// PPU of a Vs. System game, only told by NES 2.0 headers
+    pub fn vs_ppu(&self) -> Option<VsPpu> {
+        if !self.nes2() || self.console() != Console::VsSystem {
+            return None;
+        }
+        Some(match self.flags_13 & 0x0F {
+            0 | 1 | 6 | 7 => VsPpu::Rp2c03,
+            n @ 2..=5 => VsPpu::Rp2c04(n - 1),
+            n @ 8..=0xC => VsPpu::Rc2c05(n - 7),
+            n => VsPpu::Unknown(n),
+        })
+    }
+
+    /// Hardware of a Vs. System game, only told by NES 2.0 headers
+    pub fn vs_hardware(&self) -> Option<VsHardware> {
+        if !self.nes2() || self.console() != Console::VsSystem {
+            return None;
+        }
+        Some(match self.flags_13 >> 4 {
+            0 => VsHardware::Unisystem,
+            1 => VsHardware::RbiBaseball,
+            2 => VsHardware::TkoBoxing,
+            3 => VsHardware::SuperXevious,
+            4 => VsHardware::IceClimber,
+            5 => VsHardware::DualSystem,
+            6 => VsHardware::BungelingBay,
+            n => VsHardware::Unknown(n),
+        })
+    }
+
+    /// Number of miscellaneous ROMs after CHR-ROM
+    pub fn misc_roms(&self) -> u8 {
+        if self.nes2() {
+            self.flags_14 & 0x3
+        } else {
+            0
+        }
+    }
+
+    pub fn expansion_device(&self) -> ExpansionDevice {
+        if !self.nes2() {
+            return ExpansionDevice::Unspecified;
+        }
+        match self.flags_15 & 0x3F {
+            0x00 => ExpansionDevice::Unspecified,
+            0x01 => ExpansionDevice::StandardControllers,
+            0x02 => ExpansionDevice::FourScore,
+            0x03 => ExpansionDevice::FamicomFourPlayers,
+            0x04 => ExpansionDevice::VsSystem,
+            0x05 => ExpansionDevice::VsSystemReversed,
+            0x06 => ExpansionDevice::VsPinball,
+            0x07 => ExpansionDevice::VsZapper,
+            0x08 => ExpansionDevice::Zapper,
+            0x09 => ExpansionDevice::TwoZappers,
+            0x0A => ExpansionDevice::BandaiHyperShot,
+            0x0B => ExpansionDevice::PowerPadSideA,
+            0x0C => ExpansionDevice::PowerPadSideB,
+            0x0D => ExpansionDevice::FamilyTrainerSideA,
+            0x0E => ExpansionDevice::FamilyTrainerSideB,
+            0x0F => ExpansionDevice::ArkanoidNes,
+            0x10 => ExpansionDevice::ArkanoidFamicom,
+            n => ExpansionDevice::Other(n),
+        }
+    }
+
     pub fn four_screen(&self) -> bool {
         (self.flags_6 & 0b1000) != 0
     }
@@ -134,20 +491,194 @@ impl fmt::Display for NesHeader {
         write!(
             f,
             "
+            NES 2.0: {}
             PRG-ROM: {} KB, CHR-ROM: {} KB
-            Mapper ID: {}
+            Mapper ID: {}, Submapper: {}
+            PRG-RAM: {} B, PRG-NVRAM: {} B
+            CHR-RAM: {} B, CHR-NVRAM: {} B
             Support Four Screen: {}
             Have Trainer: {}
             Have Save RAM: {}
             Use Vertical Mirror: {}
+            Timing: {:?}, Console: {:?}
+            Vs. System: {:?} {:?}
+            Misc ROMs: {}, Expansion Device: {:?}
             ",
-            self.prg_rom_size as u32 * 16,
-            self.chr_rom_size as u32 * 8,
+            self.nes2(),
+            self.prg_rom_bytes() / 1024,
+            self.chr_rom_bytes() / 1024,
             self.mapper(),
+            self.submapper(),
+            self.prg_ram_bytes(),
+            self.prg_nvram_bytes(),
+            self.chr_ram_bytes(),
+            self.chr_nvram_bytes(),
             self.four_screen(),
             self.trainer(),
             self.save_ram(),
-            self.vertical_mirror()
+            self.vertical_mirror(),
+            self.timing(),
+            self.console(),
+            self.vs_ppu(),
+            self.vs_hardware(),
+            self.misc_roms(),
+            self.expansion_device()
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(bytes: &[u8; 16]) -> NesHeader {
+        NesHeader::parse(bytes)
+    }
+
+    #[test]
+    fn ines_sizes() {
+        let h = header(b"NES\x1a\x02\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00");
+        assert!(!h.nes2());
+        assert_eq!(h.prg_rom_bytes(), 0x8000);
+        assert_eq!(h.chr_rom_bytes(), 0x2000);
+        // RAM sizes are only told by NES 2.0
+        assert_eq!(h.prg_ram_bytes(), 0);
+        assert_eq!(h.chr_ram_bytes(), 0);
+    }
+
+    #[test]
+    fn nes2_msb_sizes() {
+        // 0x102 * 16 KB of PRG-ROM, 0x201 * 8 KB of CHR-ROM
+        let h = header(b"NES\x1a\x02\x01\x00\x08\x00\x21\x00\x00\x00\x00\x00\x00");
+        assert!(h.nes2());
+        assert_eq!(h.prg_rom_bytes(), 0x102 * 0x4000);
+        assert_eq!(h.chr_rom_bytes(), 0x201 * 0x2000);
+    }
+
+    #[test]
+    fn nes2_exponent_sizes() {
+        // PRG 2^10 * 3, CHR 2^7 * 1
+        let h = header(b"NES\x1a\x29\x1c\x00\x08\x00\xFF\x00\x00\x00\x00\x00\x00");
+        assert_eq!(h.prg_rom_bytes(), 3 << 10);
+        assert_eq!(h.chr_rom_bytes(), 1 << 7);
+        // 2^63 * 7 doesn't fit
+        let h = header(b"NES\x1a\xFF\x00\x00\x08\x00\x0F\x00\x00\x00\x00\x00\x00");
+        assert_eq!(h.prg_rom_bytes(), usize::MAX);
+    }
+
+    #[test]
+    fn nes2_ram_shift_counts() {
+        // PRG-RAM 64 << 7, PRG-NVRAM 64 << 9, CHR-RAM 64 << 7, no CHR-NVRAM
+        let h = header(b"NES\x1a\x02\x00\x00\x08\x00\x00\x97\x07\x00\x00\x00\x00");
+        assert_eq!(h.prg_ram_bytes(), 0x2000);
+        assert_eq!(h.prg_nvram_bytes(), 0x8000);
+        assert_eq!(h.chr_ram_bytes(), 0x2000);
+        assert_eq!(h.chr_nvram_bytes(), 0);
+    }
+
+    #[test]
+    fn nes2_mapper_and_submapper() {
+        // Mapper 0x5A3, submapper 4
+        let h = header(b"NES\x1a\x02\x00\x30\xA8\x45\x00\x00\x00\x00\x00\x00\x00");
+        assert_eq!(h.mapper(), 0x5A3);
+        assert_eq!(h.submapper(), 4);
+    }
+
+    #[test]
+    fn ines_mapper() {
+        let h = header(b"NES\x1a\x02\x00\x40\x10\x45\x00\x00\x00\x00\x00\x00\x00");
+        assert_eq!(h.mapper(), 0x14);
+        assert_eq!(h.submapper(), 0);
+    }
+
+    #[test]
+    fn diskdude_header() {
+        // MMC1 with "DiskDude!" from byte 7, the high nibble is garbage
+        let h = header(b"NES\x1a\x02\x01\x10DiskDude!");
+        assert!(!h.nes2());
+        assert_eq!(h.mapper(), 1);
+    }
+
+    /// A file of `len` bytes starting with `header`
+    fn load(header: &[u8; 16], len: usize) -> Result<(usize, usize), RomError> {
+        let mut file = [0u8; 16 + 512 + 0x4000 + 0x2000];
+        file[..16].copy_from_slice(header);
+        Rom::load(&file[..len]).map(|rom| (rom.prg.len(), rom.chr.len()))
+    }
+
+    const NROM: &[u8; 16] = b"NES\x1a\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+
+    #[test]
+    fn load_rom() {
+        assert_eq!(load(NROM, 16 + 0x4000 + 0x2000), Ok((0x4000, 0x2000)));
+        let trainer = b"NES\x1a\x01\x01\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(
+            load(trainer, 16 + 512 + 0x4000 + 0x2000),
+            Ok((0x4000, 0x2000))
+        );
+    }
+
+    #[test]
+    fn invalid_magic() {
+        let header = b"NES\x00\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(
+            load(header, 16 + 0x4000 + 0x2000),
+            Err(RomError::InvalidMagic)
+        );
+    }
+
+    #[test]
+    fn truncated_header() {
+        assert_eq!(load(NROM, 15), Err(RomError::TruncatedHeader));
+        assert_eq!(load(NROM, 0), Err(RomError::TruncatedHeader));
+    }
+
+    #[test]
+    fn truncated_trainer() {
+        let trainer = b"NES\x1a\x01\x01\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(
+            load(trainer, 16 + 100),
+            Err(RomError::TruncatedPrg {
+                expected: 512 + 0x4000,
+                found: 100
+            })
+        );
+        // A size too big for usize doesn't overflow
+        let huge = b"NES\x1a\xFF\x00\x04\x08\x00\x0F\x00\x00\x00\x00\x00\x00";
+        assert_eq!(
+            load(huge, 16 + 100),
+            Err(RomError::TruncatedPrg {
+                expected: usize::MAX,
+                found: 100
+            })
+        );
+    }
+
+    #[test]
+    fn truncated_prg() {
+        assert_eq!(
+            load(NROM, 16 + 100),
+            Err(RomError::TruncatedPrg {
+                expected: 0x4000,
+                found: 100
+            })
+        );
+    }
+
+    #[test]
+    fn truncated_chr() {
+        assert_eq!(
+            load(NROM, 16 + 0x4000 + 10),
+            Err(RomError::TruncatedChr {
+                expected: 0x2000,
+                found: 10
+            })
+        );
+    }
+
+    #[test]
+    fn no_prg() {
+        let header = b"NES\x1a\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        assert_eq!(load(header, 16 + 0x2000), Err(RomError::InconsistentSizes));
+    }
+}
@@ -0,0 +1,371 @@
+//! Save states, a little endian dump of everything that changes while the
+//! machine runs. Lookup tables and ROM data aren't saved, the checksum in
+//! the header makes sure a state is only loaded into the game it came from.
+use core::fmt;
+
+/// Bumped whenever the layout of any saved struct changes
+pub const STATE_VERSION: u32 = 1;
+const MAGIC: [u8; 4] = *b"OXST";
+/// Magic, version and ROM checksum
+pub const HEADER_SIZE: usize = 12;
+
+/// Why a save state can't be written or loaded
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StateError {
+    /// The buffer is smaller than `NES::state_size`
+    BufferTooSmall,
+    /// The data isn't a save state
+    InvalidFormat,
+    /// The state was saved by another version of the emulator
+    VersionMismatch(u32),
+    /// The state was saved while running another game
+    RomMismatch,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            StateError::BufferTooSmall => write!(f, "buffer is too small for a save state"),
+            StateError::InvalidFormat => write!(f, "not a save state"),
+            StateError::VersionMismatch(version) => write!(
+                f,
+                "save state version {} is not supported, expected {}",
+                version, STATE_VERSION
+            ),
+            StateError::RomMismatch => write!(f, "save state is for another game"),
+        }
+    }
+}
+
+/// Start a state with the magic, the format version and the ROM checksum
+pub fn write_header(w: &mut Writer, checksum: u32) {
+    w.bytes(&MAGIC);
+    STATE_VERSION.save(w);
+    checksum.save(w);
+}
+
+/// Check the header of `data` before anything is loaded from it
+pub fn check_header(data: &[u8], checksum: u32) -> Result<(), StateError> {
+    if data.len() < HEADER_SIZE || data[0..4] != MAGIC {
+        return Err(StateError::InvalidFormat);
+    }
+    let mut r = Reader::new(&data[4..]);
+    let mut version = 0u32;
+    version.load(&mut r);
+    if version != STATE_VERSION {
+        return Err(StateError::VersionMismatch(version));
+    }
+    let mut saved = 0u32;
+    saved.load(&mut r);
+    if saved != checksum {
+        return Err(StateError::RomMismatch);
+    }
+    Ok(())
+}
+
+/// Appends to a caller provided buffer. Writes past its end are dropped
+/// but still counted, so a writer on an empty buffer measures a state.
+pub struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl<'b> Writer<'b> {
+    pub fn new(buf: &'b mut [u8]) -> Writer<'b> {
+        Writer { buf, pos: 0 }
+    }
+    pub fn bytes(&mut self, data: &[u8]) {
+        let end = self.pos + data.len();
+        if let Some(out) = self.buf.get_mut(self.pos..end) {
+            out.copy_from_slice(data);
+        }
+        self.pos = end;
+    }
+    /// Bytes written so far
+    pub fn written(&self) -> usize {
+        self.pos
+    }
+}
+
+/// Reads back what a `Writer` wrote, zeros past the end
+pub struct Reader<'b> {
+    buf: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    pub fn new(buf: &'b [u8]) -> Reader<'b> {
+        Reader { buf, pos: 0 }
+    }
+    pub fn bytes(&mut self, out: &mut [u8]) {
+        let end = self.pos + out.len();
+        match self.buf.get(self.pos..end) {
+            Some(data) => out.copy_from_slice(data),
+            None => out.iter_mut().for_each(|b| *b = 0),
+        }
+        self.pos = end;
+    }
+}
+
+/// Part of the machine kept in a save state
+pub trait State {
+    fn save(&self, w: &mut Writer);
+    fn load(&mut self, r: &mut Reader);
+}
+
+macro_rules! state_number {
+    ($($t:ty),*) => {
+        $(impl State for $t {
+            fn save(&self, w: &mut Writer) {
+                w.bytes(&self.to_le_bytes());
+            }
+            fn load(&mut self, r: &mut Reader) {
+                let mut bytes = self.to_le_bytes();
+                r.bytes(&mut bytes);
+                *self = <$t>::from_le_bytes(bytes);
+            }
+        })*
+    };
+}
+
+state_number!(u8, u16, u32, u64, i8, i16, i32, f32, f64);
+
+/// Saved as 64 bits, so states don't depend on the host
+impl State for usize {
+    fn save(&self, w: &mut Writer) {
+        (*self as u64).save(w);
+    }
+    fn load(&mut self, r: &mut Reader) {
+        let mut val = 0u64;
+        val.load(r);
+        *self = val as usize;
+    }
+}
+
+impl State for bool {
+    fn save(&self, w: &mut Writer) {
+        (*self as u8).save(w);
+    }
+    fn load(&mut self, r: &mut Reader) {
+        let mut val = 0u8;
+        val.load(r);
+        *self = val != 0;
+    }
+}
+
+impl<T: State + Default> State for Option<T> {
+    fn save(&self, w: &mut Writer) {
+        self.is_some().save(w);
+        match self {
+            Some(val) => val.save(w),
+            None => T::default().save(w),
+        }
+    }
+    fn load(&mut self, r: &mut Reader) {
+        let mut some = false;
+        some.load(r);
+        let mut val = T::default();
+        val.load(r);
+        *self = if some { Some(val) } else { None };
+    }
+}
+
+impl<T: State, const N: usize> State for [T; N] {
+    fn save(&self, w: &mut Writer) {
+        self.iter().for_each(|val| val.save(w));
+    }
+    fn load(&mut self, r: &mut Reader) {
+        self.iter_mut().for_each(|val| val.load(r));
+    }
+}
+
+/// Memory borrowed from the frontend, like the disk of the disk system
+impl<'d> State for &'d mut [u8] {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(self);
+    }
+    fn load(&mut self, r: &mut Reader) {
+        r.bytes(self);
+    }
+}
+
+impl State for mos6502::Regs {
+    fn save(&self, w: &mut Writer) {
+        w.bytes(&[self.a, self.x, self.y, self.s, self.flags]);
+        self.pc.save(w);
+    }
+    fn load(&mut self, r: &mut Reader) {
+        let mut regs = [0; 5];
+        r.bytes(&mut regs);
+        let [a, x, y, s, flags] = regs;
+        self.a = a;
+        self.x = x;
+        self.y = y;
+        self.s = s;
+        self.flags = flags;
+        self.pc.load(r);
+    }
+}
+
+/// Implement `State` by saving the listed fields in order
+macro_rules! state {
+    ($name:ident $(<$lt:lifetime>)? { $($field:ident),* $(,)? }) => {
+        impl$(<$lt>)? crate::state::State for $name$(<$lt>)? {
+            fn save(&self, w: &mut crate::state::Writer) {
+                $(crate::state::State::save(&self.$field, w);)*
+            }
+            fn load(&mut self, r: &mut crate::state::Reader) {
+                $(crate::state::State::load(&mut self.$field, r);)*
+            }
+        }
+    };
+}
+
+/// Implement `State` for a bitflags register
+macro_rules! state_bits {
+    ($name:ident) => {
+        impl crate::state::State for $name {
+            fn save(&self, w: &mut crate::state::Writer) {
+                crate::state::State::save(&self.bits(), w);
+            }
+            fn load(&mut self, r: &mut crate::state::Reader) {
+                let mut bits = 0;
+                crate::state::State::load(&mut bits, r);
+                *self = $name::from_bits_truncate(bits);
+            }
+        }
+    };
+}
+
+/// CRC-32 of `data`, continuing from the CRC `crc` of the data before it
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default, PartialEq, Debug)]
+    struct Fields {
+        a: u8,
+        b: u16,
+        c: i32,
+        d: f32,
+        e: bool,
+        f: Option<u8>,
+        g: [u16; 3],
+        h: usize,
+    }
+
+    state!(Fields {
+        a,
+        b,
+        c,
+        d,
+        e,
+        f,
+        g,
+        h
+    });
+
+    fn header(checksum: u32) -> [u8; HEADER_SIZE] {
+        let mut buf = [0; HEADER_SIZE];
+        write_header(&mut Writer::new(&mut buf), checksum);
+        buf
+    }
+
+    #[test]
+    fn check_valid_header() {
+        assert_eq!(check_header(&header(0x1234_5678), 0x1234_5678), Ok(()));
+    }
+
+    #[test]
+    fn check_invalid_format() {
+        let mut buf = header(0);
+        assert_eq!(
+            check_header(&buf[..HEADER_SIZE - 1], 0),
+            Err(StateError::InvalidFormat)
+        );
+        buf[0] = b'X';
+        assert_eq!(check_header(&buf, 0), Err(StateError::InvalidFormat));
+    }
+
+    #[test]
+    fn check_version_mismatch() {
+        let mut buf = header(0);
+        buf[4] = STATE_VERSION as u8 + 1;
+        assert_eq!(
+            check_header(&buf, 0),
+            Err(StateError::VersionMismatch(STATE_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn check_rom_mismatch() {
+        assert_eq!(check_header(&header(1), 2), Err(StateError::RomMismatch));
+    }
+
+    #[test]
+    fn writer_measures_without_buffer() {
+        let fields = Fields::default();
+        let mut w = Writer::new(&mut []);
+        fields.save(&mut w);
+        // Options save their value even when None, usize takes 64 bits
+        assert_eq!(w.written(), 1 + 2 + 4 + 4 + 1 + 2 + 6 + 8);
+    }
+
+    #[test]
+    fn writer_drops_bytes_past_the_end() {
+        let mut buf = [0; 3];
+        let mut w = Writer::new(&mut buf);
+        0x1122_3344u32.save(&mut w);
+        assert_eq!(w.written(), 4);
+        assert_eq!(buf, [0, 0, 0]);
+    }
+
+    #[test]
+    fn save_load_round_trip() {
+        let fields = Fields {
+            a: 0x12,
+            b: 0x3456,
+            c: -7,
+            d: 0.25,
+            e: true,
+            f: Some(9),
+            g: [1, 2, 0xFFFF],
+            h: 0x1_0000_0000,
+        };
+        let mut buf = [0; 64];
+        let mut w = Writer::new(&mut buf);
+        fields.save(&mut w);
+        let written = w.written();
+        let mut loaded = Fields::default();
+        loaded.load(&mut Reader::new(&buf[..written]));
+        assert_eq!(loaded, fields);
+    }
+
+    #[test]
+    fn reader_zeros_past_the_end() {
+        let mut val = 0xFFFF_FFFFu32;
+        val.load(&mut Reader::new(&[1, 2]));
+        assert_eq!(val, 0);
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xCBF4_3926);
+    }
+}